@@ -0,0 +1,7 @@
+-- Mood, weather and location for diary entries
+ALTER TABLE public.notes ADD COLUMN IF NOT EXISTS mood_score smallint;
+ALTER TABLE public.notes ADD COLUMN IF NOT EXISTS mood_label text;
+ALTER TABLE public.notes ADD COLUMN IF NOT EXISTS weather text;
+ALTER TABLE public.notes ADD COLUMN IF NOT EXISTS location_name text;
+ALTER TABLE public.notes ADD COLUMN IF NOT EXISTS location_lat double precision;
+ALTER TABLE public.notes ADD COLUMN IF NOT EXISTS location_lng double precision;
@@ -0,0 +1,2 @@
+-- Notes that stay locked until a future time
+ALTER TABLE public.notes ADD COLUMN IF NOT EXISTS unlock_at timestamp with time zone;
@@ -0,0 +1,8 @@
+-- Tokens that let feed readers fetch a user's calendar and Atom feeds
+CREATE TABLE IF NOT EXISTS public.feed_tokens (
+    user_id integer NOT NULL,
+    token_hash character varying(64) NOT NULL,
+    CONSTRAINT feed_tokens_pkey PRIMARY KEY (user_id),
+    CONSTRAINT feed_tokens_token_hash_key UNIQUE (token_hash),
+    CONSTRAINT feed_tokens_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id)
+);
@@ -0,0 +1,2 @@
+-- Tags, which Atom feeds can be filtered by
+ALTER TABLE public.notes ADD COLUMN IF NOT EXISTS tags text[] DEFAULT '{}'::text[] NOT NULL;
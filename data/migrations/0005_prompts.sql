@@ -0,0 +1,28 @@
+-- Writing prompts, the one each user gets today, and the prompt a note answered
+CREATE TABLE IF NOT EXISTS public.prompts (
+    id serial NOT NULL,
+    user_id integer,
+    text text NOT NULL,
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT prompts_pkey PRIMARY KEY (id),
+    CONSTRAINT prompts_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id)
+);
+
+CREATE TABLE IF NOT EXISTS public.daily_prompts (
+    user_id integer NOT NULL,
+    day date NOT NULL,
+    prompt_id integer NOT NULL,
+    CONSTRAINT daily_prompts_pkey PRIMARY KEY (user_id),
+    CONSTRAINT daily_prompts_prompt_id_fkey FOREIGN KEY (prompt_id) REFERENCES public.prompts(id) ON DELETE CASCADE,
+    CONSTRAINT daily_prompts_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id)
+);
+
+ALTER TABLE public.notes ADD COLUMN IF NOT EXISTS prompt_id integer;
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'notes_prompt_id_fkey') THEN
+        ALTER TABLE public.notes
+            ADD CONSTRAINT notes_prompt_id_fkey FOREIGN KEY (prompt_id) REFERENCES public.prompts(id) ON DELETE SET NULL;
+    END IF;
+END $$;
@@ -0,0 +1,3 @@
+-- Which backend each image is kept in (only the postgres backend keeps the bytes in the row)
+ALTER TABLE public.images ALTER COLUMN image DROP NOT NULL;
+ALTER TABLE public.images ADD COLUMN IF NOT EXISTS storage character varying(32) DEFAULT 'postgres'::character varying NOT NULL;
@@ -0,0 +1,11 @@
+-- Hashes for spotting re-uploads of the same image, and a flag for images still being stored
+ALTER TABLE public.images ADD COLUMN IF NOT EXISTS sha256 character varying(64);
+ALTER TABLE public.images ADD COLUMN IF NOT EXISTS pending boolean DEFAULT false NOT NULL;
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'images_user_id_sha256_key') THEN
+        ALTER TABLE public.images
+            ADD CONSTRAINT images_user_id_sha256_key UNIQUE (user_id, sha256);
+    END IF;
+END $$;
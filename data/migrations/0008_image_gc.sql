@@ -0,0 +1,3 @@
+-- When each image was uploaded and when it lost its last reference, for the garbage collector
+ALTER TABLE public.images ADD COLUMN IF NOT EXISTS created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL;
+ALTER TABLE public.images ADD COLUMN IF NOT EXISTS unreferenced_since timestamp with time zone;
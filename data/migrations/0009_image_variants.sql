@@ -0,0 +1,13 @@
+-- Resized and converted copies of images, kept in the same backends as the images
+CREATE TABLE IF NOT EXISTS public.image_variants (
+    image_id integer NOT NULL,
+    variant character varying(64) NOT NULL,
+    image bytea,
+    mime_type character varying(255) NOT NULL,
+    id serial NOT NULL,
+    storage character varying(32) DEFAULT 'postgres'::character varying NOT NULL,
+    size_bytes integer NOT NULL,
+    CONSTRAINT image_variants_pkey PRIMARY KEY (image_id, variant),
+    CONSTRAINT image_variants_id_key UNIQUE (id),
+    CONSTRAINT image_variants_image_id_fkey FOREIGN KEY (image_id) REFERENCES public.images(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- When a photo was taken, read from its EXIF before that's stripped
+ALTER TABLE public.images ADD COLUMN IF NOT EXISTS captured_at timestamp with time zone;
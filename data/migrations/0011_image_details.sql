@@ -0,0 +1,4 @@
+-- Sizes and dimensions for listing images
+ALTER TABLE public.images ADD COLUMN IF NOT EXISTS size_bytes integer;
+ALTER TABLE public.images ADD COLUMN IF NOT EXISTS width integer;
+ALTER TABLE public.images ADD COLUMN IF NOT EXISTS height integer;
@@ -0,0 +1,13 @@
+-- Generic file attachments
+CREATE TABLE IF NOT EXISTS public.files (
+    id serial NOT NULL,
+    user_id integer NOT NULL,
+    name text NOT NULL,
+    mime_type character varying(255) NOT NULL,
+    size_bytes integer NOT NULL,
+    storage character varying(32) DEFAULT 'postgres'::character varying NOT NULL,
+    content bytea,
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT files_pkey PRIMARY KEY (id),
+    CONSTRAINT files_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id)
+);
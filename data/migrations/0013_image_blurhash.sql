@@ -0,0 +1,2 @@
+-- Placeholders shown while an image loads
+ALTER TABLE public.images ADD COLUMN IF NOT EXISTS blurhash character varying(64);
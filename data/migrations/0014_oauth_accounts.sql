@@ -0,0 +1,9 @@
+-- Accounts at OAuth providers that users can log in with
+CREATE TABLE IF NOT EXISTS public.oauth_accounts (
+    provider character varying(64) NOT NULL,
+    subject character varying(255) NOT NULL,
+    user_id integer NOT NULL,
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT oauth_accounts_pkey PRIMARY KEY (provider, subject),
+    CONSTRAINT oauth_accounts_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id)
+);
@@ -0,0 +1,2 @@
+-- A flag for attachments still being stored, which can't be seen until they are
+ALTER TABLE public.files ADD COLUMN IF NOT EXISTS pending boolean DEFAULT false NOT NULL;
@@ -20,6 +20,110 @@ SET default_tablespace = '';
 
 SET default_table_access_method = heap;
 
+--
+-- Name: daily_prompts; Type: TABLE; Schema: public; Owner: rileybell
+--
+
+CREATE TABLE public.daily_prompts (
+    user_id integer NOT NULL,
+    day date NOT NULL,
+    prompt_id integer NOT NULL
+);
+
+
+ALTER TABLE public.daily_prompts OWNER TO rileybell;
+
+--
+-- Name: feed_tokens; Type: TABLE; Schema: public; Owner: rileybell
+--
+
+CREATE TABLE public.feed_tokens (
+    user_id integer NOT NULL,
+    token_hash character varying(64) NOT NULL
+);
+
+
+ALTER TABLE public.feed_tokens OWNER TO rileybell;
+
+--
+-- Name: files; Type: TABLE; Schema: public; Owner: rileybell
+--
+
+CREATE TABLE public.files (
+    id integer NOT NULL,
+    user_id integer NOT NULL,
+    name text NOT NULL,
+    mime_type character varying(255) NOT NULL,
+    size_bytes integer NOT NULL,
+    storage character varying(32) DEFAULT 'postgres'::character varying NOT NULL,
+    content bytea,
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    pending boolean DEFAULT false NOT NULL
+);
+
+
+ALTER TABLE public.files OWNER TO rileybell;
+
+--
+-- Name: files_id_seq; Type: SEQUENCE; Schema: public; Owner: rileybell
+--
+
+CREATE SEQUENCE public.files_id_seq
+    AS integer
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+
+ALTER TABLE public.files_id_seq OWNER TO rileybell;
+
+--
+-- Name: files_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: rileybell
+--
+
+ALTER SEQUENCE public.files_id_seq OWNED BY public.files.id;
+
+
+--
+-- Name: image_variants; Type: TABLE; Schema: public; Owner: rileybell
+--
+
+CREATE TABLE public.image_variants (
+    image_id integer NOT NULL,
+    variant character varying(64) NOT NULL,
+    image bytea,
+    mime_type character varying(255) NOT NULL,
+    id integer NOT NULL,
+    storage character varying(32) DEFAULT 'postgres'::character varying NOT NULL,
+    size_bytes integer NOT NULL
+);
+
+
+ALTER TABLE public.image_variants OWNER TO rileybell;
+
+--
+-- Name: image_variants_id_seq; Type: SEQUENCE; Schema: public; Owner: rileybell
+--
+
+CREATE SEQUENCE public.image_variants_id_seq
+    AS integer
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+
+ALTER TABLE public.image_variants_id_seq OWNER TO rileybell;
+
+--
+-- Name: image_variants_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: rileybell
+--
+
+ALTER SEQUENCE public.image_variants_id_seq OWNED BY public.image_variants.id;
+
 --
 -- Name: images; Type: TABLE; Schema: public; Owner: rileybell
 --
@@ -27,9 +131,19 @@ SET default_table_access_method = heap;
 CREATE TABLE public.images (
     id integer NOT NULL,
     user_id integer NOT NULL,
-    image bytea NOT NULL,
+    image bytea,
     reference_count integer DEFAULT 1 NOT NULL,
-    mime_type character varying(255) NOT NULL
+    mime_type character varying(255) NOT NULL,
+    storage character varying(32) DEFAULT 'postgres'::character varying NOT NULL,
+    sha256 character varying(64),
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    unreferenced_since timestamp with time zone,
+    captured_at timestamp with time zone,
+    size_bytes integer,
+    width integer,
+    height integer,
+    blurhash character varying(64),
+    pending boolean DEFAULT false NOT NULL
 );
 
 
@@ -69,7 +183,16 @@ CREATE TABLE public.notes (
     title text DEFAULT ''::character varying NOT NULL,
     favourite boolean DEFAULT false NOT NULL,
     is_diary boolean DEFAULT false NOT NULL,
-    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    mood_score smallint,
+    mood_label text,
+    weather text,
+    location_name text,
+    location_lat double precision,
+    location_lng double precision,
+    unlock_at timestamp with time zone,
+    tags text[] DEFAULT '{}'::text[] NOT NULL,
+    prompt_id integer
 );
 
 
@@ -97,6 +220,69 @@ ALTER TABLE public.notes_id_seq OWNER TO rileybell;
 ALTER SEQUENCE public.notes_id_seq OWNED BY public.notes.id;
 
 
+--
+-- Name: oauth_accounts; Type: TABLE; Schema: public; Owner: rileybell
+--
+
+CREATE TABLE public.oauth_accounts (
+    provider character varying(64) NOT NULL,
+    subject character varying(255) NOT NULL,
+    user_id integer NOT NULL,
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+
+ALTER TABLE public.oauth_accounts OWNER TO rileybell;
+
+--
+-- Name: password_resets; Type: TABLE; Schema: public; Owner: rileybell
+--
+
+CREATE TABLE public.password_resets (
+    user_id integer NOT NULL,
+    token_hash character varying(64) NOT NULL,
+    expires_at timestamp with time zone NOT NULL
+);
+
+
+ALTER TABLE public.password_resets OWNER TO rileybell;
+
+--
+-- Name: prompts; Type: TABLE; Schema: public; Owner: rileybell
+--
+
+CREATE TABLE public.prompts (
+    id integer NOT NULL,
+    user_id integer,
+    text text NOT NULL,
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+
+ALTER TABLE public.prompts OWNER TO rileybell;
+
+--
+-- Name: prompts_id_seq; Type: SEQUENCE; Schema: public; Owner: rileybell
+--
+
+CREATE SEQUENCE public.prompts_id_seq
+    AS integer
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+
+ALTER TABLE public.prompts_id_seq OWNER TO rileybell;
+
+--
+-- Name: prompts_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: rileybell
+--
+
+ALTER SEQUENCE public.prompts_id_seq OWNED BY public.prompts.id;
+
+
 --
 -- Name: sessions; Type: TABLE; Schema: public; Owner: rileybell
 --
@@ -144,6 +330,20 @@ ALTER TABLE public.users_id_seq OWNER TO rileybell;
 ALTER SEQUENCE public.users_id_seq OWNED BY public.users.id;
 
 
+--
+-- Name: files id; Type: DEFAULT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.files ALTER COLUMN id SET DEFAULT nextval('public.files_id_seq'::regclass);
+
+
+--
+-- Name: image_variants id; Type: DEFAULT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.image_variants ALTER COLUMN id SET DEFAULT nextval('public.image_variants_id_seq'::regclass);
+
+
 --
 -- Name: images id; Type: DEFAULT; Schema: public; Owner: rileybell
 --
@@ -158,6 +358,13 @@ ALTER TABLE ONLY public.images ALTER COLUMN id SET DEFAULT nextval('public.image
 ALTER TABLE ONLY public.notes ALTER COLUMN id SET DEFAULT nextval('public.notes_id_seq'::regclass);
 
 
+--
+-- Name: prompts id; Type: DEFAULT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.prompts ALTER COLUMN id SET DEFAULT nextval('public.prompts_id_seq'::regclass);
+
+
 --
 -- Name: users id; Type: DEFAULT; Schema: public; Owner: rileybell
 --
@@ -165,6 +372,54 @@ ALTER TABLE ONLY public.notes ALTER COLUMN id SET DEFAULT nextval('public.notes_
 ALTER TABLE ONLY public.users ALTER COLUMN id SET DEFAULT nextval('public.users_id_seq'::regclass);
 
 
+--
+-- Name: daily_prompts daily_prompts_pkey; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.daily_prompts
+    ADD CONSTRAINT daily_prompts_pkey PRIMARY KEY (user_id);
+
+
+--
+-- Name: feed_tokens feed_tokens_pkey; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.feed_tokens
+    ADD CONSTRAINT feed_tokens_pkey PRIMARY KEY (user_id);
+
+
+--
+-- Name: feed_tokens feed_tokens_token_hash_key; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.feed_tokens
+    ADD CONSTRAINT feed_tokens_token_hash_key UNIQUE (token_hash);
+
+
+--
+-- Name: files files_pkey; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.files
+    ADD CONSTRAINT files_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: image_variants image_variants_pkey; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.image_variants
+    ADD CONSTRAINT image_variants_pkey PRIMARY KEY (image_id, variant);
+
+
+--
+-- Name: image_variants image_variants_id_key; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.image_variants
+    ADD CONSTRAINT image_variants_id_key UNIQUE (id);
+
+
 --
 -- Name: images images_pkey; Type: CONSTRAINT; Schema: public; Owner: rileybell
 --
@@ -173,6 +428,14 @@ ALTER TABLE ONLY public.images
     ADD CONSTRAINT images_pkey PRIMARY KEY (id);
 
 
+--
+-- Name: images images_user_id_sha256_key; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.images
+    ADD CONSTRAINT images_user_id_sha256_key UNIQUE (user_id, sha256);
+
+
 --
 -- Name: notes notes_pkey; Type: CONSTRAINT; Schema: public; Owner: rileybell
 --
@@ -181,6 +444,38 @@ ALTER TABLE ONLY public.notes
     ADD CONSTRAINT notes_pkey PRIMARY KEY (id);
 
 
+--
+-- Name: oauth_accounts oauth_accounts_pkey; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.oauth_accounts
+    ADD CONSTRAINT oauth_accounts_pkey PRIMARY KEY (provider, subject);
+
+
+--
+-- Name: password_resets password_resets_pkey; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.password_resets
+    ADD CONSTRAINT password_resets_pkey PRIMARY KEY (user_id);
+
+
+--
+-- Name: password_resets password_resets_token_hash_key; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.password_resets
+    ADD CONSTRAINT password_resets_token_hash_key UNIQUE (token_hash);
+
+
+--
+-- Name: prompts prompts_pkey; Type: CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.prompts
+    ADD CONSTRAINT prompts_pkey PRIMARY KEY (id);
+
+
 --
 -- Name: sessions sessions_pkey; Type: CONSTRAINT; Schema: public; Owner: rileybell
 --
@@ -197,6 +492,46 @@ ALTER TABLE ONLY public.users
     ADD CONSTRAINT users_pkey PRIMARY KEY (id);
 
 
+--
+-- Name: daily_prompts daily_prompts_prompt_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.daily_prompts
+    ADD CONSTRAINT daily_prompts_prompt_id_fkey FOREIGN KEY (prompt_id) REFERENCES public.prompts(id) ON DELETE CASCADE;
+
+
+--
+-- Name: daily_prompts daily_prompts_user_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.daily_prompts
+    ADD CONSTRAINT daily_prompts_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id);
+
+
+--
+-- Name: feed_tokens feed_tokens_user_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.feed_tokens
+    ADD CONSTRAINT feed_tokens_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id);
+
+
+--
+-- Name: files files_user_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.files
+    ADD CONSTRAINT files_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id);
+
+
+--
+-- Name: image_variants image_variants_image_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.image_variants
+    ADD CONSTRAINT image_variants_image_id_fkey FOREIGN KEY (image_id) REFERENCES public.images(id) ON DELETE CASCADE;
+
+
 --
 -- Name: images images_user_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
 --
@@ -213,6 +548,38 @@ ALTER TABLE ONLY public.notes
     ADD CONSTRAINT notes_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id);
 
 
+--
+-- Name: notes notes_prompt_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.notes
+    ADD CONSTRAINT notes_prompt_id_fkey FOREIGN KEY (prompt_id) REFERENCES public.prompts(id) ON DELETE SET NULL;
+
+
+--
+-- Name: oauth_accounts oauth_accounts_user_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.oauth_accounts
+    ADD CONSTRAINT oauth_accounts_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id);
+
+
+--
+-- Name: password_resets password_resets_user_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.password_resets
+    ADD CONSTRAINT password_resets_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id);
+
+
+--
+-- Name: prompts prompts_user_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
+--
+
+ALTER TABLE ONLY public.prompts
+    ADD CONSTRAINT prompts_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id);
+
+
 --
 -- Name: sessions sessions_user_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: rileybell
 --
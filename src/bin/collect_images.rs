@@ -0,0 +1,47 @@
+use std::process::ExitCode;
+
+/// Deletes images that no note has referenced for the grace period set by
+/// IMAGE_GC_GRACE_DAYS, e.g. `cargo run --bin collect_images -- --dry-run`
+///
+/// With --dry-run nothing is changed, it just reports what it would do. Safe to run
+/// while the server is running, e.g. nightly from cron
+#[rocket::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let dry_run = match args.as_slice() {
+        [] => false,
+        [flag] if flag == "--dry-run" => true,
+        _ => {
+            eprintln!("Usage: collect_images [--dry-run]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let report = match rust_back::collect_images(dry_run).await {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let verb = if dry_run { "Would" } else { "Did" };
+    println!("Scanned {} images", report.scanned);
+    for (id, old, new) in &report.corrected {
+        println!("{verb} correct image {id}'s reference count from {old} to {new}");
+    }
+    for id in &report.unreferenced {
+        println!("{verb} start image {id}'s grace period, it's no longer referenced");
+    }
+    for id in &report.deleted {
+        println!("{verb} delete image {id}");
+    }
+    println!(
+        "{} corrected, {} newly unreferenced, {} deleted",
+        report.corrected.len(),
+        report.unreferenced.len(),
+        report.deleted.len()
+    );
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,30 @@
+use std::process::ExitCode;
+
+/// Moves every image and attachment from one image store backend to another, e.g.
+/// `cargo run --bin migrate_images -- postgres s3`
+///
+/// Both backends are configured in the .env file, the same way the server's is. Once
+/// it's finished, point IMAGE_STORE at the new backend and restart the server
+#[rocket::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: migrate_images <from> <to>");
+        eprintln!("Backends: postgres, filesystem, s3");
+        return ExitCode::FAILURE;
+    }
+
+    match rust_back::migrate_images(&args[1], &args[2]).await {
+        Ok(moved) => {
+            println!(
+                "Moved {moved} images and attachments from {} to {}",
+                args[1], args[2]
+            );
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
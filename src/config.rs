@@ -0,0 +1,493 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use rocket::time::Duration;
+
+use crate::urls::PublicUrls;
+
+/// The file our configuration is read from
+const ENV_FILE: &str = ".env";
+
+/// The largest image that can be uploaded, in bytes, if not configured
+const DEFAULT_IMAGE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// The largest attachment of each type that can be uploaded, in MiB, if not configured
+const DEFAULT_FILE_SIZE_LIMITS: &str = "application/pdf=20,audio/*=50,*=10";
+
+/// How many MiB each user can store, if not configured
+const DEFAULT_STORAGE_QUOTA_MB: u64 = 1024;
+
+/// How long we'll wait to download an image from a url, in seconds, if not configured
+const DEFAULT_IMAGE_FETCH_TIMEOUT_SECS: u64 = 10;
+
+/// The backends image files can be stored in
+const IMAGE_STORE_BACKENDS: [&str; 3] = ["postgres", "filesystem", "s3"];
+
+/// How long an image can go unreferenced before it's garbage collected, if not configured
+const DEFAULT_IMAGE_GC_GRACE_DAYS: i64 = 7;
+
+/// The fonts exported pdfs are written in, if not configured. Ubuntu's fonts-dejavu-core
+const DEFAULT_PDF_FONT: &str = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf";
+const DEFAULT_PDF_BOLD_FONT: &str = "/usr/share/fonts/truetype/dejavu/DejaVuSans-Bold.ttf";
+
+/// Where the ClamAV daemon listens, if not configured
+const DEFAULT_CLAMAV_SOCKET: &str = "/var/run/clamav/clamd.ctl";
+
+/// Where infected uploads are copied to, if not configured
+const DEFAULT_QUARANTINE_PATH: &str = "quarantine";
+
+/// The scopes we ask OAuth providers for, if not configured
+const DEFAULT_OAUTH_SCOPES: &str = "openid email";
+
+/// Where emails are written, if they're written to a file and it's not configured
+const DEFAULT_MAIL_FILE_PATH: &str = "mail.log";
+
+/// The port mail servers accept STARTTLS connections on, if not configured
+const DEFAULT_SMTP_PORT: u16 = 587;
+
+/// How long a password reset link can be used for, in minutes, if not configured
+const DEFAULT_PASSWORD_RESET_EXPIRY_MINUTES: i64 = 60;
+
+/// Everything that can be configured about the server, read from the .env file
+pub struct Config {
+    /// The url of the postgres database
+    pub database_url: String,
+    /// Builds the links we hand out, from where the server is publicly reachable
+    pub urls: PublicUrls,
+    /// How many bytes each user can store across their notes, images and attachments,
+    /// or None if there's no limit
+    pub storage_quota: Option<u64>,
+    /// Where uploaded image files are stored
+    pub image_store: ImageStoreConfig,
+    /// The other backends that are configured. Files stored before `IMAGE_STORE` last
+    /// changed stay where they were until they're migrated, so are read from these
+    pub other_image_stores: Vec<ImageStoreConfig>,
+    /// How long an image has to go without being referenced by any note before
+    /// the garbage collector deletes it
+    pub image_gc_grace: Duration,
+    /// The largest image that can be uploaded, in bytes
+    pub image_max_bytes: u64,
+    /// The largest attachment of each type that can be uploaded
+    pub file_size_limits: FileSizeLimits,
+    /// How long we'll wait to download an image from a url a user gave us
+    pub image_fetch_timeout: std::time::Duration,
+    /// Whether to keep when a photo was taken when stripping its metadata on upload
+    pub keep_capture_time: bool,
+    /// The TrueType font exported pdfs are written in
+    pub pdf_font: PathBuf,
+    /// The TrueType font used for headings in exported pdfs
+    pub pdf_bold_font: PathBuf,
+    /// What checks uploads for malware
+    pub scanner: ScannerConfig,
+    /// The directory uploads found to be infected are copied into
+    pub quarantine_path: PathBuf,
+    /// The OAuth providers users can log in with, by name
+    pub oauth_providers: HashMap<String, OAuthProviderConfig>,
+    /// How emails (e.g. password resets) are sent
+    pub mailer: MailerConfig,
+    /// How long a password reset link can be used for
+    pub password_reset_lifetime: Duration,
+}
+
+/// The backends image files can be stored in, and their settings
+#[derive(Clone)]
+pub enum ImageStoreConfig {
+    /// In the images table itself
+    Postgres,
+    /// As files in a directory on this machine
+    Filesystem { root: PathBuf },
+    /// In a bucket of an S3-compatible object store (AWS, MinIO, etc)
+    S3 {
+        bucket: String,
+        region: String,
+        /// The url of the object store, e.g. http://localhost:9000 for a local MinIO
+        endpoint: String,
+        access_key: String,
+        secret_key: String,
+    },
+}
+
+/// The malware scanners uploads can be checked with, and their settings
+#[derive(Clone)]
+pub enum ScannerConfig {
+    /// Uploads aren't scanned
+    None,
+    /// A ClamAV daemon, listening on a unix socket on this machine
+    ClamAv { socket: PathBuf },
+}
+
+/// The ways emails can be sent, and their settings
+#[derive(Clone)]
+pub enum MailerConfig {
+    /// Written to the log rather than sent
+    Log,
+    /// Appended to a file rather than sent
+    File { path: PathBuf },
+    /// Sent through a mail server
+    Smtp {
+        host: String,
+        port: u16,
+        username: Option<String>,
+        password: Option<String>,
+        /// Who the emails are from, e.g. "Journal <noreply@dev.com>"
+        from: String,
+    },
+}
+
+/// A provider users can log in with over OAuth2, which has to support OpenID
+/// Connect's userinfo endpoint so we can find out who they are
+#[derive(Clone)]
+pub struct OAuthProviderConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where users are sent to log in with the provider
+    pub auth_url: String,
+    /// Where we trade the code the provider sends back for an access token
+    pub token_url: String,
+    /// Where we use the access token to get the user's id and email
+    pub userinfo_url: String,
+    /// The scopes we ask for, which need to cover the user's email
+    pub scopes: Vec<String>,
+    /// Whether the emails the provider verifies are trusted enough to link its accounts
+    /// to existing users with the same email, without them logging in first
+    pub trust_email: bool,
+}
+
+/// The largest attachment of each mime type that can be uploaded, in bytes. Types can be
+/// exact (application/pdf), cover a whole family (audio/*), or cover everything (*)
+pub struct FileSizeLimits(Vec<(String, u64)>);
+
+impl Config {
+    /// Reads the configuration from the .env file
+    ///
+    /// ### Panics
+    ///
+    /// If the .env file is missing, or contains invalid configuration. There's no
+    /// sensible way to run without a valid configuration
+    pub fn load() -> Config {
+        let vars = read_vars();
+        let backend = vars.get("IMAGE_STORE").map_or("postgres", String::as_str);
+
+        Config {
+            database_url: vars
+                .get("DATABASE_URL")
+                .expect("DATABASE_URL is missing from the env file")
+                .clone(),
+            urls: PublicUrls::new(
+                vars.get("PUBLIC_BASE_URL").map(String::as_str),
+                vars.get("PUBLIC_URLS_RELATIVE")
+                    .is_some_and(|relative| relative == "true"),
+            )
+            .expect("Invalid PUBLIC_BASE_URL"),
+            storage_quota: match vars.get("STORAGE_QUOTA_MB").map(|mib| mib.parse()) {
+                None => Some(DEFAULT_STORAGE_QUOTA_MB * 1024 * 1024),
+                Some(Ok(0)) => None,
+                Some(Ok(mib)) => Some(mib * 1024 * 1024),
+                Some(Err(_)) => panic!("STORAGE_QUOTA_MB must be a number"),
+            },
+            image_store: ImageStoreConfig::from_vars(backend, &vars)
+                .expect("Invalid image store configuration"),
+            other_image_stores: IMAGE_STORE_BACKENDS
+                .iter()
+                .filter(|other| **other != backend)
+                .filter_map(|other| ImageStoreConfig::from_vars(other, &vars).ok())
+                .collect(),
+            image_gc_grace: Duration::days(
+                vars.get("IMAGE_GC_GRACE_DAYS")
+                    .map(|days| days.parse().expect("IMAGE_GC_GRACE_DAYS must be a number"))
+                    .unwrap_or(DEFAULT_IMAGE_GC_GRACE_DAYS),
+            ),
+            image_max_bytes: vars
+                .get("IMAGE_MAX_BYTES")
+                .map(|bytes| bytes.parse().expect("IMAGE_MAX_BYTES must be a number"))
+                .unwrap_or(DEFAULT_IMAGE_MAX_BYTES),
+            file_size_limits: FileSizeLimits::parse(
+                vars.get("FILE_SIZE_LIMITS")
+                    .map_or(DEFAULT_FILE_SIZE_LIMITS, String::as_str),
+            )
+            .expect("Invalid FILE_SIZE_LIMITS"),
+            image_fetch_timeout: std::time::Duration::from_secs(
+                vars.get("IMAGE_FETCH_TIMEOUT_SECS")
+                    .map(|secs| {
+                        secs.parse()
+                            .expect("IMAGE_FETCH_TIMEOUT_SECS must be a number")
+                    })
+                    .unwrap_or(DEFAULT_IMAGE_FETCH_TIMEOUT_SECS),
+            ),
+            keep_capture_time: vars
+                .get("IMAGE_KEEP_CAPTURE_TIME")
+                .map_or(true, |keep| keep != "false"),
+            pdf_font: PathBuf::from(
+                vars.get("PDF_FONT")
+                    .map_or(DEFAULT_PDF_FONT, String::as_str),
+            ),
+            pdf_bold_font: PathBuf::from(
+                vars.get("PDF_BOLD_FONT")
+                    .map_or(DEFAULT_PDF_BOLD_FONT, String::as_str),
+            ),
+            scanner: match vars.get("MALWARE_SCANNER").map(String::as_str) {
+                None | Some("none") => ScannerConfig::None,
+                Some("clamav") => ScannerConfig::ClamAv {
+                    socket: PathBuf::from(
+                        vars.get("CLAMAV_SOCKET")
+                            .map_or(DEFAULT_CLAMAV_SOCKET, String::as_str),
+                    ),
+                },
+                Some(scanner) => panic!("Unknown malware scanner {scanner}"),
+            },
+            quarantine_path: PathBuf::from(
+                vars.get("QUARANTINE_PATH")
+                    .map_or(DEFAULT_QUARANTINE_PATH, String::as_str),
+            ),
+            oauth_providers: vars
+                .get("OAUTH_PROVIDERS")
+                .map_or("", String::as_str)
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    let provider = OAuthProviderConfig::from_vars(name, &vars)
+                        .expect("Invalid OAuth provider configuration");
+                    (name.to_string(), provider)
+                })
+                .collect(),
+            // The log mailer writes password reset links where anyone who can read the
+            // logs could use them, so it's only the default while developing
+            mailer: MailerConfig::from_vars(
+                vars.get("MAILER")
+                    .map(String::as_str)
+                    .or(cfg!(debug_assertions).then_some("log"))
+                    .expect("MAILER is required in release builds"),
+                &vars,
+            )
+            .expect("Invalid mailer configuration"),
+            password_reset_lifetime: Duration::minutes(
+                vars.get("PASSWORD_RESET_EXPIRY_MINUTES")
+                    .map(|minutes| {
+                        minutes
+                            .parse()
+                            .expect("PASSWORD_RESET_EXPIRY_MINUTES must be a number")
+                    })
+                    .unwrap_or(DEFAULT_PASSWORD_RESET_EXPIRY_MINUTES),
+            ),
+        }
+    }
+}
+
+impl ImageStoreConfig {
+    /// Reads the settings for the named image store backend
+    ///
+    /// ### Arguments
+    ///
+    /// * `backend` - The name of the backend, one of "postgres", "filesystem" or "s3"
+    /// * `vars` - The variables from the .env file
+    ///
+    /// ### Returns
+    ///
+    /// The backend's settings, or a description of what's wrong with them
+    pub fn from_vars(
+        backend: &str,
+        vars: &HashMap<String, String>,
+    ) -> Result<ImageStoreConfig, String> {
+        let var = |name: &str| match vars.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => Err(format!("{name} is required for the {backend} image store")),
+        };
+
+        match backend {
+            "postgres" => Ok(ImageStoreConfig::Postgres),
+            "filesystem" => Ok(ImageStoreConfig::Filesystem {
+                root: PathBuf::from(var("IMAGE_STORE_PATH")?),
+            }),
+            "s3" => Ok(ImageStoreConfig::S3 {
+                bucket: var("S3_BUCKET")?,
+                region: var("S3_REGION").unwrap_or_else(|_| String::from("us-east-1")),
+                endpoint: var("S3_ENDPOINT")?,
+                access_key: var("S3_ACCESS_KEY")?,
+                secret_key: var("S3_SECRET_KEY")?,
+            }),
+            _ => Err(format!("Unknown image store {backend}")),
+        }
+    }
+}
+
+impl MailerConfig {
+    /// Reads the settings for the named way of sending emails
+    ///
+    /// ### Arguments
+    ///
+    /// * `mailer` - The name of the mailer, one of "log", "file" or "smtp"
+    /// * `vars` - The variables from the .env file
+    ///
+    /// ### Returns
+    ///
+    /// The mailer's settings, or a description of what's wrong with them
+    pub fn from_vars(mailer: &str, vars: &HashMap<String, String>) -> Result<MailerConfig, String> {
+        let var = |name: &str| match vars.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => Err(format!("{name} is required for the {mailer} mailer")),
+        };
+
+        match mailer {
+            "log" => Ok(MailerConfig::Log),
+            "file" => Ok(MailerConfig::File {
+                path: PathBuf::from(
+                    var("MAIL_FILE_PATH").unwrap_or_else(|_| String::from(DEFAULT_MAIL_FILE_PATH)),
+                ),
+            }),
+            "smtp" => Ok(MailerConfig::Smtp {
+                host: var("SMTP_HOST")?,
+                port: match vars.get("SMTP_PORT") {
+                    Some(port) => port
+                        .parse()
+                        .map_err(|_| format!("{port} isn't a port number"))?,
+                    None => DEFAULT_SMTP_PORT,
+                },
+                username: var("SMTP_USERNAME").ok(),
+                password: var("SMTP_PASSWORD").ok(),
+                from: var("MAIL_FROM")?,
+            }),
+            _ => Err(format!("Unknown mailer {mailer}")),
+        }
+    }
+}
+
+impl OAuthProviderConfig {
+    /// Reads the settings for the named OAuth provider, from variables named after it,
+    /// e.g. OAUTH_GOOGLE_CLIENT_ID for the provider "google"
+    ///
+    /// ### Arguments
+    ///
+    /// * `name` - The name of the provider, which appears in its urls
+    /// * `vars` - The variables from the .env file
+    ///
+    /// ### Returns
+    ///
+    /// The provider's settings, or a description of what's wrong with them
+    pub fn from_vars(
+        name: &str,
+        vars: &HashMap<String, String>,
+    ) -> Result<OAuthProviderConfig, String> {
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(format!(
+                "OAuth provider names can only have lowercase letters, digits and -, not {name}"
+            ));
+        }
+        let prefix = format!("OAUTH_{}", name.to_ascii_uppercase().replace('-', "_"));
+        let var = |setting: &str| match vars.get(&format!("{prefix}_{setting}")) {
+            Some(value) => Ok(value.clone()),
+            None => Err(format!("{prefix}_{setting} is required for {name}")),
+        };
+
+        Ok(OAuthProviderConfig {
+            client_id: var("CLIENT_ID")?,
+            client_secret: var("CLIENT_SECRET")?,
+            auth_url: var("AUTH_URL")?,
+            token_url: var("TOKEN_URL")?,
+            userinfo_url: var("USERINFO_URL")?,
+            scopes: var("SCOPES")
+                .unwrap_or_else(|_| String::from(DEFAULT_OAUTH_SCOPES))
+                .split_whitespace()
+                .map(String::from)
+                .collect(),
+            trust_email: var("TRUST_EMAIL").is_ok_and(|trust| trust == "true"),
+        })
+    }
+}
+
+impl FileSizeLimits {
+    /// Reads the limits from a list like "application/pdf=20,audio/*=50,*=10", in MiB
+    ///
+    /// ### Arguments
+    ///
+    /// * `limits` - The comma separated list of types and their limits
+    ///
+    /// ### Returns
+    ///
+    /// The limits, or a description of what's wrong with them
+    pub fn parse(limits: &str) -> Result<FileSizeLimits, String> {
+        limits
+            .split(',')
+            .map(|limit| {
+                let (mime_type, mib) = limit
+                    .split_once('=')
+                    .ok_or(format!("{limit} should look like type=MiB"))?;
+                let mib: u64 = mib
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("{mib} isn't a number of MiB"))?;
+                Ok((mime_type.trim().to_ascii_lowercase(), mib * 1024 * 1024))
+            })
+            .collect::<Result<_, String>>()
+            .map(FileSizeLimits)
+    }
+
+    /// Gets the largest attachment of the given type that can be uploaded. The most
+    /// specific matching limit applies, and types without one can't be uploaded at all
+    ///
+    /// ### Arguments
+    ///
+    /// * `mime_type` - The type of the attachment
+    pub fn limit(&self, mime_type: &str) -> u64 {
+        let mime_type = mime_type.to_ascii_lowercase();
+        let family = mime_type
+            .split_once('/')
+            .map(|(top, _)| format!("{top}/*"))
+            .unwrap_or_default();
+
+        [mime_type.as_str(), family.as_str(), "*"]
+            .iter()
+            .find_map(|candidate| {
+                self.0
+                    .iter()
+                    .find(|(limit_type, _)| limit_type == candidate)
+                    .map(|(_, limit)| *limit)
+            })
+            .unwrap_or(0)
+    }
+
+    /// The largest attachment of any type that can be uploaded
+    pub fn max(&self) -> u64 {
+        self.0.iter().map(|(_, limit)| *limit).max().unwrap_or(0)
+    }
+}
+
+/// Reads the variables out of the .env file
+///
+/// ### Panics
+///
+/// If the .env file is missing or can't be parsed
+pub fn read_vars() -> HashMap<String, String> {
+    env_file_reader::read_file(ENV_FILE).expect("Failed to find/parse env file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn the_most_specific_file_size_limit_applies() {
+        let limits = FileSizeLimits::parse("application/pdf=20, Audio/*=50,*=10").unwrap();
+        assert_eq!(limits.limit("application/pdf"), 20 * MIB);
+        assert_eq!(limits.limit("audio/MPEG"), 50 * MIB);
+        assert_eq!(limits.limit("text/plain"), 10 * MIB);
+        assert_eq!(limits.max(), 50 * MIB);
+    }
+
+    #[test]
+    fn types_without_a_file_size_limit_are_refused() {
+        let limits = FileSizeLimits::parse("application/pdf=20").unwrap();
+        assert_eq!(limits.limit("audio/mpeg"), 0);
+    }
+
+    #[test]
+    fn invalid_file_size_limits_are_rejected() {
+        assert!(FileSizeLimits::parse("application/pdf").is_err());
+        assert!(FileSizeLimits::parse("application/pdf=lots").is_err());
+        assert!(FileSizeLimits::parse("application/pdf=20,").is_err());
+    }
+}
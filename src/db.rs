@@ -1,7 +1,12 @@
 use rocket::http::Status;
 use sqlx::{pool::PoolConnection, PgPool, Postgres};
 
+pub mod file;
+pub mod image;
 pub mod note;
+pub mod oauth;
+pub mod prompt;
+pub mod usage;
 pub mod user;
 
 /// A single database connection that can be used for queries (pass in &mut DbConn)
@@ -0,0 +1,128 @@
+use crate::db::DbConn;
+use sqlx::PgConnection;
+
+/// Fields required for recording a new attachment
+pub struct NewFile<'a> {
+    /// The file's name on the uploader's machine
+    pub name: &'a str,
+    pub mime_type: &'a str,
+    /// The length of the file in bytes
+    pub size: i32,
+    /// The name of the store backend its bytes will be kept in
+    pub storage: &'a str,
+}
+
+/// Everything we know about an attachment besides its bytes, which live in the attachment store
+pub struct FileRecord {
+    pub id: i32,
+    pub name: String,
+    pub mime_type: String,
+    pub size: i32,
+    /// The name of the store backend its bytes are kept in
+    pub storage: String,
+}
+
+/// Records a new attachment for the user. It's pending, and can't be seen until it's
+/// marked as stored
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the attachments, usually the
+///   transaction that checked the user's quota
+/// * `user_id` - The id of the user that owns the attachment
+/// * `file` - The attachment being recorded
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise the id of the attachment
+pub async fn create(
+    conn: &mut PgConnection,
+    user_id: i32,
+    file: &NewFile<'_>,
+) -> Result<i32, sqlx::Error> {
+    let record = sqlx::query!(
+        "INSERT INTO files (user_id, name, mime_type, size_bytes, storage, pending) VALUES ($1, $2, $3, $4, $5, true) RETURNING id",
+        user_id,
+        file.name,
+        file.mime_type,
+        file.size,
+        file.storage
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(record.id)
+}
+
+/// Marks a pending attachment as stored, now its bytes are in the attachment store, so
+/// it can be seen
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the attachments
+/// * `id` - The id of the attachment
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database
+pub async fn mark_stored(mut conn: DbConn, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE files SET pending = false WHERE id = $1", id)
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Gets the attachment with the given id owned by the user
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the attachments
+/// * `user_id` - The id of the user that owns the attachment
+/// * `id` - The id of the attachment
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if the user has no such attachment
+/// (or it's still being stored), otherwise the attachment's record
+pub async fn get(
+    mut conn: DbConn,
+    user_id: i32,
+    id: i32,
+) -> Result<Option<FileRecord>, sqlx::Error> {
+    let record = sqlx::query!(
+        "SELECT id, name, mime_type, size_bytes, storage FROM files WHERE id = $1 AND user_id = $2 AND NOT pending",
+        id,
+        user_id
+    )
+    .fetch_optional(&mut conn)
+    .await?;
+
+    Ok(record.map(|record| FileRecord {
+        id: record.id,
+        name: record.name,
+        mime_type: record.mime_type,
+        size: record.size_bytes,
+        storage: record.storage,
+    }))
+}
+
+/// Deletes the record of an attachment. Its bytes need to be removed from the attachment
+/// store separately
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the attachments
+/// * `id` - The id of the attachment
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, true if the attachment was deleted, false
+/// if we couldn't find an attachment to delete
+pub async fn delete(mut conn: DbConn, id: i32) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!("DELETE FROM files WHERE id = $1", id)
+        .execute(&mut conn)
+        .await?;
+
+    Ok(res.rows_affected() != 0)
+}
@@ -0,0 +1,524 @@
+use crate::db::{note::PageSize, DbConn};
+use rocket::time::{format_description::well_known, OffsetDateTime};
+use serde::Serialize;
+use sqlx::{Connection, PgConnection};
+
+/// An image file and its type
+pub struct StoredImage {
+    pub id: i32,
+    pub bytes: Vec<u8>,
+    pub mime_type: String,
+}
+
+/// Everything we know about an image besides its bytes, which live in the image store
+pub struct ImageRecord {
+    pub id: i32,
+    pub mime_type: String,
+    /// The hex SHA-256 hash of the image's bytes, if it was uploaded after we started hashing them
+    pub sha256: Option<String>,
+    /// The name of the image store backend its bytes are kept in
+    pub storage: String,
+    /// The image's size in pixels, if it was uploaded after we started recording it
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+/// A generated variant of an image, whose bytes live in the variant store
+pub struct VariantRecord {
+    pub id: i32,
+    pub mime_type: String,
+    /// The name of the image store backend its bytes are kept in
+    pub storage: String,
+}
+
+/// Fields required for recording a new variant
+pub struct NewVariant<'a> {
+    /// The key identifying the variant
+    pub key: &'a str,
+    pub mime_type: &'a str,
+    /// The name of the image store backend its bytes will be kept in
+    pub storage: &'a str,
+    /// The length of the variant's file in bytes
+    pub size: i32,
+}
+
+/// Fields required for recording a new image
+pub struct NewImage<'a> {
+    pub mime_type: &'a str,
+    /// The name of the image store backend its bytes will be kept in
+    pub storage: &'a str,
+    /// The hex SHA-256 hash of the image's bytes
+    pub sha256: &'a str,
+    /// The length of the image file in bytes
+    pub size: i32,
+    pub width: i32,
+    pub height: i32,
+    /// A tiny, blurred placeholder for the image
+    pub blurhash: Option<&'a str>,
+    /// When the photo was taken, if we know
+    pub captured_at: Option<OffsetDateTime>,
+}
+
+/// What recording an upload did
+pub struct CreatedImage {
+    pub id: i32,
+    /// Whether it's a new image, rather than one they'd already uploaded
+    pub is_new: bool,
+    /// Whether the image's bytes still need to be put in the image store. They do for
+    /// new images, and for existing ones whose first upload hasn't finished storing them
+    pub pending: bool,
+    /// The name of the image store backend its bytes are kept in
+    pub storage: String,
+}
+
+/// Everything the user can see about one of their images
+#[derive(Serialize)]
+pub struct ImageDetails {
+    id: i32,
+    mime_type: String,
+    /// The length of the image file in bytes
+    size: Option<i32>,
+    width: Option<i32>,
+    height: Option<i32>,
+    /// A tiny, blurred placeholder for the image, if it was uploaded after we made them
+    blurhash: Option<String>,
+    uploaded_at: String,
+    captured_at: Option<String>,
+    /// The ids of the user's notes that use the image
+    notes: Vec<i32>,
+}
+
+/// Records a new image for the user. If they've already uploaded an image with the
+/// same bytes, we add a reference to that one instead of recording a duplicate. New
+/// images are pending, and can't be seen until they're marked as stored
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images, usually the transaction
+///   that checked the user's quota
+/// * `user_id` - The id of the user that owns the image
+/// * `image` - The image being recorded
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise what was recorded
+pub async fn create(
+    conn: &mut PgConnection,
+    user_id: i32,
+    image: &NewImage<'_>,
+) -> Result<CreatedImage, sqlx::Error> {
+    // xmax is only 0 for rows this statement inserted
+    let record = sqlx::query!(
+        r#"INSERT INTO images (user_id, mime_type, storage, sha256, size_bytes, width, height, blurhash, captured_at, pending)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, true)
+        ON CONFLICT (user_id, sha256) DO UPDATE
+        SET reference_count = images.reference_count + 1, unreferenced_since = NULL
+        RETURNING id, (xmax = 0) AS "inserted!", pending, storage"#,
+        user_id,
+        image.mime_type,
+        image.storage,
+        image.sha256,
+        image.size,
+        image.width,
+        image.height,
+        image.blurhash,
+        image.captured_at
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(CreatedImage {
+        id: record.id,
+        is_new: record.inserted,
+        pending: record.pending,
+        storage: record.storage,
+    })
+}
+
+/// Checks whether the user has already uploaded an image with the given bytes. Uploading
+/// it again only adds a reference to the existing image, so takes up no more space
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images
+/// * `user_id` - The id of the user uploading the image
+/// * `sha256` - The hex SHA-256 hash of the image's bytes
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise whether they have
+pub async fn exists(
+    conn: &mut PgConnection,
+    user_id: i32,
+    sha256: &str,
+) -> Result<bool, sqlx::Error> {
+    let exists = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM images WHERE user_id = $1 AND sha256 = $2) AS "exists!""#,
+        user_id,
+        sha256
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(exists)
+}
+
+/// Marks a pending image as stored, now its bytes are in the image store, so it can be seen
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images
+/// * `id` - The id of the image
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database
+pub async fn mark_stored(mut conn: DbConn, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE images SET pending = false WHERE id = $1", id)
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Removes the reference an upload added to an image, for when we couldn't store its
+/// bytes. The image's record is only deleted if it's still pending and no other
+/// upload holds a reference to it, as they may yet store its bytes
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images
+/// * `id` - The id of the image
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database
+pub async fn remove_upload(mut conn: DbConn, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE images SET reference_count = reference_count - 1 WHERE id = $1",
+        id
+    )
+    .execute(&mut conn)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM images WHERE id = $1 AND pending AND reference_count <= 0",
+        id
+    )
+    .execute(&mut conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Gets the image with the given id owned by the user
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images
+/// * `user_id` - The id of the user that owns the image
+/// * `id` - The id of the image
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if the user has no such image,
+/// otherwise the image's record
+pub async fn get(
+    mut conn: DbConn,
+    user_id: i32,
+    id: i32,
+) -> Result<Option<ImageRecord>, sqlx::Error> {
+    let record = sqlx::query!(
+        "SELECT id, mime_type, sha256, storage, width, height FROM images WHERE id = $1 AND user_id = $2 AND NOT pending",
+        id,
+        user_id
+    )
+    .fetch_optional(&mut conn)
+    .await?;
+
+    Ok(record.map(|record| ImageRecord {
+        id: record.id,
+        mime_type: record.mime_type,
+        sha256: record.sha256,
+        storage: record.storage,
+        width: record.width,
+        height: record.height,
+    }))
+}
+
+/// Gets a page of the user's images, most recently uploaded first
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images
+/// * `user_id` - The id of the user that owns the images
+/// * `page` - The page number we're hoping to grab images from
+/// * `page_size` - The max number of images per page
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise the page of images, and
+/// whether there are more after it
+pub async fn get_page(
+    mut conn: DbConn,
+    user_id: i32,
+    page: i32,
+    page_size: PageSize,
+) -> Result<(Vec<ImageDetails>, bool), sqlx::Error> {
+    // Images uploaded before we recorded their size only know it if they're stored here
+    let mut records = sqlx::query!(
+        r#"SELECT id, mime_type, COALESCE(size_bytes, octet_length(image)) AS size, width, height, blurhash, created_at, captured_at,
+            ARRAY(
+                SELECT notes.id FROM notes
+                WHERE notes.user_id = images.user_id AND notes.content ~ ('/api/images/' || images.id || '([^0-9]|$)')
+                ORDER BY notes.id
+            ) AS "notes!"
+        FROM images WHERE user_id = $1 AND NOT pending
+        ORDER BY created_at desc, id desc LIMIT $2 OFFSET $3"#,
+        user_id,
+        (page_size.0 + 1) as i64,
+        (page as i64) * (page_size.0 as i64)
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    // Have we hit the last result?
+    let more_available = records.len() as i32 == (page_size.0 + 1);
+
+    // Remove our buffer elem for testing if we've got more results
+    if more_available {
+        records.pop();
+    }
+
+    let images = records
+        .into_iter()
+        .map(|record| ImageDetails {
+            id: record.id,
+            mime_type: record.mime_type,
+            size: record.size,
+            width: record.width,
+            height: record.height,
+            blurhash: record.blurhash,
+            uploaded_at: record
+                .created_at
+                .format(&well_known::Iso8601::DEFAULT)
+                .unwrap(),
+            captured_at: record
+                .captured_at
+                .map(|captured_at| captured_at.format(&well_known::Iso8601::DEFAULT).unwrap()),
+            notes: record.notes,
+        })
+        .collect();
+
+    Ok((images, more_available))
+}
+
+/// Gets the ids of the user's notes that use an image
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images and notes
+/// * `user_id` - The id of the user that owns the image
+/// * `id` - The id of the image
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise the ids of the notes
+pub async fn get_referencing_notes(
+    mut conn: DbConn,
+    user_id: i32,
+    id: i32,
+) -> Result<Vec<i32>, sqlx::Error> {
+    let records = sqlx::query!(
+        "SELECT id FROM notes WHERE user_id = $1 AND content ~ ('/api/images/' || $2::integer || '([^0-9]|$)') ORDER BY id",
+        user_id,
+        id
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(records.into_iter().map(|record| record.id).collect())
+}
+
+/// Gets all the images with the given ids owned by the user
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images
+/// * `user_id` - The id of the user that owns the images
+/// * `ids` - The ids of the images we're after. Ids of images that don't exist
+///   (or belong to someone else) are ignored
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise the records of the images we found
+pub async fn get_many(
+    mut conn: DbConn,
+    user_id: i32,
+    ids: &[i32],
+) -> Result<Vec<ImageRecord>, sqlx::Error> {
+    let records = sqlx::query!(
+        "SELECT id, mime_type, sha256, storage, width, height FROM images WHERE user_id = $1 AND id = ANY($2) AND NOT pending",
+        user_id,
+        ids
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|record| ImageRecord {
+            id: record.id,
+            mime_type: record.mime_type,
+            sha256: record.sha256,
+            storage: record.storage,
+            width: record.width,
+            height: record.height,
+        })
+        .collect())
+}
+
+/// Deletes the record of an image, along with the records of its variants. The bytes
+/// of both need to be removed from their stores separately
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images
+/// * `id` - The id of the image
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if we couldn't find an image to
+/// delete, otherwise the variants that were deleted with it
+pub async fn delete(mut conn: DbConn, id: i32) -> Result<Option<Vec<VariantRecord>>, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+    let variants = delete_variants(&mut tx, id).await?;
+    let res = sqlx::query!("DELETE FROM images WHERE id = $1", id)
+        .execute(&mut tx)
+        .await?;
+    if res.rows_affected() == 0 {
+        return Ok(None);
+    }
+    tx.commit().await?;
+
+    Ok(Some(variants))
+}
+
+/// Deletes the records of every variant of an image
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images, usually in the
+///   transaction deleting the image
+/// * `image_id` - The id of the original image
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise the variants that were deleted,
+/// whose bytes need removing from their stores
+pub async fn delete_variants(
+    conn: &mut PgConnection,
+    image_id: i32,
+) -> Result<Vec<VariantRecord>, sqlx::Error> {
+    let records = sqlx::query!(
+        "DELETE FROM image_variants WHERE image_id = $1 RETURNING id, mime_type, storage",
+        image_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|record| VariantRecord {
+            id: record.id,
+            mime_type: record.mime_type,
+            storage: record.storage,
+        })
+        .collect())
+}
+
+/// Gets the record of a previously generated variant (resized/converted version) of an
+/// image
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images
+/// * `image_id` - The id of the original image
+/// * `key` - The key identifying the variant
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if we haven't generated that
+/// variant yet, otherwise the variant's record
+pub async fn get_variant(
+    mut conn: DbConn,
+    image_id: i32,
+    key: &str,
+) -> Result<Option<VariantRecord>, sqlx::Error> {
+    let record = sqlx::query!(
+        "SELECT id, mime_type, storage FROM image_variants WHERE image_id = $1 AND variant = $2",
+        image_id,
+        key
+    )
+    .fetch_optional(&mut conn)
+    .await?;
+
+    Ok(record.map(|record| VariantRecord {
+        id: record.id,
+        mime_type: record.mime_type,
+        storage: record.storage,
+    }))
+}
+
+/// Records a generated variant of an image, so it doesn't have to be generated again.
+/// Its bytes need putting in the variant store afterwards
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images, usually the transaction
+///   that checked the user's quota
+/// * `image_id` - The id of the original image
+/// * `variant` - The variant's key, type, size and where it'll be stored
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if the variant's already been
+/// recorded, otherwise the new variant's id
+pub async fn create_variant(
+    conn: &mut PgConnection,
+    image_id: i32,
+    variant: &NewVariant<'_>,
+) -> Result<Option<i32>, sqlx::Error> {
+    let id = sqlx::query_scalar!(
+        "INSERT INTO image_variants (image_id, variant, mime_type, storage, size_bytes)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (image_id, variant) DO NOTHING
+        RETURNING id",
+        image_id,
+        variant.key,
+        variant.mime_type,
+        variant.storage,
+        variant.size
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(id)
+}
+
+/// Deletes the record of a variant, when its bytes couldn't be stored
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the images
+/// * `id` - The id of the variant
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database
+pub async fn delete_variant(mut conn: DbConn, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM image_variants WHERE id = $1", id)
+        .execute(&mut conn)
+        .await?;
+
+    Ok(())
+}
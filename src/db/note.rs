@@ -1,7 +1,8 @@
 use crate::db::DbConn;
 use chrono::Utc;
-use rocket::time::format_description::well_known;
+use rocket::time::{format_description::well_known, Date, Duration, OffsetDateTime};
 use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
 
 /// A type-safe integer for the number of notes we're allowed to select at once
 pub struct PageSize(pub i32);
@@ -26,6 +27,140 @@ impl PageSize {
     }
 }
 
+/// The range of scores a diary entry's mood can be given
+pub const MIN_MOOD_SCORE: i16 = 1;
+pub const MAX_MOOD_SCORE: i16 = 10;
+
+/// How far into the future a diary entry's created_at can be, to allow for clients
+/// whose clocks are running a little fast
+pub const CREATED_AT_LEEWAY: Duration = Duration::minutes(5);
+
+/// Limits on the tags a note can be given
+pub const MAX_TAGS: usize = 20;
+pub const MAX_TAG_LEN: usize = 50;
+
+/// How the writer was feeling, as a score and an optional label (e.g. "content")
+#[derive(Serialize, Deserialize)]
+pub struct Mood {
+    score: i16,
+    label: Option<String>,
+}
+impl Mood {
+    /// Checks the score falls within the allowed range
+    fn is_valid(&self) -> bool {
+        (MIN_MOOD_SCORE..=MAX_MOOD_SCORE).contains(&self.score)
+    }
+}
+
+/// Where a diary entry was written
+#[derive(Serialize, Deserialize)]
+pub struct Location {
+    name: String,
+    lat: f64,
+    lng: f64,
+}
+impl Location {
+    /// Checks the coordinates are somewhere on Earth
+    fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.lat) && (-180.0..=180.0).contains(&self.lng)
+    }
+}
+
+/// Structured metadata that can be attached to a diary entry. Every field is optional
+#[derive(Serialize, Deserialize, Default)]
+pub struct DiaryMetadata {
+    mood: Option<Mood>,
+    weather: Option<String>,
+    location: Option<Location>,
+}
+impl DiaryMetadata {
+    /// Builds the metadata from the nullable columns it's stored in
+    ///
+    /// ### Arguments
+    ///
+    /// * `mood_score` - The mood score column, there's no mood without one
+    /// * `mood_label` - The mood label column
+    /// * `weather` - The weather column
+    /// * `location_name` - The location name column
+    /// * `location_lat` - The location latitude column
+    /// * `location_lng` - The location longitude column
+    pub fn from_columns(
+        mood_score: Option<i16>,
+        mood_label: Option<String>,
+        weather: Option<String>,
+        location_name: Option<String>,
+        location_lat: Option<f64>,
+        location_lng: Option<f64>,
+    ) -> DiaryMetadata {
+        let mood = mood_score.map(|score| Mood {
+            score,
+            label: mood_label,
+        });
+
+        // A location is only useful if we've got all of it
+        let location = match (location_name, location_lat, location_lng) {
+            (Some(name), Some(lat), Some(lng)) => Some(Location { name, lat, lng }),
+            _ => None,
+        };
+
+        DiaryMetadata {
+            mood,
+            weather,
+            location,
+        }
+    }
+
+    /// Checks the mood score and coordinates fall within their allowed ranges
+    ///
+    /// ### Returns
+    ///
+    /// true if the metadata can be stored, false otherwise
+    pub fn is_valid(&self) -> bool {
+        self.mood.as_ref().is_none_or(Mood::is_valid)
+            && self.location.as_ref().is_none_or(Location::is_valid)
+    }
+}
+
+/// Changes to a diary entry's metadata. A missing field is left as it is, while an
+/// explicit null clears it
+#[derive(Deserialize, Default)]
+pub struct DiaryMetadataUpdate {
+    #[serde(default, deserialize_with = "present")]
+    mood: Option<Option<Mood>>,
+    #[serde(default, deserialize_with = "present")]
+    weather: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    location: Option<Option<Location>>,
+}
+impl DiaryMetadataUpdate {
+    /// Checks the new mood score and coordinates fall within their allowed ranges
+    ///
+    /// ### Returns
+    ///
+    /// true if the metadata can be stored, false otherwise
+    pub fn is_valid(&self) -> bool {
+        self.mood
+            .as_ref()
+            .and_then(Option::as_ref)
+            .is_none_or(Mood::is_valid)
+            && self
+                .location
+                .as_ref()
+                .and_then(Option::as_ref)
+                .is_none_or(Location::is_valid)
+    }
+}
+
+/// Deserializes a field that's present, so a null can be told apart from the field
+/// being missing (which serde's default leaves as None)
+fn present<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
 /// A note, and all the information that comes with it
 #[derive(Serialize, Deserialize)]
 pub struct Note {
@@ -36,6 +171,13 @@ pub struct Note {
     content: String,
     is_diary: bool,
     created_at: String,
+    #[serde(flatten)]
+    metadata: DiaryMetadata,
+    tags: Vec<String>,
+    /// The writing prompt the note was written in answer to
+    prompt_id: Option<i32>,
+    unlock_at: Option<String>,
+    locked: bool,
 }
 impl Note {
     /// Creates a new note
@@ -47,6 +189,12 @@ impl Note {
     /// * `update_time` - The timestamp of when the note was last updated
     /// * `favourite` - if the note has been favourited
     /// * `content` - The encoded string content of the note
+    /// * `metadata` - The mood, weather and location of the note (if it's a diary entry)
+    /// * `tags` - The tags the note has been given
+    /// * `prompt_id` - The id of the writing prompt the note answered, if any
+    /// * `unlock_at` - When the note can first be read. Until then its title, content,
+    ///   metadata, tags and prompt are withheld
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: i32,
         title: String,
@@ -55,7 +203,25 @@ impl Note {
         content: String,
         is_diary: bool,
         created_at: String,
+        metadata: DiaryMetadata,
+        tags: Vec<String>,
+        prompt_id: Option<i32>,
+        unlock_at: Option<OffsetDateTime>,
     ) -> Note {
+        // Locked notes are reduced to a stub, no matter who's asking. Tags and prompts
+        // are written by the user too, so they can give away what's inside
+        let locked = is_locked(unlock_at);
+        let (title, content, metadata, tags, prompt_id) = match locked {
+            true => (
+                String::new(),
+                String::new(),
+                DiaryMetadata::default(),
+                vec![],
+                None,
+            ),
+            false => (title, content, metadata, tags, prompt_id),
+        };
+
         Note {
             id,
             title,
@@ -64,6 +230,12 @@ impl Note {
             content,
             is_diary,
             created_at,
+            metadata,
+            tags,
+            prompt_id,
+            unlock_at: unlock_at
+                .map(|unlock_at| unlock_at.format(&well_known::Iso8601::DEFAULT).unwrap()),
+            locked,
         }
     }
 }
@@ -77,6 +249,8 @@ pub struct NoteOverview {
     title: String,
     is_diary: bool,
     created_at: String,
+    unlock_at: Option<String>,
+    locked: bool,
 }
 impl NoteOverview {
     /// Creates a new note overview
@@ -87,6 +261,7 @@ impl NoteOverview {
     /// * `title` - The title of the note
     /// * `update_time` - The timestamp of when the note was last updated
     /// * `favourite` - if the note has been favourited
+    /// * `unlock_at` - When the note can first be read. Until then its title is withheld
     pub fn new(
         id: i32,
         title: String,
@@ -94,7 +269,14 @@ impl NoteOverview {
         favourite: bool,
         is_diary: bool,
         created_at: String,
+        unlock_at: Option<OffsetDateTime>,
     ) -> NoteOverview {
+        let locked = is_locked(unlock_at);
+        let title = match locked {
+            true => String::new(),
+            false => title,
+        };
+
         NoteOverview {
             id,
             title,
@@ -102,6 +284,9 @@ impl NoteOverview {
             favourite,
             is_diary,
             created_at,
+            unlock_at: unlock_at
+                .map(|unlock_at| unlock_at.format(&well_known::Iso8601::DEFAULT).unwrap()),
+            locked,
         }
     }
 }
@@ -112,6 +297,32 @@ pub struct UpdateNoteInfo {
     title: Option<String>,
     content: Option<String>,
     favourite: Option<bool>,
+    #[serde(flatten)]
+    metadata: DiaryMetadataUpdate,
+    /// Replaces all of the note's tags
+    tags: Option<Vec<String>>,
+    /// An ISO-8601 timestamp, the note can't be read or updated until then
+    unlock_at: Option<String>,
+    /// An ISO-8601 timestamp, for when the note was written (e.g. backdating a diary entry)
+    created_at: Option<String>,
+}
+impl UpdateNoteInfo {
+    /// Checks the update only contains values we're willing to store. Whether the
+    /// created_at is allowed depends on the note being updated, so that's checked later
+    pub fn is_valid(&self) -> bool {
+        self.metadata.is_valid()
+            && tags_valid(self.tags.as_deref())
+            && parse_timestamp(self.unlock_at.as_deref()).is_ok()
+            && parse_timestamp(self.created_at.as_deref()).is_ok()
+    }
+
+    /// The bytes the note's new title and content will use, for the ones being updated
+    pub fn sizes(&self) -> (Option<i64>, Option<i64>) {
+        (
+            self.title.as_ref().map(|title| title.len() as i64),
+            self.content.as_ref().map(|content| content.len() as i64),
+        )
+    }
 }
 
 /// Fields required for creating a new note. We only need the content due to
@@ -122,6 +333,106 @@ pub struct CreateNoteInfo {
     content: String,
     favourite: Option<bool>,
     is_diary: Option<bool>,
+    #[serde(flatten)]
+    metadata: DiaryMetadata,
+    tags: Option<Vec<String>>,
+    /// The id of the writing prompt the note answers. Ignored if the prompt isn't
+    /// available to the user
+    prompt_id: Option<i32>,
+    /// An ISO-8601 timestamp, the note can't be read or updated until then
+    unlock_at: Option<String>,
+    /// An ISO-8601 timestamp, for when the note was written. Defaults to now
+    created_at: Option<String>,
+}
+impl CreateNoteInfo {
+    /// Checks the new note only contains values we're willing to store
+    pub fn is_valid(&self) -> bool {
+        let created_at = match parse_timestamp(self.created_at.as_deref()) {
+            Ok(created_at) => created_at,
+            Err(_) => return false,
+        };
+
+        self.metadata.is_valid()
+            && tags_valid(self.tags.as_deref())
+            && parse_timestamp(self.unlock_at.as_deref()).is_ok()
+            && created_at_valid(created_at, self.is_diary.unwrap_or(false))
+    }
+
+    /// The bytes the note's title and content will use
+    pub fn size(&self) -> i64 {
+        (self.title.as_deref().unwrap_or_default().len() + self.content.len()) as i64
+    }
+}
+
+/// The result of trying to update a note
+pub enum UpdateOutcome {
+    /// The note was updated at the given time
+    Updated(i64),
+    /// No such note exists
+    NotFound,
+    /// The note is a time capsule that hasn't unlocked yet
+    Locked,
+    /// The update isn't valid for this note (e.g. a diary entry created in the future)
+    Invalid,
+    /// The note exists, but we failed to update it
+    Failed,
+}
+
+/// Filters that can be applied when fetching diary notes. Every field is optional
+#[derive(FromForm, Default)]
+pub struct DiaryFilter {
+    /// Only include entries with at least this mood score
+    mood_min: Option<i16>,
+    /// Only include entries with at most this mood score
+    mood_max: Option<i16>,
+    /// Only include entries with this weather (case-insensitive)
+    weather: Option<String>,
+    /// Only include entries whose location name contains this (case-insensitive)
+    location: Option<String>,
+}
+impl DiaryFilter {
+    /// The weather filter as an ILIKE pattern, matching the weather exactly
+    fn weather_pattern(&self) -> Option<String> {
+        self.weather.as_deref().map(escape_like)
+    }
+
+    /// The location filter as an ILIKE pattern, matching any name containing it
+    fn location_pattern(&self) -> Option<String> {
+        self.location
+            .as_deref()
+            .map(|location| format!("%{}%", escape_like(location)))
+    }
+}
+
+/// The period of time mood scores are averaged over
+#[derive(FromFormField, Clone, Copy)]
+pub enum MoodBucket {
+    #[field(value = "day")]
+    Day,
+    #[field(value = "week")]
+    Week,
+    #[field(value = "month")]
+    Month,
+}
+impl MoodBucket {
+    /// The name postgres' date_trunc uses for the bucket
+    fn as_str(&self) -> &'static str {
+        match self {
+            MoodBucket::Day => "day",
+            MoodBucket::Week => "week",
+            MoodBucket::Month => "month",
+        }
+    }
+}
+
+/// The average mood of the diary entries written within a single period
+#[derive(Serialize)]
+pub struct MoodPoint {
+    /// The start of the period
+    period: String,
+    average: f64,
+    /// How many entries with a mood were written in the period
+    entries: i64,
 }
 
 /// Gets the current timestamp
@@ -129,19 +440,124 @@ fn now() -> i64 {
     Utc::now().timestamp_millis()
 }
 
+/// Checks there aren't too many tags, and that none of them are empty or too long
+///
+/// ### Arguments
+///
+/// * `tags` - The tags we're hoping to give a note, if any
+fn tags_valid(tags: Option<&[String]>) -> bool {
+    let tags = tags.unwrap_or_default();
+    tags.len() <= MAX_TAGS
+        && tags
+            .iter()
+            .all(|tag| !tag.is_empty() && tag.chars().count() <= MAX_TAG_LEN)
+}
+
+/// Checks a note is allowed to have been created at the given time. Diary entries
+/// can be backdated, but can't be written in the future
+///
+/// ### Arguments
+///
+/// * `created_at` - The time the note was created, or None if it's being created now
+/// * `is_diary` - Whether the note is a diary entry
+fn created_at_valid(created_at: Option<OffsetDateTime>, is_diary: bool) -> bool {
+    match created_at {
+        Some(created_at) if is_diary => created_at <= OffsetDateTime::now_utc() + CREATED_AT_LEEWAY,
+        _ => true,
+    }
+}
+
+/// Escapes the wildcards in text given by a user, so it's matched literally by
+/// LIKE/ILIKE (which use \ as their escape character)
+///
+/// ### Arguments
+///
+/// * `text` - The text to match
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Checks if a note with the given unlock time is still locked
+///
+/// ### Arguments
+///
+/// * `unlock_at` - When the note unlocks, or None if it was never locked
+fn is_locked(unlock_at: Option<OffsetDateTime>) -> bool {
+    unlock_at.is_some_and(|unlock_at| unlock_at > OffsetDateTime::now_utc())
+}
+
+/// Parses an optional ISO-8601 timestamp supplied by a client
+///
+/// ### Arguments
+///
+/// * `timestamp` - the timestamp to parse, if one was given
+///
+/// ### Returns
+///
+/// Error if a timestamp was given but couldn't be parsed, otherwise the parsed timestamp (if any)
+pub fn parse_timestamp(timestamp: Option<&str>) -> Result<Option<OffsetDateTime>, ()> {
+    timestamp
+        .map(|timestamp| OffsetDateTime::parse(timestamp, &well_known::Iso8601::DEFAULT))
+        .transpose()
+        .map_err(|_| ())
+}
+
+/// Checks the time zone is one postgres knows about, e.g. Europe/London
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database
+/// * `tz` - The IANA name of the time zone
+async fn timezone_exists(conn: &mut DbConn, tz: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM pg_timezone_names WHERE name = $1) AS "exists!""#,
+        tz
+    )
+    .fetch_one(conn)
+    .await
+}
+
 /// Grab pages of notes where is_diary is true <- the way we determine if a note
 /// is just a note, or if it's also a diary entry
+///
+/// ### Arguments
+///
+/// * `conn` - The connection to the database in which the notes are stored
+/// * `user_id` - The user id whose diary notes we should be fetching
+/// * `page` - The page number we're hoping to grab notes from
+/// * `page_size` - The max number of notes per page
+/// * `filter` - Only diary notes matching the filter will be returned
 pub async fn get_diary_notes(
     mut conn: DbConn,
     user_id: i32,
     page: i32,
     page_size: PageSize,
+    filter: &DiaryFilter,
 ) -> Result<(Vec<Note>, bool), sqlx::Error> {
     let mut records = sqlx::query!(
-        "SELECT id, title, update_time, favourite, content, created_at FROM notes WHERE user_id = $1 AND is_diary = true ORDER BY created_at desc LIMIT $2 OFFSET $3",
+        "SELECT id, title, update_time, favourite, content, created_at, mood_score, mood_label, weather, location_name, location_lat, location_lng, tags, prompt_id, unlock_at FROM notes
+        WHERE user_id = $1 AND is_diary = true
+            AND (($4::smallint IS NULL AND $5::smallint IS NULL AND $6::text IS NULL AND $7::text IS NULL) OR unlock_at IS NULL OR unlock_at <= CURRENT_TIMESTAMP)
+            AND ($4::smallint IS NULL OR mood_score >= $4)
+            AND ($5::smallint IS NULL OR mood_score <= $5)
+            AND ($6::text IS NULL OR weather ILIKE $6)
+            AND ($7::text IS NULL OR location_name ILIKE $7)
+        ORDER BY created_at desc, id desc LIMIT $2 OFFSET $3",
         user_id,
         (page_size.0 + 1) as i64,
-        (page as i64) * (page_size.0 as i64)
+        (page as i64) * (page_size.0 as i64),
+        filter.mood_min,
+        filter.mood_max,
+        filter.weather_pattern(),
+        filter.location_pattern()
     )
     .fetch_all(&mut conn)
     .await?;
@@ -169,6 +585,17 @@ pub async fn get_diary_notes(
                     .created_at
                     .format(&well_known::Iso8601::DEFAULT)
                     .unwrap(),
+                DiaryMetadata::from_columns(
+                    record.mood_score,
+                    record.mood_label,
+                    record.weather,
+                    record.location_name,
+                    record.location_lat,
+                    record.location_lng,
+                ),
+                record.tags,
+                record.prompt_id,
+                record.unlock_at,
             )
         })
         .collect();
@@ -194,7 +621,7 @@ pub async fn get_overview(
     note_id: i32,
 ) -> Result<Option<NoteOverview>, sqlx::Error> {
     let record = sqlx::query!(
-        "SELECT id, title, update_time, favourite, is_diary, created_at FROM notes WHERE user_id = $1 AND id = $2",
+        "SELECT id, title, update_time, favourite, is_diary, created_at, unlock_at FROM notes WHERE user_id = $1 AND id = $2",
         user_id,
         note_id
     )
@@ -218,6 +645,7 @@ pub async fn get_overview(
             .created_at
             .format(&well_known::Iso8601::DEFAULT)
             .unwrap(),
+        record.unlock_at,
     )))
 }
 
@@ -242,7 +670,7 @@ pub async fn get_overview_many(
     page_size: PageSize,
 ) -> Result<(Vec<NoteOverview>, bool), sqlx::Error> {
     let mut records = sqlx::query!(
-        "SELECT id, title, update_time, favourite, is_diary, created_at FROM notes WHERE user_id = $1 ORDER BY id LIMIT $2 OFFSET $3",
+        "SELECT id, title, update_time, favourite, is_diary, created_at, unlock_at FROM notes WHERE user_id = $1 ORDER BY id LIMIT $2 OFFSET $3",
         user_id,
         (page_size.0 + 1) as i64,
         (page as i64) * (page_size.0 as i64)
@@ -272,6 +700,7 @@ pub async fn get_overview_many(
                     .created_at
                     .format(&well_known::Iso8601::DEFAULT)
                     .unwrap(),
+                record.unlock_at,
             )
         })
         .collect();
@@ -297,7 +726,7 @@ pub async fn get(
     note_id: i32,
 ) -> Result<Option<Note>, sqlx::Error> {
     let record = sqlx::query!(
-        "SELECT id, title, update_time, favourite, content, is_diary, created_at FROM notes WHERE user_id = $1 AND id = $2",
+        "SELECT id, title, update_time, favourite, content, is_diary, created_at, mood_score, mood_label, weather, location_name, location_lat, location_lng, tags, prompt_id, unlock_at FROM notes WHERE user_id = $1 AND id = $2",
         user_id,
         note_id
     )
@@ -322,6 +751,17 @@ pub async fn get(
             .created_at
             .format(&well_known::Iso8601::DEFAULT)
             .unwrap(),
+        DiaryMetadata::from_columns(
+            record.mood_score,
+            record.mood_label,
+            record.weather,
+            record.location_name,
+            record.location_lat,
+            record.location_lng,
+        ),
+        record.tags,
+        record.prompt_id,
+        record.unlock_at,
     )))
 }
 
@@ -346,7 +786,7 @@ pub async fn get_many(
     page_size: PageSize,
 ) -> Result<(Vec<Note>, bool), sqlx::Error> {
     let mut records = sqlx::query!(
-        "SELECT id, title, update_time, favourite, content, is_diary, created_at FROM notes WHERE user_id = $1 ORDER BY id LIMIT $2 OFFSET $3",
+        "SELECT id, title, update_time, favourite, content, is_diary, created_at, mood_score, mood_label, weather, location_name, location_lat, location_lng, tags, prompt_id, unlock_at FROM notes WHERE user_id = $1 ORDER BY id LIMIT $2 OFFSET $3",
         user_id,
         (page_size.0 + 1) as i64,
         (page as i64) * (page_size.0 as i64)
@@ -377,6 +817,17 @@ pub async fn get_many(
                     .created_at
                     .format(&well_known::Iso8601::DEFAULT)
                     .unwrap(),
+                DiaryMetadata::from_columns(
+                    record.mood_score,
+                    record.mood_label,
+                    record.weather,
+                    record.location_name,
+                    record.location_lat,
+                    record.location_lng,
+                ),
+                record.tags,
+                record.prompt_id,
+                record.unlock_at,
             )
         })
         .collect();
@@ -390,51 +841,103 @@ pub async fn get_many(
 /// # Arguments
 ///
 /// * `user_id` - The id of the user whos note we're updating
-/// * `conn` - The postgres/db connection
+/// * `conn` - The postgres/db connection, which can be a transaction
 /// * `updated_note` - The new content of the note. Fields that exist here will be updated on the note
 ///
 /// # Returns
-/// Error if we failed to contact the database, None if no note could be found to update.
-/// If a note was found, return the update time we've set on success, None on failure to update
+/// Error if we failed to contact the database, otherwise the outcome of the update.
+/// Notes that haven't reached their unlock time can't be updated
 pub async fn update(
-    mut conn: DbConn,
+    conn: &mut PgConnection,
     user_id: i32,
     note_id: i32,
     update: &UpdateNoteInfo,
-) -> Result<Option<Option<i64>>, sqlx::Error> {
+) -> Result<UpdateOutcome, sqlx::Error> {
     // Grab the current state
     let res = sqlx::query!(
         "SELECT * FROM notes WHERE user_id = $1 AND id = $2",
         user_id,
         note_id
     )
-    .fetch_one(&mut conn)
+    .fetch_one(&mut *conn)
     .await;
     if let Err(sqlx::Error::RowNotFound) = res {
         // No note could be found to update
-        return Ok(None);
+        return Ok(UpdateOutcome::NotFound);
     }
     let current = res?;
 
+    // Time capsules can't be touched until they've unlocked
+    if is_locked(current.unlock_at) {
+        return Ok(UpdateOutcome::Locked);
+    }
+    let unlock_at = match parse_timestamp(update.unlock_at.as_deref()) {
+        Ok(Some(unlock_at)) => Some(unlock_at),
+        _ => current.unlock_at,
+    };
+
+    // Diary entries can be backdated, but not moved into the future
+    let created_at = parse_timestamp(update.created_at.as_deref()).unwrap_or(None);
+    if !created_at_valid(created_at, current.is_diary) {
+        return Ok(UpdateOutcome::Invalid);
+    }
+    let created_at = created_at.unwrap_or(current.created_at);
+
+    // Only replace the metadata that's been provided, clearing anything set to null
+    let (mood_score, mood_label) = match &update.metadata.mood {
+        Some(Some(mood)) => (Some(mood.score), mood.label.clone()),
+        Some(None) => (None, None),
+        None => (current.mood_score, current.mood_label),
+    };
+    let weather = match &update.metadata.weather {
+        Some(weather) => weather.clone(),
+        None => current.weather,
+    };
+    let (location_name, location_lat, location_lng) = match &update.metadata.location {
+        Some(Some(location)) => (
+            Some(location.name.clone()),
+            Some(location.lat),
+            Some(location.lng),
+        ),
+        Some(None) => (None, None, None),
+        None => (
+            current.location_name,
+            current.location_lat,
+            current.location_lng,
+        ),
+    };
+
+    let tags = update.tags.clone().unwrap_or(current.tags);
+
     // Perform the update
     let update_time = now();
     let res = sqlx::query!(
-        "UPDATE notes SET content = $1, title = $2, update_time = $3, favourite = $4 WHERE id = $5 AND user_id = $6",
+        "UPDATE notes SET content = $1, title = $2, update_time = $3, favourite = $4, mood_score = $7, mood_label = $8, weather = $9, location_name = $10, location_lat = $11, location_lng = $12, unlock_at = $13, tags = $14, created_at = $15
+        WHERE id = $5 AND user_id = $6 AND (unlock_at IS NULL OR unlock_at <= CURRENT_TIMESTAMP)",
         update.content.as_ref().unwrap_or_else(|| &current.content),
         update.title.as_ref().unwrap_or_else(|| &current.title),
         update_time,
         update.favourite.unwrap_or_else(|| current.favourite),
         note_id,
         user_id,
+        mood_score,
+        mood_label,
+        weather,
+        location_name,
+        location_lat,
+        location_lng,
+        unlock_at,
+        &tags,
+        created_at,
     )
-    .execute(&mut conn)
+    .execute(conn)
     .await?;
 
     // Send back the update time (on success)
     if res.rows_affected() != 0 {
-        Ok(Some(Some(update_time)))
+        Ok(UpdateOutcome::Updated(update_time))
     } else {
-        Ok(Some(None))
+        Ok(UpdateOutcome::Failed)
     }
 }
 
@@ -473,21 +976,37 @@ pub async fn delete(note_id: i32, user_id: i32, mut conn: DbConn) -> Result<bool
 ///
 /// The id of the created note on succes, or an sqlx::Error on failure
 pub async fn create(
-    mut conn: DbConn,
+    conn: &mut PgConnection,
     user_id: i32,
     note: &CreateNoteInfo,
 ) -> Result<Note, sqlx::Error> {
+    let metadata = &note.metadata;
+
     // Insert a new note into the database
     let record = sqlx::query!(
-        "INSERT INTO notes (user_id, content, update_time, title, favourite, is_diary) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+        "INSERT INTO notes (user_id, content, update_time, title, favourite, is_diary, mood_score, mood_label, weather, location_name, location_lat, location_lng, unlock_at, tags, prompt_id, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14,
+            (SELECT id FROM prompts WHERE id = $15 AND (user_id IS NULL OR user_id = $1)),
+            COALESCE($16, CURRENT_TIMESTAMP))
+        RETURNING *",
         user_id,
         note.content,
         now(),
         note.title.as_deref().unwrap_or(""),
         note.favourite.unwrap_or(false),
-        note.is_diary.unwrap_or(false)
+        note.is_diary.unwrap_or(false),
+        metadata.mood.as_ref().map(|mood| mood.score),
+        metadata.mood.as_ref().and_then(|mood| mood.label.as_deref()),
+        metadata.weather.as_deref(),
+        metadata.location.as_ref().map(|location| location.name.as_str()),
+        metadata.location.as_ref().map(|location| location.lat),
+        metadata.location.as_ref().map(|location| location.lng),
+        parse_timestamp(note.unlock_at.as_deref()).unwrap_or(None),
+        note.tags.as_deref().unwrap_or_default(),
+        note.prompt_id,
+        parse_timestamp(note.created_at.as_deref()).unwrap_or(None)
     )
-    .fetch_one(&mut conn)
+    .fetch_one(conn)
     .await?; // if fetch_one fails, something went wrong internally and the note wasn't created
 
     Ok(Note::new(
@@ -501,5 +1020,338 @@ pub async fn create(
             .created_at
             .format(&well_known::Iso8601::DEFAULT)
             .unwrap(),
+        DiaryMetadata::from_columns(
+            record.mood_score,
+            record.mood_label,
+            record.weather,
+            record.location_name,
+            record.location_lat,
+            record.location_lng,
+        ),
+        record.tags,
+        record.prompt_id,
+        record.unlock_at,
+    ))
+}
+
+/// Averages the mood of the user's diary entries over each period between `from` and `to`
+///
+/// ### Arguments
+///
+/// * `conn` - The connection to the database in which the notes are stored
+/// * `user_id` - The user whose diary entries we're summarising
+/// * `bucket` - The length of each period we're averaging over
+/// * `from` - Only include entries created at or after this time
+/// * `to` - Only include entries created before this time
+/// * `tz` - The IANA time zone (e.g. Europe/London) the periods start at midnight in
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if the time zone doesn't exist,
+/// otherwise the average mood of each period that had at least one entry with a
+/// mood, oldest first
+pub async fn get_mood_summary(
+    mut conn: DbConn,
+    user_id: i32,
+    bucket: MoodBucket,
+    from: Option<OffsetDateTime>,
+    to: Option<OffsetDateTime>,
+    tz: &str,
+) -> Result<Option<Vec<MoodPoint>>, sqlx::Error> {
+    if !timezone_exists(&mut conn, tz).await? {
+        return Ok(None);
+    }
+
+    let records = sqlx::query!(
+        r#"SELECT date_trunc($2, created_at, $5) AS "period!", AVG(mood_score)::float8 AS "average!", COUNT(*) AS "entries!" FROM notes
+        WHERE user_id = $1 AND is_diary = true AND mood_score IS NOT NULL
+            AND (unlock_at IS NULL OR unlock_at <= CURRENT_TIMESTAMP)
+            AND ($3::timestamptz IS NULL OR created_at >= $3)
+            AND ($4::timestamptz IS NULL OR created_at < $4)
+        GROUP BY 1 ORDER BY 1"#,
+        user_id,
+        bucket.as_str(),
+        from,
+        to,
+        tz
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(Some(
+        records
+            .into_iter()
+            .map(|record| MoodPoint {
+                period: record.period.format(&well_known::Iso8601::DEFAULT).unwrap(),
+                average: record.average,
+                entries: record.entries,
+            })
+            .collect(),
     ))
 }
+
+/// A diary entry with just the fields needed to export it
+pub struct DiaryEntry {
+    pub id: i32,
+    pub title: String,
+    pub content: String,
+    pub created_at: OffsetDateTime,
+}
+
+/// Gets all of the user's readable diary entries created within the given range.
+/// Time capsules that haven't unlocked yet are left out
+///
+/// ### Arguments
+///
+/// * `conn` - The connection to the database in which the notes are stored
+/// * `user_id` - The user whose diary entries we're fetching
+/// * `from` - Only include entries created at or after this time
+/// * `to` - Only include entries created before this time
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise the entries, oldest first
+pub async fn get_diary_range(
+    mut conn: DbConn,
+    user_id: i32,
+    from: OffsetDateTime,
+    to: OffsetDateTime,
+) -> Result<Vec<DiaryEntry>, sqlx::Error> {
+    let records = sqlx::query!(
+        "SELECT id, title, content, created_at FROM notes
+        WHERE user_id = $1 AND is_diary = true AND created_at >= $2 AND created_at < $3
+            AND (unlock_at IS NULL OR unlock_at <= CURRENT_TIMESTAMP)
+        ORDER BY created_at",
+        user_id,
+        from,
+        to
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|record| DiaryEntry {
+            id: record.id,
+            title: record.title,
+            content: record.content,
+            created_at: record.created_at,
+        })
+        .collect())
+}
+
+/// The parts of a diary entry that appear on a calendar
+pub struct CalendarEntry {
+    pub id: i32,
+    /// The title of the entry, or None if it's a time capsule that hasn't unlocked yet
+    pub title: Option<String>,
+    pub update_time: i64,
+    pub created_at: OffsetDateTime,
+    /// The day the entry was written, in the user's time zone
+    pub date: Date,
+}
+
+/// Gets every one of the user's diary entries, without their content
+///
+/// ### Arguments
+///
+/// * `conn` - The connection to the database in which the notes are stored
+/// * `user_id` - The user whose diary entries we're fetching
+/// * `tz` - The IANA time zone (e.g. Europe/London) the entries' dates are given in
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if the time zone doesn't exist,
+/// otherwise the entries, oldest first
+pub async fn get_diary_calendar(
+    mut conn: DbConn,
+    user_id: i32,
+    tz: &str,
+) -> Result<Option<Vec<CalendarEntry>>, sqlx::Error> {
+    if !timezone_exists(&mut conn, tz).await? {
+        return Ok(None);
+    }
+
+    let records = sqlx::query!(
+        r#"SELECT id, title, update_time, created_at, (created_at AT TIME ZONE $2)::date AS "date!", unlock_at FROM notes
+        WHERE user_id = $1 AND is_diary = true ORDER BY created_at"#,
+        user_id,
+        tz
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(Some(
+        records
+            .into_iter()
+            .map(|record| CalendarEntry {
+                id: record.id,
+                title: match is_locked(record.unlock_at) {
+                    true => None,
+                    false => Some(record.title),
+                },
+                update_time: record.update_time,
+                created_at: record.created_at,
+                date: record.date,
+            })
+            .collect(),
+    ))
+}
+
+/// Which of the user's notes appear in a feed
+#[derive(FromForm)]
+pub struct FeedFilter {
+    /// Only include notes that are (or aren't) favourited
+    favourite: Option<bool>,
+    /// Only include notes that are (or aren't) diary entries
+    diary: Option<bool>,
+    /// Only include notes with this tag
+    tag: Option<String>,
+}
+
+/// The parts of a note that appear in a feed
+pub struct FeedEntry {
+    pub id: i32,
+    pub title: String,
+    pub content: String,
+    pub update_time: i64,
+}
+
+/// The number of notes a feed shows
+pub const FEED_SIZE: i64 = 50;
+
+/// Gets the user's most recently updated notes that match the filter. Time capsules
+/// that haven't unlocked yet are left out
+///
+/// ### Arguments
+///
+/// * `conn` - The connection to the database in which the notes are stored
+/// * `user_id` - The user whose notes we're fetching
+/// * `filter` - Only notes matching the filter will be returned
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise up to FEED_SIZE notes, most
+/// recently updated first
+pub async fn get_feed(
+    mut conn: DbConn,
+    user_id: i32,
+    filter: &FeedFilter,
+) -> Result<Vec<FeedEntry>, sqlx::Error> {
+    let records = sqlx::query!(
+        "SELECT id, title, content, update_time FROM notes
+        WHERE user_id = $1
+            AND ($2::bool IS NULL OR favourite = $2)
+            AND ($3::bool IS NULL OR is_diary = $3)
+            AND ($4::text IS NULL OR $4 = ANY(tags))
+            AND (unlock_at IS NULL OR unlock_at <= CURRENT_TIMESTAMP)
+        ORDER BY update_time DESC LIMIT $5",
+        user_id,
+        filter.favourite,
+        filter.diary,
+        filter.tag,
+        FEED_SIZE
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|record| FeedEntry {
+            id: record.id,
+            title: record.title,
+            content: record.content,
+            update_time: record.update_time,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes a note with a title, content, metadata, tags and a prompt that unlocks at
+    /// the given time
+    fn note(unlock_at: Option<OffsetDateTime>) -> serde_json::Value {
+        let note = Note::new(
+            1,
+            String::from("To future me"),
+            0,
+            true,
+            String::from("The secret"),
+            true,
+            String::new(),
+            DiaryMetadata::from_columns(
+                Some(7),
+                None,
+                Some(String::from("Rain")),
+                None,
+                None,
+                None,
+            ),
+            vec![String::from("the-secret")],
+            Some(3),
+            unlock_at,
+        );
+        serde_json::to_value(note).unwrap()
+    }
+
+    #[test]
+    fn locked_notes_are_reduced_to_a_stub() {
+        let locked = note(Some(OffsetDateTime::now_utc() + Duration::days(1)));
+        assert_eq!(locked["locked"], true);
+        assert_eq!(locked["title"], "");
+        assert_eq!(locked["content"], "");
+        assert!(locked["mood"].is_null());
+        assert!(locked["weather"].is_null());
+        assert_eq!(locked["tags"], serde_json::json!([]));
+        assert!(locked["prompt_id"].is_null());
+        // Favouriting a note doesn't say anything about what's in it
+        assert_eq!(locked["favourite"], true);
+
+        let unlocked = note(Some(OffsetDateTime::now_utc() - Duration::days(1)));
+        assert_eq!(unlocked["locked"], false);
+        assert_eq!(unlocked["content"], "The secret");
+        assert_eq!(unlocked["weather"], "Rain");
+        assert_eq!(unlocked["tags"], serde_json::json!(["the-secret"]));
+        assert_eq!(unlocked["prompt_id"], 3);
+    }
+
+    #[test]
+    fn escape_like_escapes_wildcards() {
+        assert_eq!(escape_like("sunny"), "sunny");
+        assert_eq!(escape_like("100%"), "100\\%");
+        assert_eq!(escape_like("a_b"), "a\\_b");
+        assert_eq!(escape_like("back\\slash"), "back\\\\slash");
+    }
+
+    #[test]
+    fn location_pattern_matches_anywhere() {
+        let filter = DiaryFilter {
+            location: Some(String::from("50%_off")),
+            ..Default::default()
+        };
+        assert_eq!(filter.location_pattern().unwrap(), "%50\\%\\_off%");
+    }
+
+    #[test]
+    fn metadata_update_tells_null_from_missing() {
+        let update: UpdateNoteInfo =
+            serde_json::from_str(r#"{"weather": null, "mood": {"score": 3}}"#).unwrap();
+        assert!(matches!(update.metadata.weather, Some(None)));
+        assert!(matches!(
+            update.metadata.mood,
+            Some(Some(Mood { score: 3, .. }))
+        ));
+        assert!(update.metadata.location.is_none());
+    }
+
+    #[test]
+    fn metadata_update_is_validated() {
+        let update: UpdateNoteInfo = serde_json::from_str(r#"{"mood": {"score": 11}}"#).unwrap();
+        assert!(!update.is_valid());
+        let update: UpdateNoteInfo =
+            serde_json::from_str(r#"{"mood": null, "location": null}"#).unwrap();
+        assert!(update.is_valid());
+    }
+}
@@ -0,0 +1,57 @@
+use sqlx::PgConnection;
+
+/// Gets the user an account with an OAuth provider is linked to
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the linked accounts
+/// * `provider` - The name of the provider, as configured
+/// * `subject` - The provider's id for the account
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if the account isn't linked to
+/// anyone, otherwise the id of the user it's linked to
+pub async fn get_user_id(
+    conn: &mut PgConnection,
+    provider: &str,
+    subject: &str,
+) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT user_id FROM oauth_accounts WHERE provider = $1 AND subject = $2",
+        provider,
+        subject
+    )
+    .fetch_optional(conn)
+    .await
+}
+
+/// Links an account with an OAuth provider to a user, so they can log in with it
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the linked accounts
+/// * `user_id` - The id of the user the account belongs to
+/// * `provider` - The name of the provider, as configured
+/// * `subject` - The provider's id for the account
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database
+pub async fn link(
+    conn: &mut PgConnection,
+    user_id: i32,
+    provider: &str,
+    subject: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO oauth_accounts (provider, subject, user_id) VALUES ($1, $2, $3)",
+        provider,
+        subject,
+        user_id
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
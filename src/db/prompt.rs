@@ -0,0 +1,250 @@
+use crate::db::DbConn;
+use rocket::time::Date;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+/// The journaling prompts every user gets. These are added to the prompts table
+/// (without an owner) when we launch, so adding to the list is all it takes to add a prompt
+pub const BUILTIN_PROMPTS: &[&str] = &[
+    "What made you smile today?",
+    "What's something you're looking forward to?",
+    "Describe a small win from today.",
+    "What's been on your mind lately?",
+    "Who did you talk to today, and what about?",
+    "What's something you learnt recently?",
+    "What would make tomorrow a good day?",
+    "What are three things you're grateful for right now?",
+    "Describe where you are right now in as much detail as you can.",
+    "What's something you've been putting off, and why?",
+    "What did you do today that your past self would be proud of?",
+    "What's a conversation you keep replaying in your head?",
+    "How are you feeling, really?",
+    "What's the best thing you ate this week?",
+    "If today had a title, what would it be?",
+    "What's something you'd like to stop doing?",
+    "Write a letter to yourself one year from now.",
+    "What's a memory that came back to you recently?",
+    "What drained your energy today, and what gave you energy?",
+    "What's one thing you'd change about today if you could?",
+];
+
+/// The longest a user-defined prompt can be, in characters
+pub const MAX_PROMPT_LEN: usize = 500;
+
+/// A single journaling prompt
+#[derive(Serialize)]
+pub struct Prompt {
+    id: i32,
+    text: String,
+    /// true if the prompt is one of our built-in ones, false if the user wrote it
+    builtin: bool,
+}
+
+/// Fields required for creating a new prompt
+#[derive(Deserialize)]
+pub struct CreatePromptInfo {
+    text: String,
+}
+impl CreatePromptInfo {
+    /// Checks the prompt isn't empty or too long
+    pub fn is_valid(&self) -> bool {
+        !self.text.trim().is_empty() && self.text.chars().count() <= MAX_PROMPT_LEN
+    }
+}
+
+/// Adds any built-in prompts that aren't already in the database
+///
+/// ### Arguments
+///
+/// * `pool` - A pool of connections to the database storing the prompts
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database
+pub async fn sync_builtin(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let prompts: Vec<String> = BUILTIN_PROMPTS.iter().map(|p| p.to_string()).collect();
+    sqlx::query!(
+        "INSERT INTO prompts (text)
+        SELECT text FROM UNNEST($1::text[]) AS builtin(text)
+        WHERE NOT EXISTS (SELECT 1 FROM prompts WHERE user_id IS NULL AND prompts.text = builtin.text)",
+        &prompts
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Gets every prompt available to the user - the built-in ones and their own
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the prompts
+/// * `user_id` - The id of the user whose prompts we're fetching
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise the prompts ordered by id
+pub async fn get_all(mut conn: DbConn, user_id: i32) -> Result<Vec<Prompt>, sqlx::Error> {
+    let records = sqlx::query!(
+        "SELECT id, text, user_id FROM prompts WHERE user_id IS NULL OR user_id = $1 ORDER BY id",
+        user_id
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|record| Prompt {
+            id: record.id,
+            text: record.text,
+            builtin: record.user_id.is_none(),
+        })
+        .collect())
+}
+
+/// Picks the user's prompt for the given day. The same user gets the same prompt all
+/// day - the pick is stored, so adding or deleting prompts doesn't change it (unless
+/// they delete the day's prompt itself). Prompts they add only become candidates from
+/// the following day
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the prompts
+/// * `user_id` - The id of the user we're picking a prompt for
+/// * `date` - The (UTC) day we're picking a prompt for
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if there are no prompts to pick from,
+/// otherwise the day's prompt
+pub async fn get_for_day(
+    mut conn: DbConn,
+    user_id: i32,
+    date: Date,
+) -> Result<Option<Prompt>, sqlx::Error> {
+    if let Some(prompt) = get_picked(&mut conn, user_id, date).await? {
+        return Ok(Some(prompt));
+    }
+
+    let start_of_day = date.midnight().assume_utc();
+    let records = sqlx::query!(
+        "SELECT id FROM prompts WHERE user_id IS NULL OR (user_id = $1 AND created_at < $2) ORDER BY id",
+        user_id,
+        start_of_day
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    if records.is_empty() {
+        return Ok(None);
+    }
+
+    // Hash the user and day into an index, so it's stable across requests and restarts
+    let seed = openssl::sha::sha256(format!("{user_id}:{date}").as_bytes());
+    let seed = u64::from_be_bytes(seed[..8].try_into().unwrap());
+    let prompt_id = records[(seed % records.len() as u64) as usize].id;
+
+    // Only the latest day's pick is kept. If another request picked first, theirs stands
+    sqlx::query!(
+        "INSERT INTO daily_prompts (user_id, day, prompt_id) VALUES ($1, $2, $3)
+        ON CONFLICT (user_id) DO UPDATE SET day = EXCLUDED.day, prompt_id = EXCLUDED.prompt_id
+        WHERE daily_prompts.day < EXCLUDED.day",
+        user_id,
+        date,
+        prompt_id
+    )
+    .execute(&mut conn)
+    .await?;
+
+    get_picked(&mut conn, user_id, date).await
+}
+
+/// Gets the prompt that's already been picked for the user for the given day
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the prompts
+/// * `user_id` - The id of the user the prompt was picked for
+/// * `date` - The (UTC) day the prompt was picked for
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if no prompt's been picked yet,
+/// otherwise the day's prompt
+async fn get_picked(
+    conn: &mut DbConn,
+    user_id: i32,
+    date: Date,
+) -> Result<Option<Prompt>, sqlx::Error> {
+    let record = sqlx::query!(
+        "SELECT prompts.id, prompts.text, prompts.user_id FROM daily_prompts
+        JOIN prompts ON prompts.id = daily_prompts.prompt_id
+        WHERE daily_prompts.user_id = $1 AND daily_prompts.day = $2",
+        user_id,
+        date
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(record.map(|record| Prompt {
+        id: record.id,
+        text: record.text,
+        builtin: record.user_id.is_none(),
+    }))
+}
+
+/// Creates a new prompt owned by the user
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the prompts
+/// * `user_id` - The id of the user creating the prompt
+/// * `prompt` - The prompt being created
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise the created prompt
+pub async fn create(
+    mut conn: DbConn,
+    user_id: i32,
+    prompt: &CreatePromptInfo,
+) -> Result<Prompt, sqlx::Error> {
+    let record = sqlx::query!(
+        "INSERT INTO prompts (user_id, text) VALUES ($1, $2) RETURNING id, text",
+        user_id,
+        prompt.text.trim()
+    )
+    .fetch_one(&mut conn)
+    .await?;
+
+    Ok(Prompt {
+        id: record.id,
+        text: record.text,
+        builtin: false,
+    })
+}
+
+/// Deletes one of the user's own prompts. Built-in prompts can't be deleted
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the prompts
+/// * `user_id` - The id of the user who owns the prompt
+/// * `prompt_id` - The id of the prompt we're deleting
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, true if the prompt was deleted, false
+/// if we couldn't find a prompt to delete
+pub async fn delete(mut conn: DbConn, user_id: i32, prompt_id: i32) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        "DELETE FROM prompts WHERE id = $1 AND user_id = $2",
+        prompt_id,
+        user_id
+    )
+    .execute(&mut conn)
+    .await?;
+
+    Ok(res.rows_affected() != 0)
+}
@@ -0,0 +1,78 @@
+use serde::Serialize;
+use sqlx::PgConnection;
+
+/// How many bytes a user is storing, in each place they can store things
+#[derive(Serialize)]
+pub struct StorageUsage {
+    /// The titles and content of their notes
+    pub notes: i64,
+    /// Their images. The resized and converted versions we cache aren't counted, as
+    /// they can always be made again
+    pub images: i64,
+    pub files: i64,
+}
+impl StorageUsage {
+    /// The bytes used across everything
+    pub fn total(&self) -> i64 {
+        self.notes + self.images + self.files
+    }
+}
+
+/// Works out how many bytes the user is storing
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the user's things, which can be a
+///   transaction
+/// * `user_id` - The id of the user whose usage we're working out
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, otherwise the user's usage
+pub async fn get(conn: &mut PgConnection, user_id: i32) -> Result<StorageUsage, sqlx::Error> {
+    // Images uploaded before we recorded their size only know it if they're stored here
+    let record = sqlx::query!(
+        r#"SELECT
+            (SELECT COALESCE(SUM(octet_length(title) + octet_length(content)), 0)::bigint FROM notes WHERE user_id = $1) AS "notes!",
+            (SELECT COALESCE(SUM(COALESCE(size_bytes, octet_length(image), 0)), 0)::bigint FROM images WHERE user_id = $1) AS "images!",
+            (SELECT COALESCE(SUM(size_bytes), 0)::bigint FROM files WHERE user_id = $1) AS "files!""#,
+        user_id
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(StorageUsage {
+        notes: record.notes,
+        images: record.images,
+        files: record.files,
+    })
+}
+
+/// Gets how many bytes one of the user's notes is using
+///
+/// ### Arguments
+///
+/// * `conn` - A connection to the database storing the note
+/// * `user_id` - The id of the user who owns the note
+/// * `note_id` - The id of the note
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if the user has no such note,
+/// otherwise the bytes used by its title and its content
+pub async fn get_note_size(
+    conn: &mut PgConnection,
+    user_id: i32,
+    note_id: i32,
+) -> Result<Option<(i64, i64)>, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"SELECT octet_length(title)::bigint AS "title!", octet_length(content)::bigint AS "content!"
+        FROM notes WHERE id = $1 AND user_id = $2"#,
+        note_id,
+        user_id
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(record.map(|record| (record.title, record.content)))
+}
@@ -188,6 +188,61 @@ impl User {
         return Ok(res.rows_affected() != 0);
     }
 
+    /// Creates a new user without a password, for users who sign up by logging in with
+    /// an OAuth provider. They can't log in with a password, as no password matches
+    ///
+    /// ### Arguments
+    ///
+    /// * `conn` - a connection to the database where we want to store the new user
+    /// * `email` - the email of the new user
+    ///
+    /// ### Returns
+    ///
+    /// Error if we failed to access the database, otherwise the id of the new user
+    pub async fn create_without_password(
+        conn: &mut sqlx::PgConnection,
+        email: &str,
+    ) -> Result<i32, sqlx::Error> {
+        sqlx::query_scalar!(
+            "INSERT INTO users (email, password) VALUES ($1, '') RETURNING id",
+            email
+        )
+        .fetch_one(conn)
+        .await
+    }
+
+    /// Replaces the user's password, e.g. once they've reset it
+    ///
+    /// ### Arguments
+    ///
+    /// * `conn` - a connection to the database storing the user
+    /// * `user_id` - the id of the user whose password we're changing
+    /// * `password` - the new plaintext password (we'll hash it here)
+    ///
+    /// ### Returns
+    ///
+    /// Error if we failed to access the database or Ok(true if the password was changed, false if we failed to hash it or the user doesn't exist)
+    pub async fn set_password(
+        conn: &mut sqlx::PgConnection,
+        user_id: i32,
+        password: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let password = match Self::hash_password(password).await {
+            Ok(password) => password,
+            Err(_) => return Ok(false),
+        };
+
+        let res = sqlx::query!(
+            "UPDATE users SET password = $1 WHERE id = $2",
+            password.0,
+            user_id
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(res.rows_affected() != 0)
+    }
+
     /// Hashes the password into a hashed password string
     ///
     /// ### Arguments
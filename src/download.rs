@@ -0,0 +1,360 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use reqwest::{header, redirect, Url};
+use rocket::tokio::{fs::File, io::AsyncWriteExt, net, time};
+use tempfile::NamedTempFile;
+
+/// The most redirects we'll follow before giving up
+const MAX_REDIRECTS: usize = 3;
+
+/// Stuff that can go wrong while downloading a file on a user's behalf
+#[derive(Debug)]
+pub enum DownloadError {
+    /// The url isn't a valid http(s) url
+    InvalidUrl,
+    /// The url points at something private, like our own network
+    Forbidden,
+    /// The file is larger than we're willing to download
+    TooLarge,
+    /// We couldn't reach the server, it sent an error, or took too long
+    Failed,
+}
+
+/// A file downloaded from a url
+pub struct Download {
+    /// The downloaded file, which is removed when this is dropped
+    pub file: NamedTempFile,
+    /// The mime type the server said the file was, if it said
+    pub mime_type: Option<String>,
+}
+
+/// Downloads a file from a url a user gave us. As we're making the request from inside
+/// our network, anything that resolves to a private, loopback or otherwise internal
+/// address is refused, including when redirected there. The file is written to a
+/// temporary file as it arrives, rather than held in memory
+///
+/// ### Arguments
+///
+/// * `url` - The url of the file
+/// * `max_bytes` - The largest file we'll download
+/// * `timeout` - How long the whole download is allowed to take, redirects included
+///
+/// ### Returns
+///
+/// The file, or why we couldn't download it
+pub async fn download(
+    url: &str,
+    max_bytes: u64,
+    timeout: Duration,
+) -> Result<Download, DownloadError> {
+    download_from(url, max_bytes, timeout, is_public).await
+}
+
+/// Downloads a file from a url, as long as every address it leads to is allowed
+///
+/// ### Arguments
+///
+/// * `url` - The url of the file
+/// * `max_bytes` - The largest file we'll download
+/// * `timeout` - How long the whole download is allowed to take, redirects included
+/// * `allowed` - Whether we're allowed to connect to an address. Only tests allow
+///   anything but public addresses
+///
+/// ### Returns
+///
+/// The file, or why we couldn't download it
+async fn download_from(
+    url: &str,
+    max_bytes: u64,
+    timeout: Duration,
+    allowed: fn(IpAddr) -> bool,
+) -> Result<Download, DownloadError> {
+    let mut url = Url::parse(url).map_err(|_| DownloadError::InvalidUrl)?;
+
+    // The timeout covers every hop, so a chain of slow redirects can't hold us up
+    let download = async {
+        for _ in 0..=MAX_REDIRECTS {
+            // Resolve the host ourselves and pin the request to the address we checked, so
+            // the name can't resolve somewhere else by the time we connect
+            let addr = resolve_allowed(&url, allowed).await?;
+            let host = url.host_str().ok_or(DownloadError::InvalidUrl)?.to_string();
+            // A proxy would make the connection for us, to wherever it resolves the host to
+            let client = reqwest::Client::builder()
+                .redirect(redirect::Policy::none())
+                .no_proxy()
+                .resolve(&host, addr)
+                .build()
+                .map_err(|_| DownloadError::Failed)?;
+            let mut response = client
+                .get(url.clone())
+                .send()
+                .await
+                .map_err(|_| DownloadError::Failed)?;
+
+            // Follow redirects by hand, so each hop is checked
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(DownloadError::Failed)?;
+                url = url.join(location).map_err(|_| DownloadError::InvalidUrl)?;
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(DownloadError::Failed);
+            }
+
+            if response.content_length().is_some_and(|len| len > max_bytes) {
+                return Err(DownloadError::TooLarge);
+            }
+            let mime_type = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|mime_type| mime_type.to_str().ok())
+                .map(|mime_type| mime_type.split(';').next().unwrap_or("").trim().to_string());
+
+            // The length can lie (or be missing), so keep count as we read
+            let file = NamedTempFile::new().map_err(|_| DownloadError::Failed)?;
+            let mut writer = File::from_std(file.reopen().map_err(|_| DownloadError::Failed)?);
+            let mut len = 0;
+            while let Some(chunk) = response.chunk().await.map_err(|_| DownloadError::Failed)? {
+                len += chunk.len() as u64;
+                if len > max_bytes {
+                    return Err(DownloadError::TooLarge);
+                }
+                writer
+                    .write_all(&chunk)
+                    .await
+                    .map_err(|_| DownloadError::Failed)?;
+            }
+            writer.flush().await.map_err(|_| DownloadError::Failed)?;
+
+            return Ok(Download { file, mime_type });
+        }
+
+        Err(DownloadError::Failed)
+    };
+
+    time::timeout(timeout, download)
+        .await
+        .map_err(|_| DownloadError::Failed)?
+}
+
+/// Resolves the host of a url, making sure every address it resolves to is allowed
+///
+/// ### Arguments
+///
+/// * `url` - The url whose host we're resolving
+/// * `allowed` - Whether we're allowed to connect to an address
+///
+/// ### Returns
+///
+/// An address to connect to, or an error if the url is invalid or any of its
+/// addresses aren't allowed
+async fn resolve_allowed(
+    url: &Url,
+    allowed: fn(IpAddr) -> bool,
+) -> Result<SocketAddr, DownloadError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(DownloadError::InvalidUrl);
+    }
+    let host = url.host_str().ok_or(DownloadError::InvalidUrl)?;
+    let port = url
+        .port_or_known_default()
+        .ok_or(DownloadError::InvalidUrl)?;
+
+    // IPv6 hosts are wrapped in brackets in urls
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = net::lookup_host((host, port))
+        .await
+        .map_err(|_| DownloadError::Failed)?
+        .collect();
+    if addrs.is_empty() {
+        return Err(DownloadError::Failed);
+    }
+    if !addrs.iter().all(|addr| allowed(addr.ip())) {
+        return Err(DownloadError::Forbidden);
+    }
+
+    Ok(addrs[0])
+}
+
+/// Checks whether an address is on the public internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        // IPv4-mapped and IPv4-compatible addresses reach the IPv4 address they embed
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+/// Checks whether an IPv4 address is on the public internet
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // "This network", carrier-grade NAT, IETF protocol assignments, benchmarking
+        // and reserved ranges
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+/// Checks whether an IPv6 address is on the public internet
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local, link local and site local
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+        // Documentation
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        // IPv4 translation (NAT64) and Teredo tunnelling, which could reach private
+        // IPv4 addresses
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b)
+        || (segments[0] == 0x2001 && segments[1] == 0x0000)
+        // 6to4, which embeds an IPv4 address
+        || (segments[0] == 0x2002
+            && !is_public_v4(Ipv4Addr::new(
+                (segments[1] >> 8) as u8,
+                segments[1] as u8,
+                (segments[2] >> 8) as u8,
+                segments[2] as u8,
+            ))))
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// How long downloads from the pretend server are allowed to take
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Runs a pretend server on a local port, which sends each response in turn to the
+    /// next connection, then closes it
+    ///
+    /// ### Returns
+    ///
+    /// The server's base url
+    async fn mock_server(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = socket.read(&mut request).await.unwrap();
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        base
+    }
+
+    /// A response carrying the body
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: image/png; charset=binary\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// A response redirecting to the location
+    fn redirect(location: &str) -> String {
+        format!("HTTP/1.1 302 Found\r\nLocation: {location}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    }
+
+    #[rocket::async_test]
+    async fn files_are_downloaded_through_redirects() {
+        let base = mock_server(vec![redirect("/image.png"), ok("not really a png")]).await;
+        let download = download_from(&base, 1024, TIMEOUT, |_| true).await.unwrap();
+
+        assert_eq!(download.mime_type.as_deref(), Some("image/png"));
+        let contents = std::fs::read(download.file.path()).unwrap();
+        assert_eq!(contents, b"not really a png");
+    }
+
+    #[rocket::async_test]
+    async fn too_many_redirects_fail() {
+        let base = mock_server(vec![redirect("/"); MAX_REDIRECTS + 1]).await;
+        let result = download_from(&base, 1024, TIMEOUT, |_| true).await;
+        assert!(matches!(result, Err(DownloadError::Failed)));
+    }
+
+    #[rocket::async_test]
+    async fn large_files_are_refused() {
+        let base = mock_server(vec![ok(&"x".repeat(2048))]).await;
+        let result = download_from(&base, 1024, TIMEOUT, |_| true).await;
+        assert!(matches!(result, Err(DownloadError::TooLarge)));
+    }
+
+    #[rocket::async_test]
+    async fn slow_servers_time_out() {
+        // Accepts the connection, but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let result = download_from(&url, 1024, Duration::from_millis(100), |_| true).await;
+        assert!(matches!(result, Err(DownloadError::Failed)));
+        drop(listener);
+    }
+
+    #[rocket::async_test]
+    async fn local_servers_are_forbidden() {
+        let base = mock_server(vec![ok("secret")]).await;
+        let result = download(&base, 1024, TIMEOUT).await;
+        assert!(matches!(result, Err(DownloadError::Forbidden)));
+    }
+
+    #[test]
+    fn embedded_ipv4_addresses_are_checked() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+
+        assert!(public("8.8.8.8"));
+        assert!(public("2606:4700::1111"));
+        // IPv4-mapped and IPv4-compatible
+        assert!(!public("::ffff:127.0.0.1"));
+        assert!(!public("::ffff:10.0.0.1"));
+        assert!(public("::ffff:8.8.8.8"));
+        assert!(!public("::192.168.0.1"));
+        assert!(!public("::1"));
+        // 6to4
+        assert!(!public("2002:c0a8:0001::1"));
+        assert!(public("2002:0808:0808::1"));
+        // Teredo and NAT64
+        assert!(!public("2001:0:4136:e378:8000:63bf:3fff:fdd2"));
+        assert!(!public("64:ff9b::a00:1"));
+    }
+
+    #[test]
+    fn carrier_grade_nat_is_not_public() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+
+        assert!(!public("100.64.0.1"));
+        assert!(!public("100.127.255.254"));
+        assert!(public("100.63.255.255"));
+        assert!(public("100.128.0.1"));
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use rocket::time::{Month, OffsetDateTime};
+
+use crate::db::{image::StoredImage, note::DiaryEntry};
+
+pub use pdf::PdfFonts;
+
+pub mod epub;
+pub mod pdf;
+
+/// Everything that goes into a printable diary book
+pub struct Book {
+    pub title: String,
+    pub author: String,
+    pub from: OffsetDateTime,
+    pub to: OffsetDateTime,
+    /// The diary entries in the book, oldest first
+    pub entries: Vec<DiaryEntry>,
+    /// The images embedded in the entries, by id
+    pub images: HashMap<i32, StoredImage>,
+}
+
+/// A month of diary entries, each month gets its own chapter
+pub struct Chapter<'a> {
+    pub title: String,
+    pub entries: Vec<&'a DiaryEntry>,
+}
+
+impl Book {
+    /// Splits the book's entries into one chapter per month
+    pub fn chapters(&self) -> Vec<Chapter<'_>> {
+        let mut chapters: Vec<Chapter> = vec![];
+        let mut current: Option<(i32, Month)> = None;
+        for entry in &self.entries {
+            let month = (entry.created_at.year(), entry.created_at.month());
+            if current != Some(month) {
+                current = Some(month);
+                chapters.push(Chapter {
+                    title: format!("{} {}", month.1, month.0),
+                    entries: vec![],
+                });
+            }
+
+            // We've always pushed a chapter by this point
+            if let Some(chapter) = chapters.last_mut() {
+                chapter.entries.push(entry);
+            }
+        }
+
+        chapters
+    }
+
+    /// The range of dates the book covers, e.g. "1 January 2024 - 31 December 2024"
+    pub fn subtitle(&self) -> String {
+        format!("{} - {}", format_date(self.from), format_date(self.to))
+    }
+}
+
+/// The file formats a book can be exported as
+#[derive(FromFormField, Clone, Copy)]
+pub enum BookFormat {
+    #[field(value = "pdf")]
+    Pdf,
+    #[field(value = "epub")]
+    Epub,
+}
+impl BookFormat {
+    /// The mime type of the exported file
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            BookFormat::Pdf => "application/pdf",
+            BookFormat::Epub => "application/epub+zip",
+        }
+    }
+
+    /// The file extension of the exported file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            BookFormat::Pdf => "pdf",
+            BookFormat::Epub => "epub",
+        }
+    }
+}
+
+/// Stuff that can go wrong while rendering a book
+#[derive(Debug)]
+pub enum ExportError {
+    Pdf,
+    Epub,
+}
+
+/// Renders the book in the given format
+///
+/// ### Arguments
+///
+/// * `book` - The book to render
+/// * `format` - The format to render it in
+/// * `fonts` - The fonts to write pdfs in, or None to use the builtin fonts
+///
+/// ### Returns
+///
+/// The bytes of the rendered file, or an error if we failed to render it
+pub fn render(
+    book: &Book,
+    format: BookFormat,
+    fonts: Option<&PdfFonts>,
+) -> Result<Vec<u8>, ExportError> {
+    match format {
+        BookFormat::Pdf => pdf::render(book, fonts),
+        BookFormat::Epub => epub::render(book),
+    }
+}
+
+/// Formats a date as the heading of a diary entry, e.g. "Monday 4 March 2024"
+pub fn date_heading(date: OffsetDateTime) -> String {
+    format!("{} {}", date.weekday(), format_date(date))
+}
+
+/// Formats a date as e.g. "4 March 2024"
+fn format_date(date: OffsetDateTime) -> String {
+    format!("{} {} {}", date.day(), date.month(), date.year())
+}
@@ -0,0 +1,110 @@
+use std::io::Cursor;
+
+use epub_builder::{EpubBuilder, EpubContent, EpubVersion, ReferenceType, ZipLibrary};
+
+use crate::{
+    db::image::StoredImage,
+    export::{date_heading, Book, ExportError},
+    render::{self, escape},
+};
+
+/// Wraps the body of a chapter in the boilerplate every xhtml file in an epub needs
+fn xhtml(title: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n\
+        <head><title>{}</title></head>\n\
+        <body>\n{body}</body>\n\
+        </html>\n",
+        escape(title)
+    )
+}
+
+/// The path an image is stored at within the epub
+fn image_path(image: &StoredImage) -> String {
+    let extension = image.mime_type.split('/').next_back().unwrap_or("img");
+    format!("images/{}.{extension}", image.id)
+}
+
+/// Renders the book as an epub, with a title page and one chapter per month
+///
+/// ### Arguments
+///
+/// * `book` - The book we're rendering
+///
+/// ### Returns
+///
+/// The bytes of the epub file, or ExportError::Epub if we failed to generate it
+pub fn render(book: &Book) -> Result<Vec<u8>, ExportError> {
+    let mut builder = ZipLibrary::new()
+        .and_then(EpubBuilder::new)
+        .map_err(|_| ExportError::Epub)?;
+    builder
+        .epub_version(EpubVersion::V30)
+        .metadata("title", &book.title)
+        .and_then(|builder| builder.metadata("author", &book.author))
+        .map_err(|_| ExportError::Epub)?;
+
+    // Embed every image the entries use
+    for image in book.images.values() {
+        builder
+            .add_resource(
+                image_path(image),
+                Cursor::new(image.bytes.clone()),
+                &image.mime_type,
+            )
+            .map_err(|_| ExportError::Epub)?;
+    }
+
+    // Title page
+    let title_page = xhtml(
+        &book.title,
+        &format!(
+            "<h1>{}</h1>\n<p>{}</p>\n<p>{}</p>\n",
+            escape(&book.title),
+            escape(&book.subtitle()),
+            escape(&book.author)
+        ),
+    );
+    builder
+        .add_content(
+            EpubContent::new("title.xhtml", title_page.as_bytes())
+                .title(&book.title)
+                .reftype(ReferenceType::TitlePage),
+        )
+        .map_err(|_| ExportError::Epub)?;
+
+    // Images are referenced by their path within the epub
+    let image_src = |url: &str| {
+        render::image_id(url)
+            .and_then(|id| book.images.get(&id))
+            .map(image_path)
+    };
+
+    for (i, chapter) in book.chapters().iter().enumerate() {
+        let mut body = format!("<h1>{}</h1>\n", escape(&chapter.title));
+        for entry in &chapter.entries {
+            body += &format!("<h2>{}</h2>\n", escape(&date_heading(entry.created_at)));
+            if !entry.title.is_empty() {
+                body += &format!("<h3>{}</h3>\n", escape(&entry.title));
+            }
+            body += &render::to_html(&render::parse(&entry.content), image_src);
+        }
+
+        let chapter_page = xhtml(&chapter.title, &body);
+        builder
+            .add_content(
+                EpubContent::new(format!("chapter_{i}.xhtml"), chapter_page.as_bytes())
+                    .title(&chapter.title)
+                    .reftype(ReferenceType::Text),
+            )
+            .map_err(|_| ExportError::Epub)?;
+    }
+
+    let mut bytes = vec![];
+    builder
+        .generate(&mut bytes)
+        .map_err(|_| ExportError::Epub)?;
+
+    Ok(bytes)
+}
@@ -0,0 +1,394 @@
+use std::{io, path::Path, sync::Arc};
+
+use printpdf::{
+    BuiltinFont, ColorBits, ColorSpace, Image, ImageTransform, ImageXObject, IndirectFontRef, Mm,
+    PdfDocument, PdfDocumentReference, PdfLayerReference, Px,
+};
+use ttf_parser::Face;
+
+use crate::{
+    export::{date_heading, Book, ExportError},
+    render::{self, Block},
+};
+
+/// A4, in millimetres
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 20.0;
+const CONTENT_WIDTH: f32 = PAGE_WIDTH - 2.0 * MARGIN;
+
+/// Font sizes, in points
+const TITLE_SIZE: f32 = 32.0;
+const CHAPTER_SIZE: f32 = 24.0;
+const HEADING_SIZE: f32 = 16.0;
+const BODY_SIZE: f32 = 11.0;
+
+/// How many millimetres are in a point
+const MM_PER_PT: f32 = 0.3528;
+/// The builtin fonts don't tell us how wide their characters are, so we wrap
+/// lines assuming every character is roughly this fraction of the font size.
+/// It's also used for characters an embedded font doesn't have
+const AVERAGE_CHAR_WIDTH: f32 = 0.5;
+/// The spacing between lines, as a multiple of the font size
+const LINE_HEIGHT: f32 = 1.4;
+
+/// The TrueType fonts books are written in, which are embedded in each pdf. Unlike
+/// the builtin fonts, they can show any script they have glyphs for
+#[derive(Clone)]
+pub struct PdfFonts {
+    regular: Arc<Vec<u8>>,
+    bold: Arc<Vec<u8>>,
+}
+
+impl PdfFonts {
+    /// Reads the fonts from disk
+    ///
+    /// ### Arguments
+    ///
+    /// * `regular` - The path of the font for body text
+    /// * `bold` - The path of the font for headings
+    ///
+    /// ### Returns
+    ///
+    /// The fonts, or an error if either can't be read or isn't a TrueType font
+    pub fn load(regular: &Path, bold: &Path) -> io::Result<PdfFonts> {
+        let read = |path: &Path| -> io::Result<Arc<Vec<u8>>> {
+            let bytes = std::fs::read(path)?;
+            Face::from_slice(&bytes, 0).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} isn't a TrueType font: {err}", path.display()),
+                )
+            })?;
+            Ok(Arc::new(bytes))
+        };
+
+        Ok(PdfFonts {
+            regular: read(regular)?,
+            bold: read(bold)?,
+        })
+    }
+}
+
+/// A font the document is written in
+struct Font<'a> {
+    reference: IndirectFontRef,
+    /// The font's glyphs, to measure text with, or None for a builtin font
+    face: Option<Face<'a>>,
+}
+
+impl Font<'_> {
+    /// How wide the character is, as a fraction of the font size
+    fn char_width(&self, c: char) -> f32 {
+        self.face
+            .as_ref()
+            .and_then(|face| {
+                let advance = face.glyph_hor_advance(face.glyph_index(c)?)?;
+                Some(advance as f32 / face.units_per_em()? as f32)
+            })
+            .unwrap_or(AVERAGE_CHAR_WIDTH)
+    }
+}
+
+/// Lays text and images out top to bottom, starting new pages as they fill up
+struct PageWriter<'a> {
+    doc: PdfDocumentReference,
+    layer: PdfLayerReference,
+    regular: Font<'a>,
+    bold: Font<'a>,
+    /// How far down the page we've written, measured from the bottom of the page
+    y: f32,
+}
+
+impl<'a> PageWriter<'a> {
+    /// Creates a writer for a new document, positioned at the top of its first page
+    ///
+    /// ### Arguments
+    ///
+    /// * `title` - The title of the document
+    /// * `fonts` - The fonts to embed, or None to fall back to the builtin Helvetica,
+    ///   which can only show Latin text
+    fn new(title: &str, fonts: Option<&'a PdfFonts>) -> Result<PageWriter<'a>, ExportError> {
+        let (doc, page, layer) =
+            PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+        let (regular, bold) = match fonts {
+            Some(fonts) => (
+                embed_font(&doc, &fonts.regular)?,
+                embed_font(&doc, &fonts.bold)?,
+            ),
+            None => (
+                builtin_font(&doc, BuiltinFont::Helvetica)?,
+                builtin_font(&doc, BuiltinFont::HelveticaBold)?,
+            ),
+        };
+        let layer = doc.get_page(page).get_layer(layer);
+
+        Ok(PageWriter {
+            doc,
+            layer,
+            regular,
+            bold,
+            y: PAGE_HEIGHT - MARGIN,
+        })
+    }
+
+    /// Starts writing at the top of a new page
+    fn new_page(&mut self) {
+        let (page, layer) = self
+            .doc
+            .add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+        self.layer = self.doc.get_page(page).get_layer(layer);
+        self.y = PAGE_HEIGHT - MARGIN;
+    }
+
+    /// Moves down the page, starting a new page if there isn't `height` mm of space left
+    fn reserve(&mut self, height: f32) {
+        if self.y - height < MARGIN {
+            self.new_page();
+        }
+        self.y -= height;
+    }
+
+    /// Writes the text, wrapping it across as many lines (and pages) as it needs
+    ///
+    /// ### Arguments
+    ///
+    /// * `text` - The text to write, newlines start a new line
+    /// * `size` - The font size, in points
+    /// * `bold` - Whether to write it in bold
+    /// * `indent` - How far in from the left margin to write, in mm
+    fn text(&mut self, text: &str, size: f32, bold: bool, indent: f32) {
+        let line_height = size * LINE_HEIGHT * MM_PER_PT;
+        let font = match bold {
+            true => &self.bold,
+            false => &self.regular,
+        };
+        let lines = wrap(text, CONTENT_WIDTH - indent, |c| {
+            font.char_width(c) * size * MM_PER_PT
+        });
+        let font = font.reference.clone();
+
+        for line in lines {
+            self.reserve(line_height);
+            self.layer
+                .use_text(line, size, Mm(MARGIN + indent), Mm(self.y), &font);
+        }
+    }
+
+    /// Leaves a gap of the given height, in mm
+    fn space(&mut self, height: f32) {
+        self.y -= height;
+    }
+
+    /// Draws the image scaled to fit the width of the page, skipping it if it can't be decoded
+    fn image(&mut self, bytes: &[u8]) {
+        let image = match image::load_from_memory(bytes) {
+            Ok(image) => image.to_rgb8(),
+            Err(_) => return,
+        };
+
+        // Shrink the image (by raising its dpi) until it fits on the page
+        let width = image.width() as f32;
+        let height = image.height() as f32;
+        let max_height = PAGE_HEIGHT - 2.0 * MARGIN;
+        let dpi = (width * 25.4 / CONTENT_WIDTH)
+            .max(height * 25.4 / max_height)
+            .max(150.0);
+        let height = height * 25.4 / dpi;
+
+        self.reserve(height);
+        let image = Image::from(ImageXObject {
+            width: Px(image.width() as usize),
+            height: Px(image.height() as usize),
+            color_space: ColorSpace::Rgb,
+            bits_per_component: ColorBits::Bit8,
+            interpolate: true,
+            image_data: image.into_raw(),
+            image_filter: None,
+            clipping_bbox: None,
+        });
+        image.add_to_layer(
+            self.layer.clone(),
+            ImageTransform {
+                translate_x: Some(Mm(MARGIN)),
+                translate_y: Some(Mm(self.y)),
+                dpi: Some(dpi),
+                ..Default::default()
+            },
+        );
+        self.space(BODY_SIZE * MM_PER_PT);
+    }
+}
+
+/// Embeds a TrueType font in the document
+fn embed_font<'a>(doc: &PdfDocumentReference, bytes: &'a [u8]) -> Result<Font<'a>, ExportError> {
+    Ok(Font {
+        reference: doc.add_external_font(bytes).map_err(|_| ExportError::Pdf)?,
+        face: Some(Face::from_slice(bytes, 0).map_err(|_| ExportError::Pdf)?),
+    })
+}
+
+/// Adds one of the fonts every pdf reader has to the document
+fn builtin_font(
+    doc: &PdfDocumentReference,
+    font: BuiltinFont,
+) -> Result<Font<'static>, ExportError> {
+    Ok(Font {
+        reference: doc.add_builtin_font(font).map_err(|_| ExportError::Pdf)?,
+        face: None,
+    })
+}
+
+/// Splits text into lines no wider than `max_width`, breaking between words. Words
+/// too long to fit on a line of their own are broken wherever they reach the edge
+///
+/// ### Arguments
+///
+/// * `text` - The text to split, newlines always start a new line
+/// * `max_width` - How wide a line can be
+/// * `char_width` - How wide each character is, in the same units as `max_width`
+fn wrap(text: &str, max_width: f32, char_width: impl Fn(char) -> f32) -> Vec<String> {
+    let space = char_width(' ');
+    let mut lines = vec![];
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        let mut line_width = 0.0;
+        for word in paragraph.split_whitespace() {
+            let word_width: f32 = word.chars().map(&char_width).sum();
+            if !line.is_empty() && line_width + space + word_width > max_width {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0.0;
+            }
+            if !line.is_empty() {
+                line.push(' ');
+                line_width += space;
+            }
+            for c in word.chars() {
+                let width = char_width(c);
+                if !line.is_empty() && line_width + width > max_width {
+                    lines.push(std::mem::take(&mut line));
+                    line_width = 0.0;
+                }
+                line.push(c);
+                line_width += width;
+            }
+        }
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Renders the book as a paginated A4 pdf
+///
+/// ### Arguments
+///
+/// * `book` - The book we're rendering
+/// * `fonts` - The fonts to write it in, or None to use the builtin fonts
+///
+/// ### Returns
+///
+/// The bytes of the pdf file, or ExportError::Pdf if we failed to generate it
+pub fn render(book: &Book, fonts: Option<&PdfFonts>) -> Result<Vec<u8>, ExportError> {
+    let mut writer = PageWriter::new(&book.title, fonts)?;
+
+    // Title page
+    writer.space(PAGE_HEIGHT / 3.0);
+    writer.text(&book.title, TITLE_SIZE, true, 0.0);
+    writer.space(HEADING_SIZE * MM_PER_PT);
+    writer.text(&book.subtitle(), HEADING_SIZE, false, 0.0);
+    writer.text(&book.author, HEADING_SIZE, false, 0.0);
+
+    for chapter in book.chapters() {
+        writer.new_page();
+        writer.text(&chapter.title, CHAPTER_SIZE, true, 0.0);
+        writer.space(CHAPTER_SIZE * MM_PER_PT);
+
+        for entry in chapter.entries {
+            writer.text(&date_heading(entry.created_at), HEADING_SIZE, true, 0.0);
+            if !entry.title.is_empty() {
+                writer.text(&entry.title, BODY_SIZE, true, 0.0);
+            }
+            writer.space(BODY_SIZE * MM_PER_PT);
+
+            for block in render::parse(&entry.content) {
+                write_block(&mut writer, book, &block);
+            }
+            writer.space(HEADING_SIZE * MM_PER_PT);
+        }
+    }
+
+    writer.doc.save_to_bytes().map_err(|_| ExportError::Pdf)
+}
+
+/// Writes a single block of an entry's content
+fn write_block(writer: &mut PageWriter, book: &Book, block: &Block) {
+    match block {
+        Block::Paragraph(text) => writer.text(text, BODY_SIZE, false, 0.0),
+        Block::Header { text, .. } => writer.text(text, BODY_SIZE + 2.0, true, 0.0),
+        Block::List { ordered, items } => {
+            for (i, item) in items.iter().enumerate() {
+                let bullet = match ordered {
+                    true => format!("{}.", i + 1),
+                    false => "-".to_string(),
+                };
+                writer.text(&format!("{bullet} {item}"), BODY_SIZE, false, 5.0);
+            }
+        }
+        Block::Checklist(items) => {
+            for (checked, item) in items {
+                let check = if *checked { "[x]" } else { "[ ]" };
+                writer.text(&format!("{check} {item}"), BODY_SIZE, false, 5.0);
+            }
+        }
+        Block::Quote { text, caption } => {
+            writer.text(text, BODY_SIZE, false, 10.0);
+            if !caption.is_empty() {
+                writer.text(&format!("- {caption}"), BODY_SIZE, false, 10.0);
+            }
+        }
+        Block::Code(code) => writer.text(code, BODY_SIZE - 1.0, false, 5.0),
+        Block::Image { url, caption } => {
+            let image = render::image_id(url).and_then(|id| book.images.get(&id));
+            if let Some(image) = image {
+                writer.image(&image.bytes);
+                if !caption.is_empty() {
+                    writer.text(caption, BODY_SIZE - 1.0, false, 0.0);
+                }
+            }
+        }
+        Block::Delimiter => writer.text("* * *", BODY_SIZE, false, CONTENT_WIDTH / 2.0 - 5.0),
+    }
+    writer.space(BODY_SIZE * 0.5 * MM_PER_PT);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_breaks_between_words() {
+        let lines = wrap("the quick brown fox", 9.0, |_| 1.0);
+        assert_eq!(lines, vec!["the quick", "brown fox"]);
+    }
+
+    #[test]
+    fn wrap_keeps_blank_lines() {
+        let lines = wrap("one\n\ntwo", 10.0, |_| 1.0);
+        assert_eq!(lines, vec!["one", "", "two"]);
+    }
+
+    #[test]
+    fn wrap_breaks_long_words() {
+        let lines = wrap("a abcdefghij b", 4.0, |_| 1.0);
+        assert_eq!(lines, vec!["a", "abcd", "efgh", "ij b"]);
+    }
+
+    #[test]
+    fn wrap_uses_character_widths() {
+        // Wide characters, like CJK, fill a line sooner
+        let width = |c: char| if c.is_ascii() { 1.0 } else { 2.0 };
+        let lines = wrap("日本語のテキスト", 6.0, width);
+        assert_eq!(lines, vec!["日本語", "のテキ", "スト"]);
+    }
+}
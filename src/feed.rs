@@ -0,0 +1,2 @@
+pub mod atom;
+pub mod ical;
@@ -0,0 +1,104 @@
+use rocket::time::{format_description::well_known, OffsetDateTime};
+
+use crate::{
+    db::note::FeedEntry,
+    render::{self, escape},
+    urls::PublicUrls,
+};
+
+/// Converts one of our millisecond update times into an RFC 3339 timestamp
+fn format_update_time(update_time: i64) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(update_time as i128 * 1_000_000)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+        .format(&well_known::Rfc3339)
+        .unwrap()
+}
+
+/// Renders the notes as an Atom feed, with each note's content rendered as html
+///
+/// ### Arguments
+///
+/// * `title` - The title of the feed
+/// * `feed_id` - A unique, unchanging id for the feed
+/// * `entries` - The notes in the feed, most recently updated first
+/// * `urls` - Builds the links to each note, and to the images in them
+///
+/// ### Returns
+///
+/// The feed's xml
+pub fn render(title: &str, feed_id: &str, entries: &[FeedEntry], urls: &PublicUrls) -> String {
+    // The feed was last updated when its most recently updated note was
+    let updated = entries
+        .iter()
+        .map(|entry| entry.update_time)
+        .max()
+        .unwrap_or(0);
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+        <title>{}</title>\n\
+        <id>{}</id>\n\
+        <updated>{}</updated>\n",
+        escape(title),
+        escape(feed_id),
+        format_update_time(updated)
+    );
+
+    for entry in entries {
+        let title = match entry.title.is_empty() {
+            true => "Untitled",
+            false => entry.title.as_str(),
+        };
+        // Our images may be stored with relative links, which feed readers can't follow
+        let image_src = |url: &str| {
+            Some(
+                render::image_id(url).map_or_else(|| url.to_string(), |id| urls.absolute_image(id)),
+            )
+        };
+        let html = render::to_html(&render::parse(&entry.content), image_src);
+
+        xml += &format!(
+            "<entry>\n\
+            <title>{}</title>\n\
+            <id>urn:journal:note:{}</id>\n\
+            <link href=\"{}\"/>\n\
+            <updated>{}</updated>\n\
+            <content type=\"html\">{}</content>\n\
+            </entry>\n",
+            escape(title),
+            entry.id,
+            escape(&urls.note(entry.id)),
+            format_update_time(entry.update_time),
+            escape(&html)
+        );
+    }
+    xml += "</feed>\n";
+
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_links_are_absolute() {
+        let entry = FeedEntry {
+            id: 1,
+            title: String::from("Photos"),
+            content: String::from(
+                r#"{"blocks":[
+                    {"type":"image","data":{"file":{"url":"/api/images/5"},"caption":""}},
+                    {"type":"image","data":{"file":{"url":"https://example.com/cat.png"},"caption":""}}
+                ]}"#,
+            ),
+            update_time: 0,
+        };
+        let urls = PublicUrls::new(Some("https://journal.test"), true).unwrap();
+        let feed = render("Journal", "urn:journal:feed:1", &[entry], &urls);
+
+        assert!(feed.contains("src=&quot;https://journal.test/api/images/5&quot;"));
+        assert!(feed.contains("src=&quot;https://example.com/cat.png&quot;"));
+    }
+}
@@ -0,0 +1,146 @@
+use rocket::time::{Date, Duration, OffsetDateTime, UtcOffset};
+
+use crate::{db::note::CalendarEntry, urls::PublicUrls};
+
+/// The longest a line in an iCalendar file is allowed to be, in bytes
+const MAX_LINE_LEN: usize = 75;
+
+/// Renders the diary entries as an iCalendar file, with one all-day event per entry,
+/// on the day it was written in the user's time zone
+///
+/// ### Arguments
+///
+/// * `entries` - The diary entries to put on the calendar
+/// * `urls` - Builds the links to each entry
+///
+/// ### Returns
+///
+/// The contents of the .ics file
+pub fn render(entries: &[CalendarEntry], urls: &PublicUrls) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//journal-rust-backend//Diary//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "X-WR-CALNAME:Journal".to_string(),
+    ];
+
+    for entry in entries {
+        let date = entry.date;
+        let updated = OffsetDateTime::from_unix_timestamp(entry.update_time / 1000)
+            .unwrap_or(entry.created_at);
+        let summary = match &entry.title {
+            Some(title) if !title.is_empty() => title.as_str(),
+            Some(_) => "Diary entry",
+            None => "Time capsule",
+        };
+
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:note-{}@journal", entry.id));
+        lines.push(format!("DTSTAMP:{}", format_timestamp(updated)));
+        lines.push(format!("DTSTART;VALUE=DATE:{}", format_date(date)));
+        lines.push(format!(
+            "DTEND;VALUE=DATE:{}",
+            format_date(date + Duration::days(1))
+        ));
+        lines.push(format!("SUMMARY:{}", escape(summary)));
+        lines.push(format!("URL:{}", urls.note(entry.id)));
+        lines.push("TRANSP:TRANSPARENT".to_string());
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold(line)).collect()
+}
+
+/// Formats a date as an iCalendar DATE, e.g. 20240304
+fn format_date(date: Date) -> String {
+    format!(
+        "{:04}{:02}{:02}",
+        date.year(),
+        u8::from(date.month()),
+        date.day()
+    )
+}
+
+/// Formats a timestamp as an iCalendar UTC DATE-TIME, e.g. 20240304T091500Z
+fn format_timestamp(timestamp: OffsetDateTime) -> String {
+    let timestamp = timestamp.to_offset(UtcOffset::UTC);
+    format!(
+        "{}T{:02}{:02}{:02}Z",
+        format_date(timestamp.date()),
+        timestamp.hour(),
+        timestamp.minute(),
+        timestamp.second()
+    )
+}
+
+/// Escapes text so it can be used as an iCalendar TEXT value
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+        .replace('\r', "")
+}
+
+/// Splits a line into CRLF-terminated lines no longer than MAX_LINE_LEN bytes, as
+/// iCalendar requires. Continuation lines start with a space
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > MAX_LINE_LEN {
+            folded.push_str("\r\n ");
+            len = 1;
+        }
+        folded.push(c);
+        len += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::time::Month;
+
+    use super::*;
+
+    #[test]
+    fn events_fall_on_the_users_day() {
+        // Written on the evening of the 4th in New York, which was already the 5th in UTC
+        let entry = CalendarEntry {
+            id: 1,
+            title: Some(String::from("Evening")),
+            update_time: 0,
+            created_at: Date::from_calendar_date(2024, Month::March, 5)
+                .unwrap()
+                .with_hms(1, 30, 0)
+                .unwrap()
+                .assume_utc(),
+            date: Date::from_calendar_date(2024, Month::March, 4).unwrap(),
+        };
+        let urls = PublicUrls::new(None, false).unwrap();
+        let calendar = render(&[entry], &urls);
+
+        assert!(calendar.contains("DTSTART;VALUE=DATE:20240304\r\n"));
+        assert!(calendar.contains("DTEND;VALUE=DATE:20240305\r\n"));
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(escape("a,b;c\\d\r\ne"), r"a\,b\;c\\d\ne");
+    }
+
+    #[test]
+    fn long_lines_are_folded() {
+        let line = "x".repeat(100);
+        let folded = fold(&line);
+        let lines: Vec<&str> = folded.split("\r\n").collect();
+        assert_eq!(lines[0].len(), MAX_LINE_LEN);
+        assert_eq!(lines[1], format!(" {}", "x".repeat(25)));
+        assert_eq!(lines[2], "");
+    }
+}
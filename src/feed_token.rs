@@ -0,0 +1,126 @@
+use base64::{engine::general_purpose, Engine as _};
+
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    Request, State,
+};
+use sqlx::{PgConnection, PgPool};
+
+const FEED_TOKEN_QUERY_PARAM: &str = "token";
+const FEED_TOKEN_LEN: usize = 32;
+
+/// Identifies a user by the secret token in a feed's url, rather than their session cookie.
+/// Calendar apps and feed readers can't log in, so this is how they read a user's feeds
+pub struct FeedToken {
+    pub user_id: i32,
+}
+
+impl FeedToken {
+    /// Generates a new feed token for the user, replacing any token they already had
+    ///
+    /// ### Arguments
+    ///
+    /// * `user_id` - The id of the user the token is for
+    /// * `conn` - A connection to the database storing the feed tokens
+    ///
+    /// ### Returns
+    ///
+    /// Error if we failed to save the token, otherwise the token itself. We only store its
+    /// hash, so this is the only time it's available
+    pub async fn rotate(user_id: i32, conn: &mut PgConnection) -> Result<String, sqlx::Error> {
+        let token = Self::generate_token();
+        sqlx::query!(
+            "INSERT INTO feed_tokens (user_id, token_hash) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET token_hash = EXCLUDED.token_hash",
+            user_id,
+            Self::hash(&token)
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Revokes the user's feed token, so their feeds can no longer be read
+    ///
+    /// ### Arguments
+    ///
+    /// * `user_id` - The id of the user whose token we're revoking
+    /// * `conn` - A connection to the database storing the feed tokens
+    ///
+    /// ### Returns
+    ///
+    /// Error if we failed to contact the database, otherwise true if a token was revoked
+    pub async fn revoke(user_id: i32, conn: &mut PgConnection) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query!("DELETE FROM feed_tokens WHERE user_id = $1", user_id)
+            .execute(conn)
+            .await?;
+
+        Ok(res.rows_affected() != 0)
+    }
+
+    /// Generates a random, url-safe token
+    fn generate_token() -> String {
+        let mut buf = [0; FEED_TOKEN_LEN];
+        openssl::rand::rand_bytes(&mut buf).unwrap();
+        general_purpose::URL_SAFE_NO_PAD.encode(buf)
+    }
+
+    /// Hashes a token for storage, so a leaked database doesn't leak everyone's feeds
+    fn hash(token: &str) -> String {
+        crate::hash::sha256_hex(token.as_bytes())
+    }
+}
+
+/// Stuff that can go wrong while reading a feed token with FromRequest
+#[derive(Debug)]
+pub enum FeedTokenError {
+    NoToken,
+    DBError,
+    NotFound,
+}
+
+/// Allows us to grab the user a feed belongs to from the token in its url
+#[async_trait]
+impl<'r> FromRequest<'r> for FeedToken {
+    type Error = FeedTokenError;
+
+    /// Gets the feed token for the request (validating it exists in the database)
+    async fn from_request(req: &'r Request<'_>) -> Outcome<FeedToken, FeedTokenError> {
+        // get the token out of the url
+        let token = match req.query_value::<&str>(FEED_TOKEN_QUERY_PARAM) {
+            Some(Ok(token)) => token,
+            _ => return Outcome::Failure((Status::Unauthorized, FeedTokenError::NoToken)),
+        };
+
+        // Get a DB connection
+        let pool: &State<PgPool> = match req.guard().await {
+            Outcome::Success(pool) => pool,
+            Outcome::Failure(_) => {
+                return Outcome::Failure((Status::InternalServerError, FeedTokenError::DBError))
+            }
+            Outcome::Forward(forward) => return Outcome::Forward(forward),
+        };
+        let mut conn = match crate::db::acquire_conn(pool).await {
+            Ok(conn) => conn,
+            Err(_) => {
+                return Outcome::Failure((Status::InternalServerError, FeedTokenError::DBError))
+            }
+        };
+
+        // Ensure the token exists in the database
+        let record = sqlx::query!(
+            "SELECT user_id FROM feed_tokens WHERE token_hash = $1",
+            Self::hash(token)
+        )
+        .fetch_one(conn.as_mut())
+        .await;
+        match record {
+            Ok(record) => Outcome::Success(FeedToken {
+                user_id: record.user_id,
+            }),
+            Err(_) => Outcome::Failure((Status::Unauthorized, FeedTokenError::NotFound)),
+        }
+    }
+}
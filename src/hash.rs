@@ -0,0 +1,43 @@
+use std::io::{self, Read};
+
+use openssl::sha::Sha256;
+
+/// How much of a file is hashed at a time
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Hashes the bytes with SHA-256
+///
+/// ### Returns
+///
+/// The hash as a lowercase hex string (64 characters long)
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    to_hex(&openssl::sha::sha256(bytes))
+}
+
+/// Hashes everything that can be read from the reader with SHA-256, a chunk at a time
+///
+/// ### Returns
+///
+/// The hash as a lowercase hex string (64 characters long), and the number of bytes
+/// that were hashed, or an error if we couldn't read them
+pub fn sha256_hex_reader(mut reader: impl Read) -> io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0; CHUNK_BYTES];
+    let mut len = 0;
+    loop {
+        match reader.read(&mut chunk)? {
+            0 => break,
+            read => {
+                hasher.update(&chunk[..read]);
+                len += read as u64;
+            }
+        }
+    }
+
+    Ok((to_hex(&hasher.finish()), len))
+}
+
+/// Formats a hash as a lowercase hex string
+fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,506 @@
+use std::collections::HashMap;
+
+use rocket::{
+    futures::TryStreamExt,
+    time::{Duration, OffsetDateTime},
+};
+use sqlx::PgPool;
+
+use crate::{
+    config::Config,
+    db::image::{self, VariantRecord},
+    image_store::{self, Kind, Stores},
+    render,
+};
+
+/// What the garbage collector did (or would do, on a dry run)
+#[derive(Default)]
+pub struct GcReport {
+    /// The number of images we looked at
+    pub scanned: usize,
+    /// The images whose reference count was wrong, with the old and corrected counts
+    pub corrected: Vec<(i32, i32, i32)>,
+    /// The images that just became unreferenced, starting their grace period
+    pub unreferenced: Vec<i32>,
+    /// The images that have been unreferenced for longer than the grace period
+    pub deleted: Vec<i32>,
+}
+
+/// Deletes images that no note references any more. Images aren't deleted as soon as
+/// they're unreferenced - the first run marks them, and they're only deleted once
+/// they've stayed unreferenced for the configured grace period. This gives editors
+/// time to save a note after uploading an image, and users time to undo an edit
+///
+/// Along the way, every image's reference count is corrected to the number of times
+/// the owner's notes actually reference it
+///
+/// ### Arguments
+///
+/// * `dry_run` - If true, nothing is changed, and the report describes what would be
+///
+/// ### Returns
+///
+/// A report of what was done, or a description of what went wrong
+pub async fn collect(dry_run: bool) -> Result<GcReport, String> {
+    let config = Config::load();
+    let pool = PgPool::connect(&config.database_url)
+        .await
+        .map_err(|err| format!("Failed to connect to the DB: {err}"))?;
+    let stores = image_store::build_all(&config, pool.clone(), Kind::Image)
+        .await
+        .map_err(|err| format!("Failed to set up the image stores: {err:?}"))?;
+    let variant_stores = image_store::build_all(&config, pool.clone(), Kind::Variant)
+        .await
+        .map_err(|err| format!("Failed to set up the variant stores: {err:?}"))?;
+
+    run(
+        &pool,
+        &stores,
+        &variant_stores,
+        config.image_gc_grace,
+        dry_run,
+    )
+    .await
+}
+
+/// Collects the images, with everything it needs already set up
+///
+/// ### Arguments
+///
+/// * `pool` - A pool of connections to the database storing the images and notes
+/// * `stores` - The stores the images' bytes might be in
+/// * `variant_stores` - The stores the images' variants' bytes might be in
+/// * `grace` - How long images have to be unreferenced before they're deleted
+/// * `dry_run` - If true, nothing is changed, and the report describes what would be
+///
+/// ### Returns
+///
+/// A report of what was done, or a description of what went wrong
+async fn run(
+    pool: &PgPool,
+    stores: &Stores,
+    variant_stores: &Stores,
+    grace: Duration,
+    dry_run: bool,
+) -> Result<GcReport, String> {
+    let references = count_references(pool)
+        .await
+        .map_err(|err| format!("Failed to read notes: {err}"))?;
+
+    let images = sqlx::query_as!(
+        ImageRecord,
+        "SELECT id, user_id, storage, reference_count, unreferenced_since FROM images WHERE NOT pending ORDER BY id"
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|err| format!("Failed to list images: {err}"))?;
+
+    let (mut report, changes) = plan(&images, &references, OffsetDateTime::now_utc(), grace);
+    if dry_run {
+        return Ok(report);
+    }
+
+    for change in changes {
+        match change {
+            Change::Delete(image) => {
+                let id = image.id;
+                let deleted = delete_unreferenced(pool, id, image.unreferenced_since)
+                    .await
+                    .map_err(|err| format!("Failed to delete image {id}: {err}"))?;
+                let Some(variants) = deleted else {
+                    report.deleted.retain(|deleted| *deleted != id);
+                    continue;
+                };
+                for variant in variants {
+                    let store = variant_stores.get(&variant.storage).map_err(|err| {
+                        format!(
+                            "Failed to find the store for variant {}: {err:?}",
+                            variant.id
+                        )
+                    })?;
+                    store.delete(variant.id).await.map_err(|err| {
+                        format!(
+                            "Failed to remove variant {} from {}: {err:?}",
+                            variant.id,
+                            store.name()
+                        )
+                    })?;
+                }
+                let store = stores
+                    .get(&image.storage)
+                    .map_err(|err| format!("Failed to find the store for image {id}: {err:?}"))?;
+                store.delete(id).await.map_err(|err| {
+                    format!("Failed to remove image {id} from {}: {err:?}", store.name())
+                })?;
+            }
+            Change::Update {
+                id,
+                reference_count,
+                unreferenced_since,
+            } => {
+                sqlx::query!(
+                    "UPDATE images SET reference_count = $1, unreferenced_since = $2 WHERE id = $3",
+                    reference_count,
+                    unreferenced_since,
+                    id
+                )
+                .execute(pool)
+                .await
+                .map_err(|err| format!("Failed to update image {id}: {err}"))?;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// An image, as the garbage collector sees it
+struct ImageRecord {
+    id: i32,
+    user_id: i32,
+    storage: String,
+    reference_count: i32,
+    unreferenced_since: Option<OffsetDateTime>,
+}
+
+/// Something the garbage collector will change about an image
+enum Change<'a> {
+    /// Delete the image, as long as it's still unreferenced
+    Delete(&'a ImageRecord),
+    /// Correct the image's reference count, or when it became unreferenced
+    Update {
+        id: i32,
+        reference_count: i32,
+        unreferenced_since: Option<OffsetDateTime>,
+    },
+}
+
+/// Where an image is in its grace period
+#[derive(Debug, PartialEq)]
+enum GracePeriod {
+    /// A note references the image, so it's not in one
+    Referenced,
+    /// The image just became unreferenced, so its grace period starts now
+    Starting,
+    /// The image became unreferenced at the time, and can't be deleted yet
+    Running(OffsetDateTime),
+    /// The image has been unreferenced for the whole grace period, so can be deleted
+    Over,
+}
+
+/// Works out where an image is in its grace period
+///
+/// ### Arguments
+///
+/// * `references` - How many times the owner's notes reference the image
+/// * `unreferenced_since` - When the image was recorded as becoming unreferenced
+/// * `now` - The time now
+/// * `grace` - How long images have to be unreferenced before they're deleted
+///
+/// ### Returns
+///
+/// Where the image is in its grace period
+fn grace_period(
+    references: i32,
+    unreferenced_since: Option<OffsetDateTime>,
+    now: OffsetDateTime,
+    grace: Duration,
+) -> GracePeriod {
+    match (references, unreferenced_since) {
+        (0, None) => GracePeriod::Starting,
+        (0, Some(since)) if since + grace <= now => GracePeriod::Over,
+        (0, Some(since)) => GracePeriod::Running(since),
+        _ => GracePeriod::Referenced,
+    }
+}
+
+/// Works out what the garbage collector should do to each image, without doing it
+///
+/// ### Arguments
+///
+/// * `images` - Every image that's finished uploading
+/// * `references` - The number of references to each image, keyed by the user whose
+///   notes reference it and the image's id
+/// * `now` - The time now
+/// * `grace` - How long images have to be unreferenced before they're deleted
+///
+/// ### Returns
+///
+/// A report of what would be done, and the changes to make to do it
+fn plan<'a>(
+    images: &'a [ImageRecord],
+    references: &HashMap<(i32, i32), i32>,
+    now: OffsetDateTime,
+    grace: Duration,
+) -> (GcReport, Vec<Change<'a>>) {
+    let mut report = GcReport {
+        scanned: images.len(),
+        ..GcReport::default()
+    };
+    let mut changes = vec![];
+    for image in images {
+        let id = image.id;
+        let count = references.get(&(image.user_id, id)).copied().unwrap_or(0);
+        if count != image.reference_count {
+            report.corrected.push((id, image.reference_count, count));
+        }
+
+        let unreferenced_since = match grace_period(count, image.unreferenced_since, now, grace) {
+            GracePeriod::Referenced => None,
+            GracePeriod::Starting => {
+                report.unreferenced.push(id);
+                Some(now)
+            }
+            GracePeriod::Running(since) => Some(since),
+            GracePeriod::Over => {
+                report.deleted.push(id);
+                changes.push(Change::Delete(image));
+                continue;
+            }
+        };
+        if count != image.reference_count || unreferenced_since != image.unreferenced_since {
+            changes.push(Change::Update {
+                id,
+                reference_count: count,
+                unreferenced_since,
+            });
+        }
+    }
+
+    (report, changes)
+}
+
+/// Counts every reference to an image in each user's notes, reading them a note at a
+/// time. Users can only use their own images, so references are counted per user
+///
+/// ### Arguments
+///
+/// * `pool` - A pool of connections to the database storing the notes
+///
+/// ### Returns
+///
+/// Error if we failed to read the notes, otherwise the number of references to each
+/// image, keyed by the user whose notes reference it and the image's id
+async fn count_references(pool: &PgPool) -> Result<HashMap<(i32, i32), i32>, sqlx::Error> {
+    let mut references = HashMap::new();
+    let mut notes = sqlx::query!("SELECT user_id, content FROM notes").fetch(pool);
+    while let Some(note) = notes.try_next().await? {
+        for image_id in render::image_ids(&note.content) {
+            *references.entry((note.user_id, image_id)).or_default() += 1;
+        }
+    }
+
+    Ok(references)
+}
+
+/// Deletes an image's record, and those of its variants, as long as it's still
+/// unreferenced. The notes were read a while ago, so they're checked again with the
+/// image locked, in case one's been saved with it since
+///
+/// ### Arguments
+///
+/// * `pool` - A pool of connections to the database storing the images and notes
+/// * `id` - The id of the image
+/// * `unreferenced_since` - When we read that the image became unreferenced
+///
+/// ### Returns
+///
+/// Error if we failed to contact the database, None if the image wasn't deleted,
+/// otherwise the variants deleted with it, whose bytes need removing from their stores
+async fn delete_unreferenced(
+    pool: &PgPool,
+    id: i32,
+    unreferenced_since: Option<OffsetDateTime>,
+) -> Result<Option<Vec<VariantRecord>>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Only delete the row if nothing's re-uploaded the image since we read it
+    let image = sqlx::query!(
+        "SELECT user_id FROM images WHERE id = $1 AND unreferenced_since = $2 FOR UPDATE",
+        id,
+        unreferenced_since
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let Some(image) = image else {
+        return Ok(None);
+    };
+    let referenced = sqlx::query_scalar!(
+        r#"SELECT EXISTS(
+            SELECT 1 FROM notes WHERE user_id = $1 AND content ~ ('/api/images/' || $2::integer || '([^0-9]|$)')
+        ) AS "referenced!""#,
+        image.user_id,
+        id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    if referenced {
+        return Ok(None);
+    }
+
+    let variants = image::delete_variants(&mut tx, id).await?;
+    sqlx::query!("DELETE FROM images WHERE id = $1", id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(Some(variants))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_store::{filesystem::FilesystemStore, tests, ImageStore};
+
+    const GRACE: Duration = Duration::days(7);
+
+    /// An image record, with the number of references recorded against it and when
+    /// it was recorded as becoming unreferenced
+    fn image(
+        id: i32,
+        reference_count: i32,
+        unreferenced_since: Option<OffsetDateTime>,
+    ) -> ImageRecord {
+        ImageRecord {
+            id,
+            user_id: 1,
+            storage: String::from("filesystem"),
+            reference_count,
+            unreferenced_since,
+        }
+    }
+
+    #[test]
+    fn referenced_images_are_never_in_a_grace_period() {
+        let now = OffsetDateTime::now_utc();
+        for since in [None, Some(now), Some(now - GRACE * 2)] {
+            assert_eq!(grace_period(1, since, now, GRACE), GracePeriod::Referenced);
+        }
+    }
+
+    #[test]
+    fn images_can_only_be_deleted_once_the_grace_period_is_over() {
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(grace_period(0, None, now, GRACE), GracePeriod::Starting);
+
+        let since = now - GRACE + Duration::seconds(1);
+        assert_eq!(
+            grace_period(0, Some(since), now, GRACE),
+            GracePeriod::Running(since)
+        );
+        assert_eq!(
+            grace_period(0, Some(now - GRACE), now, GRACE),
+            GracePeriod::Over
+        );
+        assert_eq!(
+            grace_period(0, Some(now - GRACE * 2), now, GRACE),
+            GracePeriod::Over
+        );
+    }
+
+    #[test]
+    fn plans_only_change_what_needs_changing() {
+        let now = OffsetDateTime::now_utc();
+        let images = [
+            // Referenced, and recorded as such
+            image(1, 1, None),
+            // Re-referenced during its grace period, with the wrong count
+            image(2, 0, Some(now - Duration::days(1))),
+            // Just unreferenced
+            image(3, 1, None),
+            // Partway through its grace period
+            image(4, 0, Some(now - Duration::days(1))),
+            // Past its grace period
+            image(5, 0, Some(now - GRACE)),
+        ];
+        let references = HashMap::from([((1, 1), 1), ((1, 2), 2)]);
+
+        let (report, changes) = plan(&images, &references, now, GRACE);
+
+        assert_eq!(report.scanned, 5);
+        assert_eq!(report.corrected, [(2, 0, 2), (3, 1, 0)]);
+        assert_eq!(report.unreferenced, [3]);
+        assert_eq!(report.deleted, [5]);
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|change| match change {
+                Change::Delete(image) => (image.id, None, None),
+                Change::Update {
+                    id,
+                    reference_count,
+                    unreferenced_since,
+                } => (*id, Some(*reference_count), Some(*unreferenced_since)),
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (2, Some(2), Some(None)),
+                (3, Some(0), Some(Some(now))),
+                (5, None, None)
+            ]
+        );
+    }
+
+    /// Reads the user's images' ids, reference counts and when they became unreferenced
+    async fn images_of(pool: &PgPool, user_id: i32) -> Vec<(i32, i32, Option<OffsetDateTime>)> {
+        sqlx::query!(
+            "SELECT id, reference_count, unreferenced_since FROM images WHERE user_id = $1 ORDER BY id",
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|image| (image.id, image.reference_count, image.unreferenced_since))
+        .collect()
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a scratch database at TEST_DATABASE_URL"]
+    async fn dry_runs_change_nothing() {
+        let pool = tests::test_pool().await;
+        let dir = tempfile::tempdir().unwrap();
+        let store = FilesystemStore::new(dir.path().to_path_buf())
+            .await
+            .unwrap();
+        let (user_id, expired) = tests::add_image(&pool, store.name()).await;
+        store.put(expired, b"an image").await.unwrap();
+        let new = sqlx::query_scalar!(
+            "INSERT INTO images (user_id, mime_type, storage, reference_count)
+            VALUES ($1, 'image/png', $2, 1) RETURNING id",
+            user_id,
+            store.name()
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        // Well past the grace period
+        sqlx::query!(
+            "UPDATE images SET reference_count = 0, unreferenced_since = now() - interval '30 days'
+            WHERE id = $1",
+            expired
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let stores = tests::only(store);
+        let variant_stores = tests::only(
+            FilesystemStore::new(dir.path().join("variants"))
+                .await
+                .unwrap(),
+        );
+
+        let before = images_of(&pool, user_id).await;
+        let report = run(&pool, &stores, &variant_stores, GRACE, true).await;
+        let after = images_of(&pool, user_id).await;
+        let bytes = stores.current().get(expired).await.unwrap();
+        tests::remove_user(&pool, user_id).await;
+
+        let report = report.unwrap();
+        assert!(report.deleted.contains(&expired));
+        assert!(report.unreferenced.contains(&new));
+        assert_eq!(before, after);
+        assert_eq!(bytes.as_deref(), Some(&b"an image"[..]));
+    }
+}
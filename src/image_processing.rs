@@ -0,0 +1,995 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::File,
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, RgbaImage,
+};
+use libheif_rs::{ColorSpace, HeifContext, ImageHandle, ItemId, LibHeif, RgbChroma};
+use rocket::time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+use tempfile::NamedTempFile;
+
+use crate::hash;
+
+/// How much of an upload is searched for markup at a time
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// The size images are shrunk to before their blurhash is worked out. Blurhashes are
+/// blurry enough that working from the full image makes no difference
+const BLURHASH_SOURCE_SIZE: u32 = 64;
+
+/// How many components a blurhash has across its longer side. More is more detailed,
+/// but longer
+const BLURHASH_COMPONENTS: u32 = 4;
+
+/// An uploaded image, cleaned up and ready to store
+pub struct ProcessedImage {
+    /// Where the processed image file is. This is either the upload itself, or a
+    /// temporary file that's removed when this is dropped
+    pub path: PathBuf,
+    /// Keeps the temporary file around until the image has been stored
+    _temp_file: Option<NamedTempFile>,
+    /// The length of the processed image file in bytes
+    pub size: u64,
+    /// The hex SHA-256 hash of the processed image file
+    pub sha256: String,
+    pub mime_type: &'static str,
+    /// The width of the image in pixels, once it's been rotated upright
+    pub width: u32,
+    /// The height of the image in pixels, once it's been rotated upright
+    pub height: u32,
+    /// A tiny, blurred placeholder for the image to show while it loads
+    pub blurhash: Option<String>,
+    /// When the photo was taken, according to its EXIF data
+    pub captured_at: Option<OffsetDateTime>,
+}
+
+/// The brands (from the ftyp box at the start of the file) of HEIF files holding
+/// HEVC-coded images, such as the photos iPhones take
+const HEIC_BRANDS: &[&[u8]] = &[b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx"];
+
+/// The brands of HEIF files holding any other kind of image
+const HEIF_BRANDS: &[&[u8]] = &[b"mif1", b"msf1"];
+
+/// The types of HEIF item that hold metadata rather than an image. XMP is kept in
+/// `mime` items
+const HEIF_METADATA_ITEMS: &[&[u8]] = &[b"Exif", b"mime"];
+
+/// The largest meta box we'll read out of a HEIF file. It only describes the file's
+/// items (their bytes are elsewhere), so real ones are tiny
+const HEIF_MAX_META_BYTES: u64 = 1024 * 1024;
+
+/// Markup that has no business being in an image file. Browsers that sniff content
+/// types could run a file containing it as a web page (or an SVG) rather than an image
+const MARKUP: &[&[u8]] = &[
+    b"<script",
+    b"<html",
+    b"<svg",
+    b"<?php",
+    b"<!doctype",
+    b"<?xml",
+];
+
+/// The kinds of image we accept
+enum Sniffed {
+    /// An image we can read and write
+    Raster(ImageFormat),
+    /// A HEIF image, which we can read but not write, and its mime type
+    Heif(&'static str),
+}
+
+/// Stuff that can go wrong while processing an uploaded image
+#[derive(Debug)]
+pub enum ProcessError {
+    /// It isn't an image format we accept
+    Unsupported,
+    /// Its bytes are a different format to the one it claimed to be
+    Mismatch,
+    /// It's an image, but it's also something else (a polyglot file)
+    Polyglot,
+    /// It claims to be an image we accept, but we couldn't read or rewrite it
+    Image(ImageError),
+    /// We couldn't read the upload, or write the processed image
+    Io(io::Error),
+}
+impl From<ImageError> for ProcessError {
+    fn from(err: ImageError) -> ProcessError {
+        ProcessError::Image(err)
+    }
+}
+impl From<io::Error> for ProcessError {
+    fn from(err: io::Error) -> ProcessError {
+        ProcessError::Io(err)
+    }
+}
+
+/// Prepares an uploaded image for storage. Its bytes are checked to really be the
+/// image it claims to be, then photos are rotated the way their EXIF data
+/// says they should be displayed, and re-encoded without any of their metadata, so
+/// things like the GPS coordinates phones embed in photos are never stored. The upload
+/// is read from disk as it's decoded, and the processed image is written back to disk,
+/// so neither file is ever held in memory whole. HEIF images (which browsers mostly
+/// can't show) can't be re-encoded, so are kept with their metadata blanked out, and
+/// converted when they're viewed
+///
+/// ### Arguments
+///
+/// * `path` - Where the uploaded image file is
+/// * `claimed_mime_type` - The mime type the upload said the file was
+/// * `keep_capture_time` - Whether to read when the photo was taken out of its
+///   metadata before it's stripped
+///
+/// ### Returns
+///
+/// The processed image, or an error if it isn't an image we can process. This is slow,
+/// so shouldn't be run on an async worker
+pub fn process(
+    path: &Path,
+    claimed_mime_type: &str,
+    keep_capture_time: bool,
+) -> Result<ProcessedImage, ProcessError> {
+    let format = match sniff(path, claimed_mime_type)? {
+        Sniffed::Raster(format) => format,
+        Sniffed::Heif(mime_type) => return process_heif(path, mime_type, keep_capture_time),
+    };
+    let mut reader = ImageReader::new(BufReader::new(File::open(path)?));
+    reader.set_format(format);
+    // GIFs don't carry EXIF data, and re-encoding them would lose their animation
+    if format == ImageFormat::Gif {
+        // Only the first frame is decoded, for the placeholder
+        let image = DynamicImage::from_decoder(reader.into_decoder()?)?;
+        let (sha256, size) = hash::sha256_hex_reader(File::open(path)?)?;
+        return Ok(ProcessedImage {
+            path: path.to_path_buf(),
+            _temp_file: None,
+            size,
+            sha256,
+            mime_type: format.to_mime_type(),
+            width: image.width(),
+            height: image.height(),
+            blurhash: blurhash(&image),
+            captured_at: None,
+        });
+    }
+
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let exif = decoder.exif_metadata()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    // Encoding writes none of the original's metadata
+    let out = NamedTempFile::new()?;
+    let mut writer = BufWriter::new(out.as_file());
+    image.write_to(&mut writer, format)?;
+    writer.flush()?;
+    drop(writer);
+    let (sha256, size) = hash::sha256_hex_reader(File::open(out.path())?)?;
+
+    Ok(ProcessedImage {
+        path: out.path().to_path_buf(),
+        _temp_file: Some(out),
+        size,
+        sha256,
+        mime_type: format.to_mime_type(),
+        width: image.width(),
+        height: image.height(),
+        blurhash: blurhash(&image),
+        captured_at: match exif {
+            Some(exif) if keep_capture_time => capture_time(exif),
+            _ => None,
+        },
+    })
+}
+
+/// Prepares an uploaded HEIF image for storage. We can't write HEIF images, so unlike
+/// other images it isn't re-encoded. Instead, a copy is kept with the bytes of its EXIF
+/// and XMP overwritten, which leaves its images untouched
+///
+/// ### Arguments
+///
+/// * `path` - Where the uploaded image file is
+/// * `mime_type` - The mime type of the image, from its brand
+/// * `keep_capture_time` - Whether to read when the photo was taken out of its metadata
+///
+/// ### Returns
+///
+/// The processed image, or an error if we couldn't decode it
+fn process_heif(
+    path: &Path,
+    mime_type: &'static str,
+    keep_capture_time: bool,
+) -> Result<ProcessedImage, ProcessError> {
+    let context = HeifContext::read_from_file(&path.to_string_lossy()).map_err(heif_error)?;
+    let handle = context.primary_image_handle().map_err(heif_error)?;
+    let image = decode_heif(&handle)?;
+
+    let out = NamedTempFile::new()?;
+    io::copy(&mut File::open(path)?, &mut out.as_file())?;
+    strip_heif_metadata(&mut out.as_file()).map_err(|err| match err.kind() {
+        // Files we can't find all the metadata in aren't kept
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            ProcessError::Image(heif_error(err))
+        }
+        _ => ProcessError::Io(err),
+    })?;
+    let (sha256, size) = hash::sha256_hex_reader(File::open(out.path())?)?;
+
+    Ok(ProcessedImage {
+        path: out.path().to_path_buf(),
+        _temp_file: Some(out),
+        size,
+        sha256,
+        mime_type,
+        width: image.width(),
+        height: image.height(),
+        blurhash: blurhash(&image),
+        captured_at: match heif_exif(&handle) {
+            Some(exif) if keep_capture_time => capture_time(exif),
+            _ => None,
+        },
+    })
+}
+
+/// Whether images of the mime type are HEIF images, which need converting before
+/// most browsers can show them
+pub fn is_heif(mime_type: &str) -> bool {
+    matches!(mime_type, "image/heic" | "image/heif")
+}
+
+/// Works out whether a file is a HEIF image from its first few bytes
+///
+/// ### Arguments
+///
+/// * `header` - The start of the file, at least its first 12 bytes
+///
+/// ### Returns
+///
+/// The mime type of the image, or None if it isn't a HEIF image
+pub fn heif_mime_type(header: &[u8]) -> Option<&'static str> {
+    if header.get(4..8)? != b"ftyp" {
+        return None;
+    }
+    let brand = header.get(8..12)?;
+    if HEIC_BRANDS.contains(&brand) {
+        Some("image/heic")
+    } else if HEIF_BRANDS.contains(&brand) {
+        Some("image/heif")
+    } else {
+        None
+    }
+}
+
+/// Decodes a HEIF image. This is slow, so shouldn't be run on an async worker
+///
+/// ### Arguments
+///
+/// * `bytes` - The HEIF image file
+///
+/// ### Returns
+///
+/// The image, rotated upright, or an error if we couldn't decode it
+pub fn load_heif(bytes: &[u8]) -> Result<DynamicImage, ImageError> {
+    let context = HeifContext::read_from_bytes(bytes).map_err(heif_error)?;
+    let handle = context.primary_image_handle().map_err(heif_error)?;
+    decode_heif(&handle)
+}
+
+/// Decodes the image of a HEIF file
+///
+/// ### Arguments
+///
+/// * `handle` - The image in the HEIF file to decode
+///
+/// ### Returns
+///
+/// The image, rotated upright, or an error if we couldn't decode it
+fn decode_heif(handle: &ImageHandle) -> Result<DynamicImage, ImageError> {
+    // libheif applies the rotation and mirroring the file asks for as it decodes
+    let image = LibHeif::new()
+        .decode(handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(heif_error)?;
+    let plane = image
+        .planes()
+        .interleaved
+        .ok_or_else(|| heif_error("The image has no pixels"))?;
+
+    // Rows can be padded past the end of their pixels
+    let row_len = plane.width as usize * 4;
+    let mut pixels = Vec::with_capacity(row_len * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(row.get(..row_len).ok_or_else(|| heif_error("Short row"))?);
+    }
+    let image = RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .ok_or_else(|| heif_error("The image is missing pixels"))?;
+
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+/// Gets the raw EXIF data out of a HEIF image
+///
+/// ### Arguments
+///
+/// * `handle` - The image in the HEIF file
+///
+/// ### Returns
+///
+/// The EXIF data, starting from its TIFF header, or None if it has none
+fn heif_exif(handle: &ImageHandle) -> Option<Vec<u8>> {
+    let count = usize::try_from(handle.number_of_metadata_blocks(b"Exif")).ok()?;
+    let mut ids: Vec<ItemId> = vec![0; count];
+    let found = handle.metadata_block_ids(&mut ids, b"Exif");
+    let id = *ids.get(..found)?.first()?;
+    let block = handle.metadata(id).ok()?;
+    // The block starts with how far past those first 4 bytes the TIFF header is
+    let offset = u32::from_be_bytes(block.get(..4)?.try_into().ok()?) as usize;
+    block.get(4 + offset..).map(<[u8]>::to_vec)
+}
+
+/// Where the bytes of an item in a HEIF file are
+struct ItemLocation {
+    /// 0 if the offsets are from the start of the file, or 1 if they're from the start
+    /// of the meta box's idat box
+    construction_method: u64,
+    base_offset: u64,
+    /// The offset and length of each piece of the item
+    extents: Vec<(u64, u64)>,
+}
+
+/// A box inside another HEIF box: its type, where its body starts in the outer box's
+/// body, and its body
+type ChildBox<'a> = ([u8; 4], usize, &'a [u8]);
+
+/// Blanks out the metadata in a HEIF file, such as its EXIF data (with any GPS location
+/// the camera recorded) and XMP. Each metadata item's bytes are overwritten with zeros
+/// where they are, so every other item stays where the file says it is
+///
+/// ### Arguments
+///
+/// * `file` - The HEIF file, which is changed in place
+///
+/// ### Returns
+///
+/// An error if we couldn't read or write the file, or an InvalidData or UnexpectedEof
+/// error if we couldn't find where all its metadata is
+fn strip_heif_metadata(file: &mut (impl Read + Write + Seek)) -> io::Result<()> {
+    // Find the meta box, which says what's in the file and where
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut meta = None;
+    let mut offset = 0;
+    while offset < len {
+        file.seek(SeekFrom::Start(offset))?;
+        let (kind, header, size) = read_box_header(file, len - offset)?;
+        if &kind == b"meta" {
+            if meta.is_some() {
+                return Err(invalid_heif("There's more than one meta box"));
+            }
+            if size - header > HEIF_MAX_META_BYTES {
+                return Err(invalid_heif("The meta box is too large"));
+            }
+            let mut body = vec![0; (size - header) as usize];
+            file.read_exact(&mut body)?;
+            meta = Some((offset + header, body));
+        }
+        offset += size;
+    }
+    let (meta_offset, meta) = meta.ok_or_else(|| invalid_heif("There's no meta box"))?;
+
+    // The meta box has a version and flags before its children
+    let mut metadata_items = HashSet::new();
+    let mut locations = HashMap::new();
+    let mut idat_offset = None;
+    for (kind, body_offset, body) in child_boxes(meta.get(4..).unwrap_or_default())? {
+        match &kind {
+            b"iinf" => metadata_items = heif_metadata_items(body)?,
+            b"iloc" => locations = heif_item_locations(body)?,
+            b"idat" => idat_offset = Some(meta_offset + 4 + body_offset as u64),
+            _ => (),
+        }
+    }
+
+    for id in metadata_items {
+        // Items without a location have no bytes
+        let Some(location) = locations.get(&id) else {
+            continue;
+        };
+        let start = match location.construction_method {
+            0 => 0,
+            1 => idat_offset.ok_or_else(|| invalid_heif("There's no idat box"))?,
+            _ => return Err(invalid_heif("Metadata is made from other items")),
+        };
+        for &(extent_offset, extent_len) in &location.extents {
+            // A length of 0 means the rest of the file, which would take the images too
+            let extent_start = start
+                .checked_add(location.base_offset)
+                .and_then(|offset| offset.checked_add(extent_offset))
+                .filter(|_| extent_len != 0);
+            let extent_end = extent_start
+                .and_then(|offset| offset.checked_add(extent_len))
+                .filter(|end| *end <= len)
+                .ok_or_else(|| invalid_heif("Metadata is outside the file"))?;
+
+            file.seek(SeekFrom::Start(extent_end - extent_len))?;
+            let zeros = vec![0; CHUNK_BYTES];
+            let mut left = extent_len;
+            while left > 0 {
+                let chunk = left.min(CHUNK_BYTES as u64);
+                file.write_all(&zeros[..chunk as usize])?;
+                left -= chunk;
+            }
+        }
+    }
+
+    file.flush()
+}
+
+/// Finds the metadata items in a HEIF file's iinf box
+///
+/// ### Arguments
+///
+/// * `iinf` - The body of the iinf box
+///
+/// ### Returns
+///
+/// The ids of the items holding metadata, or an error if the box is malformed
+fn heif_metadata_items(iinf: &[u8]) -> io::Result<HashSet<u64>> {
+    let mut reader = Cursor::new(iinf);
+    let version = read_uint(&mut reader, 1)?;
+    read_uint(&mut reader, 3)?;
+    read_uint(&mut reader, if version == 0 { 2 } else { 4 })?;
+
+    let mut items = HashSet::new();
+    let entries = iinf.get(reader.position() as usize..).unwrap_or_default();
+    for (kind, _, infe) in child_boxes(entries)? {
+        if &kind != b"infe" {
+            continue;
+        }
+        let mut reader = Cursor::new(infe);
+        let version = read_uint(&mut reader, 1)?;
+        read_uint(&mut reader, 3)?;
+        // Older entries don't have a type, and can't be EXIF or XMP
+        if version < 2 {
+            continue;
+        }
+        let id = read_uint(&mut reader, if version == 2 { 2 } else { 4 })?;
+        read_uint(&mut reader, 2)?;
+        let mut item_type = [0; 4];
+        reader.read_exact(&mut item_type)?;
+        if HEIF_METADATA_ITEMS.contains(&&item_type[..]) {
+            items.insert(id);
+        }
+    }
+
+    Ok(items)
+}
+
+/// Reads where each item in a HEIF file is from its iloc box
+///
+/// ### Arguments
+///
+/// * `iloc` - The body of the iloc box
+///
+/// ### Returns
+///
+/// The location of each item by id, or an error if the box is malformed
+fn heif_item_locations(iloc: &[u8]) -> io::Result<HashMap<u64, ItemLocation>> {
+    let mut reader = Cursor::new(iloc);
+    let version = read_uint(&mut reader, 1)?;
+    if version > 2 {
+        return Err(invalid_heif("Unknown iloc version"));
+    }
+    read_uint(&mut reader, 3)?;
+    let sizes = read_uint(&mut reader, 2)?;
+    let offset_size = (sizes >> 12) as usize & 0xf;
+    let length_size = (sizes >> 8) as usize & 0xf;
+    let base_offset_size = (sizes >> 4) as usize & 0xf;
+    let index_size = if version == 0 {
+        0
+    } else {
+        sizes as usize & 0xf
+    };
+    let id_size = if version < 2 { 2 } else { 4 };
+
+    let mut locations = HashMap::new();
+    for _ in 0..read_uint(&mut reader, id_size)? {
+        let id = read_uint(&mut reader, id_size)?;
+        let construction_method = match version {
+            0 => 0,
+            _ => read_uint(&mut reader, 2)? & 0xf,
+        };
+        // The data reference index, which is always this file in HEIF files
+        read_uint(&mut reader, 2)?;
+        let base_offset = read_uint(&mut reader, base_offset_size)?;
+        let mut extents = vec![];
+        for _ in 0..read_uint(&mut reader, 2)? {
+            read_uint(&mut reader, index_size)?;
+            let offset = read_uint(&mut reader, offset_size)?;
+            let length = read_uint(&mut reader, length_size)?;
+            extents.push((offset, length));
+        }
+        locations.insert(
+            id,
+            ItemLocation {
+                construction_method,
+                base_offset,
+                extents,
+            },
+        );
+    }
+
+    Ok(locations)
+}
+
+/// Splits the body of a HEIF box into the boxes inside it
+///
+/// ### Arguments
+///
+/// * `data` - The body of the box
+///
+/// ### Returns
+///
+/// The boxes, or an error if one runs past the end of `data`
+fn child_boxes(data: &[u8]) -> io::Result<Vec<ChildBox<'_>>> {
+    let mut children = vec![];
+    let mut reader = Cursor::new(data);
+    while (reader.position() as usize) < data.len() {
+        let start = reader.position();
+        let (kind, header, size) = read_box_header(&mut reader, data.len() as u64 - start)?;
+        let body = (start + header) as usize..(start + size) as usize;
+        children.push((kind, body.start, &data[body]));
+        reader.set_position(start + size);
+    }
+    Ok(children)
+}
+
+/// Reads the header at the start of a HEIF box
+///
+/// ### Arguments
+///
+/// * `reader` - Where the box is, positioned at its start
+/// * `remaining` - How many bytes there are from the start of the box to the end of
+///   whatever it's in
+///
+/// ### Returns
+///
+/// The type of the box, the length of its header and its length including the header,
+/// or an error if it's cut off or runs past `remaining`
+fn read_box_header(reader: &mut impl Read, remaining: u64) -> io::Result<([u8; 4], u64, u64)> {
+    let size = read_uint(reader, 4)?;
+    let mut kind = [0; 4];
+    reader.read_exact(&mut kind)?;
+    let (header, size) = match size {
+        0 => (8, remaining),
+        1 => (16, read_uint(reader, 8)?),
+        size => (8, size),
+    };
+    if size < header || size > remaining {
+        return Err(invalid_heif("A box runs past its end"));
+    }
+    Ok((kind, header, size))
+}
+
+/// Reads a big-endian unsigned integer from a HEIF box
+///
+/// ### Arguments
+///
+/// * `reader` - Where the integer is
+/// * `bytes` - How long the integer is, up to 8 bytes. A length of 0 reads as 0
+///
+/// ### Returns
+///
+/// The integer, or an error if it's cut off or too long
+fn read_uint(reader: &mut impl Read, bytes: usize) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    let start = 8usize
+        .checked_sub(bytes)
+        .ok_or_else(|| invalid_heif("An integer is too long"))?;
+    reader.read_exact(&mut buf[start..])?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Makes an error for a HEIF file we couldn't make sense of
+fn invalid_heif(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Wraps up something that went wrong reading a HEIF image as an image error
+fn heif_error(err: impl Into<Box<dyn Error + Send + Sync>>) -> ImageError {
+    ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name(String::from("HEIF")),
+        err,
+    ))
+}
+
+/// Works out what format an image is from its bytes, rather than trusting the type it
+/// was uploaded with. Only raster formats are accepted - SVGs can contain scripts.
+/// Formats we don't re-encode (GIF and HEIF) are also searched for markup.
+/// Everything else is re-encoded, which drops anything that isn't the image, so photos
+/// carrying XMP metadata aren't turned away
+///
+/// ### Arguments
+///
+/// * `path` - Where the image file is
+/// * `claimed_mime_type` - The mime type the upload said the file was
+///
+/// ### Returns
+///
+/// The kind of image it is, or an error if it isn't one we accept, isn't the format
+/// it claimed to be, or would be stored with markup as well as an image
+fn sniff(path: &Path, claimed_mime_type: &str) -> Result<Sniffed, ProcessError> {
+    // Every format we accept can be recognised from its first few bytes
+    let mut header = vec![];
+    File::open(path)?.take(32).read_to_end(&mut header)?;
+    let claimed_mime_type = claimed_mime_type.to_ascii_lowercase();
+
+    let sniffed = match heif_mime_type(&header) {
+        // Clients don't agree on whether to call HEIC photos HEIC or HEIF
+        Some(mime_type) if is_heif(&claimed_mime_type) => Sniffed::Heif(mime_type),
+        Some(_) => return Err(ProcessError::Mismatch),
+        None => {
+            let format = match image::guess_format(&header) {
+                Ok(
+                    format @ (ImageFormat::Jpeg
+                    | ImageFormat::Png
+                    | ImageFormat::WebP
+                    | ImageFormat::Gif),
+                ) => format,
+                _ => return Err(ProcessError::Unsupported),
+            };
+            let claimed = match claimed_mime_type.as_str() {
+                "image/jpg" | "image/pjpeg" => ImageFormat::Jpeg,
+                claimed => ImageFormat::from_mime_type(claimed).ok_or(ProcessError::Mismatch)?,
+            };
+            if claimed != format {
+                return Err(ProcessError::Mismatch);
+            }
+            Sniffed::Raster(format)
+        }
+    };
+
+    let verbatim = matches!(
+        sniffed,
+        Sniffed::Heif(_) | Sniffed::Raster(ImageFormat::Gif)
+    );
+    if verbatim && contains_markup(File::open(path)?)? {
+        return Err(ProcessError::Polyglot);
+    }
+
+    Ok(sniffed)
+}
+
+/// Searches a file for markup, a chunk at a time
+///
+/// ### Arguments
+///
+/// * `file` - The file to search
+///
+/// ### Returns
+///
+/// Whether the file contains any markup, or an error if we couldn't read it
+fn contains_markup(mut file: impl Read) -> io::Result<bool> {
+    // Markup can straddle two chunks, so the end of each chunk is searched again
+    // along with the next
+    let overlap = MARKUP.iter().map(|markup| markup.len()).max().unwrap_or(1) - 1;
+    let mut chunk = vec![0; CHUNK_BYTES];
+    let mut window = Vec::with_capacity(CHUNK_BYTES + overlap);
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(false);
+        }
+        window.extend(chunk[..read].iter().map(u8::to_ascii_lowercase));
+        if MARKUP.iter().any(|markup| {
+            window
+                .windows(markup.len())
+                .any(|candidate| candidate == *markup)
+        }) {
+            return Ok(true);
+        }
+        window.drain(..window.len().saturating_sub(overlap));
+    }
+}
+
+/// Works out the blurhash of an image, a short string that decodes to a blurred
+/// version of it
+///
+/// ### Arguments
+///
+/// * `image` - The image, rotated upright
+///
+/// ### Returns
+///
+/// The blurhash, or None if the image is empty
+fn blurhash(image: &DynamicImage) -> Option<String> {
+    if image.width() == 0 || image.height() == 0 {
+        return None;
+    }
+    let small = image
+        .thumbnail(BLURHASH_SOURCE_SIZE, BLURHASH_SOURCE_SIZE)
+        .to_rgba8();
+
+    // Keep the components roughly in proportion with the image
+    let (width, height) = small.dimensions();
+    let (x_components, y_components) = if width >= height {
+        let y = (BLURHASH_COMPONENTS * height / width).max(1);
+        (BLURHASH_COMPONENTS, y)
+    } else {
+        let x = (BLURHASH_COMPONENTS * width / height).max(1);
+        (x, BLURHASH_COMPONENTS)
+    };
+
+    blurhash::encode(x_components, y_components, width, height, small.as_raw()).ok()
+}
+
+/// Reads when a photo was taken out of its EXIF data
+///
+/// ### Arguments
+///
+/// * `exif` - The raw EXIF data of the photo
+///
+/// ### Returns
+///
+/// When the photo was taken, or None if it isn't recorded (or is invalid). Cameras that
+/// don't record their UTC offset are assumed to be on UTC
+fn capture_time(exif: Vec<u8>) -> Option<OffsetDateTime> {
+    let exif = exif::Reader::new().read_raw(exif).ok()?;
+    let ascii = |tag| match exif
+        .get_field(tag, exif::In::PRIMARY)
+        .map(|field| &field.value)
+    {
+        Some(exif::Value::Ascii(values)) => values.first().cloned(),
+        _ => None,
+    };
+
+    let mut taken = exif::DateTime::from_ascii(&ascii(exif::Tag::DateTimeOriginal)?).ok()?;
+    if let Some(offset) = ascii(exif::Tag::OffsetTimeOriginal) {
+        let _ = taken.parse_offset(&offset);
+    }
+
+    let date = Date::from_calendar_date(
+        taken.year.into(),
+        Month::try_from(taken.month).ok()?,
+        taken.day,
+    )
+    .ok()?;
+    let time = Time::from_hms(taken.hour, taken.minute, taken.second).ok()?;
+    let offset = UtcOffset::from_whole_seconds(i32::from(taken.offset.unwrap_or(0)) * 60).ok()?;
+
+    Some(PrimitiveDateTime::new(date, time).assume_offset(offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Sniffs the bytes as an upload of the claimed type
+    fn sniff_bytes(bytes: &[u8], claimed_mime_type: &str) -> Result<Sniffed, ProcessError> {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(bytes).unwrap();
+        sniff(file.path(), claimed_mime_type)
+    }
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\0\x10JFIF\0";
+    const GIF: &[u8] = b"GIF89a\x01\0\x01\0";
+    const HEIC: &[u8] = b"\0\0\0\x18ftypheic\0\0\0\0";
+
+    #[test]
+    fn heif_images_are_recognised_by_their_brand() {
+        assert_eq!(heif_mime_type(HEIC), Some("image/heic"));
+        assert_eq!(heif_mime_type(b"\0\0\0\x18ftypmif1"), Some("image/heif"));
+        // Other ISO media files, like MP4 videos, have brands too
+        assert_eq!(heif_mime_type(b"\0\0\0\x18ftypisom"), None);
+        assert_eq!(heif_mime_type(b"\0\0\0\x18ftyp"), None);
+        assert_eq!(heif_mime_type(PNG), None);
+    }
+
+    #[test]
+    fn images_are_sniffed_from_their_bytes() {
+        assert!(matches!(
+            sniff_bytes(PNG, "image/png"),
+            Ok(Sniffed::Raster(ImageFormat::Png))
+        ));
+        assert!(matches!(
+            sniff_bytes(JPEG, "image/jpg"),
+            Ok(Sniffed::Raster(ImageFormat::Jpeg))
+        ));
+        // HEIC photos are accepted whichever of the two names they're uploaded with
+        assert!(matches!(
+            sniff_bytes(HEIC, "image/heif"),
+            Ok(Sniffed::Heif("image/heic"))
+        ));
+    }
+
+    #[test]
+    fn images_must_be_what_they_claim() {
+        assert!(matches!(
+            sniff_bytes(PNG, "image/jpeg"),
+            Err(ProcessError::Mismatch)
+        ));
+        assert!(matches!(
+            sniff_bytes(HEIC, "image/png"),
+            Err(ProcessError::Mismatch)
+        ));
+        assert!(matches!(
+            sniff_bytes(
+                b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>",
+                "image/svg+xml"
+            ),
+            Err(ProcessError::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn only_images_stored_verbatim_are_searched_for_markup() {
+        let with_markup = |image: &[u8]| [image, b"<script>alert(1)</script>"].concat();
+        assert!(matches!(
+            sniff_bytes(&with_markup(GIF), "image/gif"),
+            Err(ProcessError::Polyglot)
+        ));
+        assert!(matches!(
+            sniff_bytes(&with_markup(HEIC), "image/heic"),
+            Err(ProcessError::Polyglot)
+        ));
+        // Re-encoding leaves the XMP behind
+        let xmp = [JPEG, b"<?xml version=\"1.0\"?><x:xmpmeta/>"].concat();
+        assert!(matches!(
+            sniff_bytes(&xmp, "image/jpeg"),
+            Ok(Sniffed::Raster(ImageFormat::Jpeg))
+        ));
+    }
+
+    /// Wraps the body up as an ISO media box
+    fn boxed(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u32 + 8).to_be_bytes()[..], kind, body].concat()
+    }
+
+    /// Makes a HEIC file with an image and its EXIF in the mdat box, and its XMP in the
+    /// idat box, with the EXIF taking up `exif_len` bytes according to the iloc box
+    fn heic(pixels: &[u8], exif: &[u8], exif_len: u32, xmp: &[u8]) -> Vec<u8> {
+        let ftyp = boxed(b"ftyp", b"heic\0\0\0\0mif1heic");
+        let hdlr = boxed(b"hdlr", &[&[0; 8][..], b"pict", &[0; 13]].concat());
+        let infe = |id: u8, item_type: &[u8], extra: &[u8]| {
+            boxed(
+                b"infe",
+                &[&[2, 0, 0, 0, 0, id, 0, 0][..], item_type, b"\0", extra].concat(),
+            )
+        };
+        let iinf = boxed(
+            b"iinf",
+            &[
+                &[0, 0, 0, 0, 0, 3][..],
+                &infe(1, b"hvc1", b""),
+                &infe(2, b"Exif", b""),
+                &infe(3, b"mime", b"application/rdf+xml\0"),
+            ]
+            .concat(),
+        );
+        let meta = |mdat_start: u32| {
+            // Version 1, with 4 byte offsets and lengths and no base offsets
+            let mut iloc = vec![1, 0, 0, 0, 0x44, 0, 0, 3];
+            let mut item = |id: u8, construction_method: u8, offset: u32, len: u32| {
+                iloc.extend([0, id, 0, construction_method, 0, 0, 0, 1]);
+                iloc.extend(offset.to_be_bytes());
+                iloc.extend(len.to_be_bytes());
+            };
+            item(1, 0, mdat_start, pixels.len() as u32);
+            item(2, 0, mdat_start + pixels.len() as u32, exif_len);
+            item(3, 1, 0, xmp.len() as u32);
+            let children = [
+                hdlr.clone(),
+                iinf.clone(),
+                boxed(b"iloc", &iloc),
+                boxed(b"idat", xmp),
+            ];
+            boxed(b"meta", &[&[0; 4][..], &children.concat()].concat())
+        };
+        let mdat_start = (ftyp.len() + meta(0).len() + 8) as u32;
+        let mdat = boxed(b"mdat", &[pixels, exif].concat());
+        [ftyp, meta(mdat_start), mdat].concat()
+    }
+
+    /// Makes EXIF data, prefixed the way HEIF files store it, saying where a photo was taken
+    fn gps_exif() -> Vec<u8> {
+        let fields = [
+            exif::Field {
+                tag: exif::Tag::GPSLatitudeRef,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Ascii(vec![b"N".to_vec()]),
+            },
+            exif::Field {
+                tag: exif::Tag::GPSLatitude,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Rational(vec![(51, 1).into(), (30, 1).into(), (26, 1).into()]),
+            },
+        ];
+        let mut writer = exif::experimental::Writer::new();
+        fields.iter().for_each(|field| writer.push_field(field));
+        let mut tiff = Cursor::new(vec![]);
+        writer.write(&mut tiff, false).unwrap();
+        [&[0; 4][..], &tiff.into_inner()].concat()
+    }
+
+    /// Whether the HEIF file has EXIF data saying where the photo was taken
+    fn has_location(file: &[u8]) -> bool {
+        match exif::Reader::new().read_from_container(&mut Cursor::new(file)) {
+            Ok(exif) => exif
+                .get_field(exif::Tag::GPSLatitude, exif::In::PRIMARY)
+                .is_some(),
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn heic_uploads_lose_their_location() {
+        let pixels = b"not really hevc, but the pixels all the same";
+        let exif = gps_exif();
+        let xmp = b"<x:xmpmeta><exif:GPSLatitude>51,30.4N</exif:GPSLatitude></x:xmpmeta>";
+        let original = heic(pixels, &exif, exif.len() as u32, xmp);
+        assert!(has_location(&original));
+
+        let mut file = Cursor::new(original.clone());
+        strip_heif_metadata(&mut file).unwrap();
+        let stripped = file.into_inner();
+
+        assert!(!has_location(&stripped));
+        assert!(!stripped.windows(11).any(|bytes| bytes == b"GPSLatitude"));
+        // Everything but the metadata is left where it was
+        assert_eq!(stripped.len(), original.len());
+        let mdat_start = original.len() - pixels.len() - exif.len();
+        assert_eq!(
+            &stripped[..mdat_start - xmp.len() - 8],
+            &original[..mdat_start - xmp.len() - 8]
+        );
+        assert_eq!(&stripped[mdat_start..mdat_start + pixels.len()], pixels);
+    }
+
+    #[test]
+    fn heic_files_with_metadata_we_cant_find_are_rejected() {
+        let exif = gps_exif();
+        // Lengths of 0 mean the rest of the file
+        let mut file = Cursor::new(heic(b"pixels", &exif, 0, b"<x:xmpmeta/>"));
+        assert_eq!(
+            strip_heif_metadata(&mut file).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut file = Cursor::new(heic(b"pixels", &exif, exif.len() as u32 + 1, b""));
+        assert_eq!(
+            strip_heif_metadata(&mut file).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut file = Cursor::new(HEIC.to_vec());
+        assert!(strip_heif_metadata(&mut file).is_err());
+    }
+
+    #[test]
+    fn markup_is_found_across_chunks() {
+        // Starting a few bytes before the end of the first chunk
+        let mut file = vec![0; CHUNK_BYTES - 3];
+        file.extend(b"<SCRIPT");
+        assert!(contains_markup(Cursor::new(&file)).unwrap());
+
+        // And entirely inside a later chunk
+        let mut file = vec![0; CHUNK_BYTES * 2 + 10];
+        file.extend(b"<html>");
+        assert!(contains_markup(Cursor::new(&file)).unwrap());
+    }
+
+    #[test]
+    fn markup_split_between_unrelated_bytes_is_not_found() {
+        let mut file = vec![0; CHUNK_BYTES - 3];
+        file.extend(b"<sc");
+        file.extend(vec![0; 10]);
+        file.extend(b"ript");
+        assert!(!contains_markup(Cursor::new(&file)).unwrap());
+    }
+}
@@ -0,0 +1,523 @@
+use std::{
+    future::Future,
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use rocket::tokio::{
+    io::{self, AsyncRead, DuplexStream, ReadBuf},
+    sync::oneshot,
+    task,
+};
+use sqlx::PgPool;
+
+use crate::config::{self, Config, ImageStoreConfig};
+
+pub mod filesystem;
+pub mod postgres;
+pub mod s3;
+
+/// How much of a file being copied into a pipe can be buffered before it's read, in bytes
+const PIPE_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Stuff that can go wrong while reading or writing an image file
+#[derive(Debug)]
+pub enum StoreError {
+    Database(sqlx::Error),
+    Io(std::io::Error),
+    S3(String),
+    /// The file is kept in a backend that isn't configured, named here
+    Unconfigured(String),
+}
+
+/// The kinds of file we keep, each identified by their id in their own table
+#[derive(Clone, Copy)]
+pub enum Kind {
+    /// Images, from the images table
+    Image,
+    /// File attachments, from the files table
+    Attachment,
+    /// Resized or converted versions of images, from the image_variants table
+    Variant,
+}
+
+/// A stored file being read a chunk at a time, so it never has to be held in memory whole
+pub struct FileStream {
+    pub reader: Pin<Box<dyn AsyncRead + Send>>,
+    /// The length of the file in bytes
+    pub len: u64,
+}
+
+impl FileStream {
+    /// Streams a file that's copied into a pipe by a task of its own, for backends whose
+    /// downloads can't be read from directly. If the copy fails, reading fails once
+    /// everything copied before then has been read, rather than the file ending early,
+    /// so responses it's sent in are cut off instead of looking complete
+    ///
+    /// ### Arguments
+    ///
+    /// * `len` - The length of the file in bytes
+    /// * `copy` - Copies the file into the pipe it's given
+    ///
+    /// ### Returns
+    ///
+    /// The stream
+    pub fn piped<F, Fut>(len: u64, copy: F) -> FileStream
+    where
+        F: FnOnce(DuplexStream) -> Fut,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let (writer, pipe) = io::duplex(PIPE_BUFFER_BYTES);
+        let (sender, result) = oneshot::channel();
+        let copying = copy(writer);
+        task::spawn(async move {
+            let _ = sender.send(copying.await);
+        });
+
+        FileStream {
+            reader: Box::pin(PipeReader {
+                pipe,
+                result: Some(result),
+            }),
+            len,
+        }
+    }
+}
+
+/// Reads the file a task is copying into a pipe, and whether the copy succeeded once
+/// the pipe's been emptied
+struct PipeReader {
+    pipe: DuplexStream,
+    /// Whether the copy succeeded, until it's been passed on
+    result: Option<oneshot::Receiver<io::Result<()>>>,
+}
+
+impl AsyncRead for PipeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.pipe).poll_read(cx, buf))?;
+        if buf.filled().len() > filled || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        // The pipe's closed, so the copy is over. Only let the file end if it finished
+        let Some(result) = this.result.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let result = ready!(Pin::new(result).poll(cx));
+        this.result = None;
+        Poll::Ready(
+            result.unwrap_or_else(|_| Err(io::Error::other("The copy stopped before it finished"))),
+        )
+    }
+}
+
+/// The store new files of one kind are written to, along with a store for every other
+/// configured backend. Each file's record names the backend it was written to, which
+/// stays the same until it's migrated, so files are read and deleted through that
+pub struct Stores {
+    current: Box<dyn ImageStore>,
+    others: Vec<Box<dyn ImageStore>>,
+}
+
+impl Stores {
+    /// The store new files are written to
+    pub fn current(&self) -> &dyn ImageStore {
+        self.current.as_ref()
+    }
+
+    /// The store for the named backend
+    ///
+    /// ### Arguments
+    ///
+    /// * `backend` - The name of the backend, as recorded against the file
+    ///
+    /// ### Returns
+    ///
+    /// The store, or StoreError::Unconfigured if the backend isn't configured
+    pub fn get(&self, backend: &str) -> Result<&dyn ImageStore, StoreError> {
+        std::iter::once(&self.current)
+            .chain(&self.others)
+            .find(|store| store.name() == backend)
+            .map(|store| store.as_ref())
+            .ok_or_else(|| StoreError::Unconfigured(String::from(backend)))
+    }
+}
+
+/// The stores for images, and for the variants generated from them
+pub struct ImageStores {
+    pub images: Stores,
+    pub variants: Stores,
+}
+
+/// The stores for file attachments, kept apart from the image stores so each can be
+/// told apart in Rocket's managed state
+pub struct AttachmentStores(pub Stores);
+
+/// Somewhere to keep the bytes of uploaded images (or attachments, for a store of
+/// Kind::Attachment). Everything else about an image (who owns it, its mime type, etc)
+/// stays in the images table, and images are identified by their id in that table
+#[async_trait]
+pub trait ImageStore: Send + Sync {
+    /// The name of the backend, recorded against each image it stores
+    fn name(&self) -> &'static str;
+
+    /// Stores the bytes of the image, replacing any bytes already stored for it
+    async fn put(&self, id: i32, bytes: &[u8]) -> Result<(), StoreError>;
+
+    /// Stores the file at the path as the image, replacing any bytes already stored for
+    /// it. Backends that can copy it across a chunk at a time do, rather than reading
+    /// it into memory
+    async fn put_file(&self, id: i32, path: &Path) -> Result<(), StoreError>;
+
+    /// Gets the bytes of the image, or None if we aren't storing any for it
+    async fn get(&self, id: i32) -> Result<Option<Vec<u8>>, StoreError>;
+
+    /// Opens the image to be read a chunk at a time, or None if we aren't storing any
+    /// for it
+    async fn open(&self, id: i32) -> Result<Option<FileStream>, StoreError>;
+
+    /// Deletes the bytes of the image. Deleting an image that isn't stored isn't an error
+    async fn delete(&self, id: i32) -> Result<(), StoreError>;
+}
+
+/// Creates the image store described by the config
+///
+/// ### Arguments
+///
+/// * `config` - The backend to use, and its settings
+/// * `pool` - A pool of connections to the database, for the postgres backend
+/// * `kind` - The kind of file the store is for
+///
+/// ### Returns
+///
+/// The image store, or an error if we couldn't set up the backend
+pub async fn build(
+    config: &ImageStoreConfig,
+    pool: PgPool,
+    kind: Kind,
+) -> Result<Box<dyn ImageStore>, StoreError> {
+    Ok(match config {
+        ImageStoreConfig::Postgres => Box::new(postgres::PostgresStore::new(pool, kind)),
+        ImageStoreConfig::Filesystem { root } => {
+            // Images are kept at the top level, as they were before we had attachments
+            let root = match kind {
+                Kind::Image => root.clone(),
+                Kind::Attachment => root.join("files"),
+                Kind::Variant => root.join("variants"),
+            };
+            Box::new(filesystem::FilesystemStore::new(root).await?)
+        }
+        ImageStoreConfig::S3 {
+            bucket,
+            region,
+            endpoint,
+            access_key,
+            secret_key,
+        } => Box::new(s3::S3Store::new(
+            bucket, region, endpoint, access_key, secret_key, kind,
+        )?),
+    })
+}
+
+/// Creates a store for every configured backend, writing new files to the one
+/// `IMAGE_STORE` names
+///
+/// ### Arguments
+///
+/// * `config` - The server's configuration, with the settings of each backend
+/// * `pool` - A pool of connections to the database, for the postgres backend
+/// * `kind` - The kind of file the stores are for
+///
+/// ### Returns
+///
+/// The stores, or an error if we couldn't set up one of the backends
+pub async fn build_all(config: &Config, pool: PgPool, kind: Kind) -> Result<Stores, StoreError> {
+    let current = build(&config.image_store, pool.clone(), kind).await?;
+    let mut others = vec![];
+    for other in &config.other_image_stores {
+        others.push(build(other, pool.clone(), kind).await?);
+    }
+
+    Ok(Stores { current, others })
+}
+
+/// Moves every image, attachment and variant stored in one backend into another. Safe to re-run
+/// if it's interrupted, as each file is only marked as moved once it's been copied
+///
+/// ### Arguments
+///
+/// * `from` - The name of the backend the images are currently stored in
+/// * `to` - The name of the backend to move them to
+///
+/// ### Returns
+///
+/// The number of files moved, or a description of what went wrong
+pub async fn migrate(from: &str, to: &str) -> Result<usize, String> {
+    if from == to {
+        return Err(String::from("The backends must be different"));
+    }
+
+    let vars = config::read_vars();
+    let pool = PgPool::connect(&vars["DATABASE_URL"])
+        .await
+        .map_err(|err| format!("Failed to connect to the DB: {err}"))?;
+    let from_config = ImageStoreConfig::from_vars(from, &vars)?;
+    let to_config = ImageStoreConfig::from_vars(to, &vars)?;
+    let mut moved = 0;
+    for kind in [Kind::Image, Kind::Attachment, Kind::Variant] {
+        let from = build(&from_config, pool.clone(), kind)
+            .await
+            .map_err(|err| format!("Failed to set up {from}: {err:?}"))?;
+        let to = build(&to_config, pool.clone(), kind)
+            .await
+            .map_err(|err| format!("Failed to set up {to}: {err:?}"))?;
+        moved += migrate_kind(&pool, from.as_ref(), to.as_ref(), kind).await?;
+    }
+
+    Ok(moved)
+}
+
+/// Moves every file of one kind stored in one backend into another
+///
+/// ### Arguments
+///
+/// * `pool` - A pool of connections to the database recording where files are stored
+/// * `from` - The store the files are currently in
+/// * `to` - The store to move them to
+/// * `kind` - The kind of file being moved
+///
+/// ### Returns
+///
+/// The number of files moved, or a description of what went wrong
+async fn migrate_kind(
+    pool: &PgPool,
+    from: &dyn ImageStore,
+    to: &dyn ImageStore,
+    kind: Kind,
+) -> Result<usize, String> {
+    let ids = match kind {
+        Kind::Image => {
+            sqlx::query_scalar!(
+                "SELECT id FROM images WHERE storage = $1 AND NOT pending ORDER BY id",
+                from.name()
+            )
+            .fetch_all(pool)
+            .await
+        }
+        Kind::Attachment => {
+            sqlx::query_scalar!(
+                "SELECT id FROM files WHERE storage = $1 AND NOT pending ORDER BY id",
+                from.name()
+            )
+            .fetch_all(pool)
+            .await
+        }
+        Kind::Variant => {
+            sqlx::query_scalar!(
+                "SELECT id FROM image_variants WHERE storage = $1 ORDER BY id",
+                from.name()
+            )
+            .fetch_all(pool)
+            .await
+        }
+    }
+    .map_err(|err| format!("Failed to list files: {err}"))?;
+
+    let mut moved = 0;
+    for id in ids {
+        let bytes = match from.get(id).await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return Err(format!("File {id} is missing from {}", from.name())),
+            Err(err) => return Err(format!("Failed to read file {id}: {err:?}")),
+        };
+
+        // Copy it, then mark it as moved before removing the original
+        to.put(id, &bytes)
+            .await
+            .map_err(|err| format!("Failed to write file {id}: {err:?}"))?;
+        match kind {
+            Kind::Image => {
+                sqlx::query!(
+                    "UPDATE images SET storage = $1 WHERE id = $2",
+                    to.name(),
+                    id
+                )
+                .execute(pool)
+                .await
+            }
+            Kind::Attachment => {
+                sqlx::query!("UPDATE files SET storage = $1 WHERE id = $2", to.name(), id)
+                    .execute(pool)
+                    .await
+            }
+            Kind::Variant => {
+                sqlx::query!(
+                    "UPDATE image_variants SET storage = $1 WHERE id = $2",
+                    to.name(),
+                    id
+                )
+                .execute(pool)
+                .await
+            }
+        }
+        .map_err(|err| format!("Failed to mark file {id} as moved: {err}"))?;
+        from.delete(id)
+            .await
+            .map_err(|err| format!("Failed to remove file {id} from {}: {err:?}", from.name()))?;
+
+        moved += 1;
+    }
+
+    Ok(moved)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    /// Stores, reads, replaces and deletes a file through the store, checking each step
+    ///
+    /// ### Arguments
+    ///
+    /// * `store` - The store to test
+    /// * `id` - The id to store the file under. Stores that keep files in the database
+    ///   need a record with this id to exist
+    pub(crate) async fn round_trip(store: &dyn ImageStore, id: i32) {
+        // Large enough to be streamed out in several chunks
+        let bytes: Vec<u8> = (0..600_000u32).map(|i| (i % 251) as u8).collect();
+        store.put(id, &bytes).await.unwrap();
+        assert_eq!(store.get(id).await.unwrap().as_ref(), Some(&bytes));
+
+        let mut stream = store.open(id).await.unwrap().unwrap();
+        assert_eq!(stream.len, bytes.len() as u64);
+        let mut read = vec![];
+        stream.reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, bytes);
+
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"replaced").unwrap();
+        store.put_file(id, file.path()).await.unwrap();
+        assert_eq!(
+            store.get(id).await.unwrap().as_deref(),
+            Some(&b"replaced"[..])
+        );
+
+        store.delete(id).await.unwrap();
+        assert_eq!(store.get(id).await.unwrap(), None);
+        assert!(store.open(id).await.unwrap().is_none());
+        // Deleting it again is fine too
+        store.delete(id).await.unwrap();
+    }
+
+    /// Wraps a store up as the only one configured, for code that looks stores up by name
+    pub(crate) fn only(store: impl ImageStore + 'static) -> Stores {
+        Stores {
+            current: Box::new(store),
+            others: vec![],
+        }
+    }
+
+    /// Connects to the database at TEST_DATABASE_URL. This should be a scratch database
+    /// with the schema loaded, and no images of its own, as tests add and move records
+    pub(crate) async fn test_pool() -> PgPool {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL isn't set");
+        PgPool::connect(&url).await.unwrap()
+    }
+
+    /// Adds a user with an image, recorded as stored in the named backend
+    ///
+    /// ### Returns
+    ///
+    /// The ids of the user and the image
+    pub(crate) async fn add_image(pool: &PgPool, storage: &str) -> (i32, i32) {
+        let user_id =
+            sqlx::query_scalar!("INSERT INTO users (email, password) VALUES ('', '') RETURNING id")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        let id = sqlx::query_scalar!(
+            "INSERT INTO images (user_id, mime_type, storage) VALUES ($1, 'image/png', $2)
+            RETURNING id",
+            user_id,
+            storage
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+        (user_id, id)
+    }
+
+    /// Removes a user added by add_image, along with their images
+    pub(crate) async fn remove_user(pool: &PgPool, user_id: i32) {
+        sqlx::query!("DELETE FROM images WHERE user_id = $1", user_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a scratch database at TEST_DATABASE_URL"]
+    async fn migrating_moves_files_and_records_where_they_went() {
+        let pool = test_pool().await;
+        let dir = tempfile::tempdir().unwrap();
+        let from = filesystem::FilesystemStore::new(dir.path().to_path_buf())
+            .await
+            .unwrap();
+        let to = postgres::PostgresStore::new(pool.clone(), Kind::Image);
+        let (user_id, id) = add_image(&pool, from.name()).await;
+        from.put(id, b"an image").await.unwrap();
+
+        let moved = migrate_kind(&pool, &from, &to, Kind::Image).await.unwrap();
+
+        let storage = sqlx::query_scalar!("SELECT storage FROM images WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let copied = to.get(id).await.unwrap();
+        let left_behind = from.get(id).await.unwrap();
+        remove_user(&pool, user_id).await;
+        assert_eq!(moved, 1);
+        assert_eq!(storage, "postgres");
+        assert_eq!(copied.as_deref(), Some(&b"an image"[..]));
+        assert_eq!(left_behind, None);
+    }
+
+    #[rocket::async_test]
+    async fn piped_files_are_read_whole() {
+        let copy = |mut writer: DuplexStream| async move { writer.write_all(b"hello").await };
+        let mut stream = FileStream::piped(5, copy);
+        let mut read = vec![];
+        stream.reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"hello");
+    }
+
+    #[rocket::async_test]
+    async fn piped_files_that_fail_to_copy_fail_to_read() {
+        let mut stream = FileStream::piped(10, |mut writer| async move {
+            writer.write_all(b"hello").await?;
+            Err(io::Error::other("The connection dropped"))
+        });
+        let mut read = vec![];
+        let err = stream.reader.read_to_end(&mut read).await.unwrap_err();
+        assert_eq!(err.to_string(), "The connection dropped");
+        // Everything copied before it failed is still read first
+        assert_eq!(read, b"hello");
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use rocket::tokio::fs;
+
+use crate::image_store::{FileStream, ImageStore, StoreError};
+
+/// Counts the temporary files we've written, so each gets its own name
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Stores image files (or attachments) as files in a directory, named by their id
+pub struct FilesystemStore {
+    root: PathBuf,
+}
+
+impl FilesystemStore {
+    /// Creates a store that keeps images in the given directory, creating it if it doesn't exist
+    pub async fn new(root: PathBuf) -> Result<FilesystemStore, StoreError> {
+        fs::create_dir_all(&root).await.map_err(StoreError::Io)?;
+
+        Ok(FilesystemStore { root })
+    }
+
+    /// The path the image with the given id is stored at
+    fn path(&self, id: i32) -> PathBuf {
+        self.root.join(id.to_string())
+    }
+
+    /// A path to write the image with the given id to before it's moved into place.
+    /// Each write gets its own, as the same image can be stored by two uploads at once
+    fn temp_path(&self, id: i32) -> PathBuf {
+        let count = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+        self.root
+            .join(format!("{id}.{}-{count}.tmp", process::id()))
+    }
+}
+
+#[async_trait]
+impl ImageStore for FilesystemStore {
+    fn name(&self) -> &'static str {
+        "filesystem"
+    }
+
+    async fn put(&self, id: i32, bytes: &[u8]) -> Result<(), StoreError> {
+        // Write to a temporary file first, so readers never see a half-written image
+        let temp_path = self.temp_path(id);
+        fs::write(&temp_path, bytes).await.map_err(StoreError::Io)?;
+        fs::rename(&temp_path, self.path(id))
+            .await
+            .map_err(StoreError::Io)
+    }
+
+    async fn put_file(&self, id: i32, path: &Path) -> Result<(), StoreError> {
+        let temp_path = self.temp_path(id);
+        fs::copy(path, &temp_path).await.map_err(StoreError::Io)?;
+        fs::rename(&temp_path, self.path(id))
+            .await
+            .map_err(StoreError::Io)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<Vec<u8>>, StoreError> {
+        match fs::read(self.path(id)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(StoreError::Io(err)),
+        }
+    }
+
+    async fn open(&self, id: i32) -> Result<Option<FileStream>, StoreError> {
+        let file = match fs::File::open(self.path(id)).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(StoreError::Io(err)),
+        };
+        let len = file.metadata().await.map_err(StoreError::Io)?.len();
+
+        Ok(Some(FileStream {
+            reader: Box::pin(file),
+            len,
+        }))
+    }
+
+    async fn delete(&self, id: i32) -> Result<(), StoreError> {
+        match fs::remove_file(self.path(id)).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(StoreError::Io(err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_store::tests::round_trip;
+
+    #[rocket::async_test]
+    async fn files_are_stored_read_and_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FilesystemStore::new(dir.path().join("images"))
+            .await
+            .unwrap();
+        round_trip(&store, 1).await;
+
+        // Nothing is left behind, not even the temporary files
+        let mut left = fs::read_dir(dir.path().join("images")).await.unwrap();
+        assert!(left.next_entry().await.unwrap().is_none());
+    }
+
+    #[rocket::async_test]
+    async fn files_are_kept_apart_by_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FilesystemStore::new(dir.path().to_path_buf())
+            .await
+            .unwrap();
+        store.put(1, b"one").await.unwrap();
+        store.put(2, b"two").await.unwrap();
+        store.delete(1).await.unwrap();
+        assert_eq!(store.get(1).await.unwrap(), None);
+        assert_eq!(store.get(2).await.unwrap().as_deref(), Some(&b"two"[..]));
+    }
+}
@@ -0,0 +1,257 @@
+use std::path::Path;
+
+use rocket::tokio::{
+    fs,
+    io::{self, AsyncWriteExt, DuplexStream},
+};
+use sqlx::PgPool;
+
+use crate::image_store::{FileStream, ImageStore, Kind, StoreError};
+
+/// How much of a file is read out of the database at a time when it's streamed
+const CHUNK_BYTES: i32 = 256 * 1024;
+
+/// Stores image files in the `image` column of the images table (or of the
+/// image_variants table, for variants), or attachments in the `content` column of the
+/// files table. Columns can only be written whole, so files pass through memory on
+/// their way in, but files opened to be streamed are read out a chunk at a time
+pub struct PostgresStore {
+    pool: PgPool,
+    kind: Kind,
+}
+
+impl PostgresStore {
+    /// Creates a store that keeps images (or attachments) in the given database
+    pub fn new(pool: PgPool, kind: Kind) -> PostgresStore {
+        PostgresStore { pool, kind }
+    }
+
+    /// Copies a file into a pipe a chunk at a time
+    ///
+    /// ### Arguments
+    ///
+    /// * `pool` - The database the file is stored in
+    /// * `kind` - The kind of file it is
+    /// * `id` - The id of the file
+    /// * `len` - The length of the file in bytes
+    /// * `writer` - The pipe to copy it into
+    ///
+    /// ### Returns
+    ///
+    /// An error if we couldn't read all of the file, or write it into the pipe
+    async fn copy(
+        pool: PgPool,
+        kind: Kind,
+        id: i32,
+        len: i32,
+        mut writer: DuplexStream,
+    ) -> io::Result<()> {
+        let mut offset = 0;
+        while offset < len {
+            let chunk = match PostgresStore::read_chunk(&pool, kind, id, offset).await {
+                Ok(Some(chunk)) if !chunk.is_empty() => chunk,
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "The file was removed while it was being read",
+                    ))
+                }
+                Err(err) => {
+                    error!("Failed to read file {id}: {err}");
+                    return Err(io::Error::other(err));
+                }
+            };
+            writer.write_all(&chunk).await?;
+            offset += chunk.len() as i32;
+        }
+        Ok(())
+    }
+
+    /// Reads part of a file
+    ///
+    /// ### Arguments
+    ///
+    /// * `pool` - The database the file is stored in
+    /// * `kind` - The kind of file it is
+    /// * `id` - The id of the file
+    /// * `offset` - How far into the file to start reading from
+    ///
+    /// ### Returns
+    ///
+    /// Up to CHUNK_BYTES of the file, or None if it's no longer stored
+    async fn read_chunk(
+        pool: &PgPool,
+        kind: Kind,
+        id: i32,
+        offset: i32,
+    ) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        // Postgres counts bytes from 1
+        let chunk = match kind {
+            Kind::Image => {
+                sqlx::query_scalar!(
+                    "SELECT substring(image FROM $2 FOR $3) FROM images WHERE id = $1",
+                    id,
+                    offset + 1,
+                    CHUNK_BYTES
+                )
+                .fetch_optional(pool)
+                .await
+            }
+            Kind::Attachment => {
+                sqlx::query_scalar!(
+                    "SELECT substring(content FROM $2 FOR $3) FROM files WHERE id = $1",
+                    id,
+                    offset + 1,
+                    CHUNK_BYTES
+                )
+                .fetch_optional(pool)
+                .await
+            }
+            Kind::Variant => {
+                sqlx::query_scalar!(
+                    "SELECT substring(image FROM $2 FOR $3) FROM image_variants WHERE id = $1",
+                    id,
+                    offset + 1,
+                    CHUNK_BYTES
+                )
+                .fetch_optional(pool)
+                .await
+            }
+        }?;
+
+        Ok(chunk.flatten())
+    }
+}
+
+#[async_trait]
+impl ImageStore for PostgresStore {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn put(&self, id: i32, bytes: &[u8]) -> Result<(), StoreError> {
+        match self.kind {
+            Kind::Image => {
+                sqlx::query!("UPDATE images SET image = $1 WHERE id = $2", bytes, id)
+                    .execute(&self.pool)
+                    .await
+            }
+            Kind::Attachment => {
+                sqlx::query!("UPDATE files SET content = $1 WHERE id = $2", bytes, id)
+                    .execute(&self.pool)
+                    .await
+            }
+            Kind::Variant => {
+                sqlx::query!(
+                    "UPDATE image_variants SET image = $1 WHERE id = $2",
+                    bytes,
+                    id
+                )
+                .execute(&self.pool)
+                .await
+            }
+        }
+        .map_err(StoreError::Database)?;
+
+        Ok(())
+    }
+
+    async fn put_file(&self, id: i32, path: &Path) -> Result<(), StoreError> {
+        let bytes = fs::read(path).await.map_err(StoreError::Io)?;
+        self.put(id, &bytes).await
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<Vec<u8>>, StoreError> {
+        let bytes = match self.kind {
+            Kind::Image => {
+                sqlx::query_scalar!("SELECT image FROM images WHERE id = $1", id)
+                    .fetch_optional(&self.pool)
+                    .await
+            }
+            Kind::Attachment => {
+                sqlx::query_scalar!("SELECT content FROM files WHERE id = $1", id)
+                    .fetch_optional(&self.pool)
+                    .await
+            }
+            Kind::Variant => {
+                sqlx::query_scalar!("SELECT image FROM image_variants WHERE id = $1", id)
+                    .fetch_optional(&self.pool)
+                    .await
+            }
+        }
+        .map_err(StoreError::Database)?;
+
+        Ok(bytes.flatten())
+    }
+
+    async fn open(&self, id: i32) -> Result<Option<FileStream>, StoreError> {
+        let len = match self.kind {
+            Kind::Image => {
+                sqlx::query_scalar!("SELECT octet_length(image) FROM images WHERE id = $1", id)
+                    .fetch_optional(&self.pool)
+                    .await
+            }
+            Kind::Attachment => {
+                sqlx::query_scalar!("SELECT octet_length(content) FROM files WHERE id = $1", id)
+                    .fetch_optional(&self.pool)
+                    .await
+            }
+            Kind::Variant => {
+                sqlx::query_scalar!(
+                    "SELECT octet_length(image) FROM image_variants WHERE id = $1",
+                    id
+                )
+                .fetch_optional(&self.pool)
+                .await
+            }
+        }
+        .map_err(StoreError::Database)?;
+        let Some(len) = len.flatten() else {
+            return Ok(None);
+        };
+
+        let pool = self.pool.clone();
+        let kind = self.kind;
+        Ok(Some(FileStream::piped(len as u64, move |writer| {
+            PostgresStore::copy(pool, kind, id, len, writer)
+        })))
+    }
+
+    async fn delete(&self, id: i32) -> Result<(), StoreError> {
+        match self.kind {
+            Kind::Image => {
+                sqlx::query!("UPDATE images SET image = NULL WHERE id = $1", id)
+                    .execute(&self.pool)
+                    .await
+            }
+            Kind::Attachment => {
+                sqlx::query!("UPDATE files SET content = NULL WHERE id = $1", id)
+                    .execute(&self.pool)
+                    .await
+            }
+            Kind::Variant => {
+                sqlx::query!("UPDATE image_variants SET image = NULL WHERE id = $1", id)
+                    .execute(&self.pool)
+                    .await
+            }
+        }
+        .map_err(StoreError::Database)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_store::tests::{add_image, remove_user, round_trip, test_pool};
+
+    #[rocket::async_test]
+    #[ignore = "needs a scratch database at TEST_DATABASE_URL"]
+    async fn files_are_stored_read_and_deleted() {
+        let pool = test_pool().await;
+        let (user_id, id) = add_image(&pool, "postgres").await;
+        round_trip(&PostgresStore::new(pool.clone(), Kind::Image), id).await;
+        remove_user(&pool, user_id).await;
+    }
+}
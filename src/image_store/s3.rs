@@ -0,0 +1,186 @@
+use std::path::Path;
+
+use rocket::tokio::{fs, io};
+use s3::{creds::Credentials, Bucket, Region};
+
+use crate::image_store::{FileStream, ImageStore, Kind, StoreError};
+
+/// Stores image files (or attachments) in a bucket of an S3-compatible object store,
+/// such as MinIO
+pub struct S3Store {
+    bucket: Bucket,
+    kind: Kind,
+}
+
+impl S3Store {
+    /// Creates a store that keeps images in the given bucket
+    ///
+    /// ### Arguments
+    ///
+    /// * `bucket` - The name of the bucket, which must already exist
+    /// * `region` - The region the bucket is in
+    /// * `endpoint` - The url of the object store
+    /// * `access_key` - The access key to authenticate with
+    /// * `secret_key` - The secret key to authenticate with
+    /// * `kind` - The kind of file the store is for
+    pub fn new(
+        bucket: &str,
+        region: &str,
+        endpoint: &str,
+        access_key: &str,
+        secret_key: &str,
+        kind: Kind,
+    ) -> Result<S3Store, StoreError> {
+        let region = Region::Custom {
+            region: region.to_string(),
+            endpoint: endpoint.to_string(),
+        };
+        let credentials = Credentials::new(Some(access_key), Some(secret_key), None, None, None)
+            .map_err(|err| StoreError::S3(err.to_string()))?;
+
+        // Path style (endpoint/bucket/key) works with MinIO and other non-AWS stores
+        let bucket = Bucket::new(bucket, region, credentials)
+            .map_err(|err| StoreError::S3(err.to_string()))?
+            .with_path_style();
+
+        Ok(S3Store { bucket, kind })
+    }
+
+    /// The key the file with the given id is stored under
+    fn key(&self, id: i32) -> String {
+        match self.kind {
+            Kind::Image => format!("images/{id}"),
+            Kind::Attachment => format!("files/{id}"),
+            Kind::Variant => format!("variants/{id}"),
+        }
+    }
+}
+
+#[async_trait]
+impl ImageStore for S3Store {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn put(&self, id: i32, bytes: &[u8]) -> Result<(), StoreError> {
+        let response = self
+            .bucket
+            .put_object(self.key(id), bytes)
+            .await
+            .map_err(|err| StoreError::S3(err.to_string()))?;
+
+        match response.status_code() {
+            200..=299 => Ok(()),
+            status => Err(StoreError::S3(format!(
+                "Upload failed with status {status}"
+            ))),
+        }
+    }
+
+    async fn put_file(&self, id: i32, path: &Path) -> Result<(), StoreError> {
+        // Large files are sent as a multipart upload, a chunk at a time
+        let mut file = fs::File::open(path).await.map_err(StoreError::Io)?;
+        let status = self
+            .bucket
+            .put_object_stream(&mut file, self.key(id))
+            .await
+            .map_err(|err| StoreError::S3(err.to_string()))?;
+
+        match status {
+            200..=299 => Ok(()),
+            status => Err(StoreError::S3(format!(
+                "Upload failed with status {status}"
+            ))),
+        }
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<Vec<u8>>, StoreError> {
+        let response = self
+            .bucket
+            .get_object(self.key(id))
+            .await
+            .map_err(|err| StoreError::S3(err.to_string()))?;
+
+        match response.status_code() {
+            200..=299 => Ok(Some(response.bytes().to_vec())),
+            404 => Ok(None),
+            status => Err(StoreError::S3(format!(
+                "Download failed with status {status}"
+            ))),
+        }
+    }
+
+    async fn open(&self, id: i32) -> Result<Option<FileStream>, StoreError> {
+        // Check it's there, and how long it is, before we start sending it
+        let key = self.key(id);
+        let (head, status) = self
+            .bucket
+            .head_object(&key)
+            .await
+            .map_err(|err| StoreError::S3(err.to_string()))?;
+        let len = match status {
+            200..=299 => head
+                .content_length
+                .and_then(|len| u64::try_from(len).ok())
+                .ok_or_else(|| StoreError::S3(String::from("The object has no length")))?,
+            404 => return Ok(None),
+            status => {
+                return Err(StoreError::S3(format!(
+                    "Download failed with status {status}"
+                )))
+            }
+        };
+
+        // The bucket's own streams can't be sent between threads, so the object is
+        // downloaded by a task of its own, into a pipe the response reads from
+        let bucket = self.bucket.clone();
+        Ok(Some(FileStream::piped(len, |mut writer| async move {
+            let failure = match bucket.get_object_to_writer(&key, &mut writer).await {
+                Ok(200..=299) => return Ok(()),
+                Ok(status) => format!("status {status}"),
+                Err(err) => err.to_string(),
+            };
+            error!("Failed to download {key}: {failure}");
+            Err(io::Error::other(failure))
+        })))
+    }
+
+    async fn delete(&self, id: i32) -> Result<(), StoreError> {
+        let response = self
+            .bucket
+            .delete_object(self.key(id))
+            .await
+            .map_err(|err| StoreError::S3(err.to_string()))?;
+
+        match response.status_code() {
+            200..=299 | 404 => Ok(()),
+            status => Err(StoreError::S3(format!(
+                "Delete failed with status {status}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::image_store::tests::round_trip;
+
+    #[rocket::async_test]
+    #[ignore = "needs an S3-compatible store, such as MinIO, at S3_ENDPOINT"]
+    async fn files_are_stored_read_and_deleted() {
+        let var = |name| env::var(name).unwrap_or_else(|_| panic!("{name} isn't set"));
+        let store = S3Store::new(
+            &var("S3_BUCKET"),
+            &env::var("S3_REGION").unwrap_or_else(|_| String::from("us-east-1")),
+            &var("S3_ENDPOINT"),
+            &var("S3_ACCESS_KEY"),
+            &var("S3_SECRET_KEY"),
+            Kind::Image,
+        )
+        .unwrap();
+        round_trip(&store, i32::MAX).await;
+    }
+}
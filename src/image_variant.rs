@@ -0,0 +1,255 @@
+use std::io::Cursor;
+
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+
+use crate::image_processing;
+
+/// The largest width or height we'll resize an image to, so a request can't make us
+/// allocate an enormous image
+pub const MAX_DIMENSION: u32 = 4096;
+
+/// The widths and heights we actually resize to. Requested sizes are rounded up to one
+/// of these, so only a handful of variants of each image ever get cached
+const SIZES: [u32; 13] = [
+    32,
+    64,
+    128,
+    256,
+    384,
+    512,
+    768,
+    1024,
+    1280,
+    1536,
+    2048,
+    3072,
+    MAX_DIMENSION,
+];
+
+/// How an image is fitted into the requested width and height, when both are given
+#[derive(FromFormField, Clone, Copy, Default)]
+pub enum Fit {
+    /// Scale the image to fit inside the box, keeping its aspect ratio
+    #[default]
+    #[field(value = "contain")]
+    Contain,
+    /// Scale the image to cover the box, keeping its aspect ratio, and crop the overflow
+    #[field(value = "cover")]
+    Cover,
+    /// Stretch the image to exactly the box
+    #[field(value = "fill")]
+    Fill,
+}
+impl Fit {
+    /// The name of the fit in the query string
+    fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+/// The formats we can convert an image to
+#[derive(FromFormField, Clone, Copy)]
+pub enum Format {
+    #[field(value = "webp")]
+    Webp,
+    #[field(value = "jpeg")]
+    Jpeg,
+    #[field(value = "png")]
+    Png,
+}
+impl Format {
+    /// The name of the format in the query string
+    fn as_str(&self) -> &'static str {
+        match self {
+            Format::Webp => "webp",
+            Format::Jpeg => "jpeg",
+            Format::Png => "png",
+        }
+    }
+}
+
+/// A resized and/or converted version of an image, as requested in the query string
+#[derive(FromForm, Clone, Copy, Default)]
+pub struct Variant {
+    /// The width to resize to, in pixels
+    w: Option<u32>,
+    /// The height to resize to, in pixels
+    h: Option<u32>,
+    fit: Option<Fit>,
+    format: Option<Format>,
+}
+impl Variant {
+    /// Checks the requested size is something we're willing to produce
+    pub fn is_valid(&self) -> bool {
+        [self.w, self.h]
+            .iter()
+            .flatten()
+            .all(|size| (1..=MAX_DIMENSION).contains(size))
+    }
+
+    /// The variant converted to the given format, unless another format was asked for
+    pub fn or_format(self, format: Format) -> Variant {
+        Variant {
+            format: self.format.or(Some(format)),
+            ..self
+        }
+    }
+
+    /// Whether the original image has been asked for, unchanged
+    pub fn is_original(&self) -> bool {
+        self.w.is_none() && self.h.is_none() && self.format.is_none()
+    }
+
+    /// The variant we'll actually generate for an image of the given size, so that
+    /// requests which would produce the same image share a key. Sizes are rounded up to
+    /// one of SIZES, then down to the image's own size, as images are never scaled up.
+    /// The fit only matters when both sides are given
+    ///
+    /// ### Arguments
+    ///
+    /// * `width` - The width of the original image, if we know it
+    /// * `height` - The height of the original image, if we know it
+    ///
+    /// ### Returns
+    ///
+    /// The normalised variant
+    pub fn normalise(self, width: Option<u32>, height: Option<u32>) -> Variant {
+        let snap = |size: Option<u32>, limit: Option<u32>| {
+            size.map(|size| {
+                let snapped = SIZES
+                    .into_iter()
+                    .find(|snapped| *snapped >= size)
+                    .unwrap_or(MAX_DIMENSION);
+                limit.map_or(snapped, |limit| snapped.min(limit))
+            })
+        };
+        let (w, h) = (snap(self.w, width), snap(self.h, height));
+
+        Variant {
+            w,
+            h,
+            fit: self.fit.filter(|_| w.is_some() && h.is_some()),
+            format: self.format,
+        }
+    }
+
+    /// A name for the variant that's the same for every equivalent request, used to
+    /// cache the variants we've generated. Only normalised variants have one key per
+    /// distinct image
+    pub fn key(&self) -> String {
+        let size = |size: Option<u32>| size.map_or(String::from("auto"), |size| size.to_string());
+        format!(
+            "{}x{}-{}.{}",
+            size(self.w),
+            size(self.h),
+            self.fit.unwrap_or_default().as_str(),
+            self.format.map_or("original", |format| format.as_str())
+        )
+    }
+
+    /// Generates the variant of an image. This is slow, so shouldn't be run on an async worker
+    ///
+    /// ### Arguments
+    ///
+    /// * `bytes` - The bytes of the original image
+    ///
+    /// ### Returns
+    ///
+    /// The bytes and mime type of the variant, or an error if the original couldn't be
+    /// decoded or the variant couldn't be encoded
+    pub fn render(&self, bytes: &[u8]) -> Result<(Vec<u8>, &'static str), image::ImageError> {
+        // We can't write HEIF images, so they're converted to JPEGs unless another
+        // format was asked for
+        let (original, original_format) = match image_processing::heif_mime_type(bytes) {
+            Some(_) => (image_processing::load_heif(bytes)?, ImageFormat::Jpeg),
+            None => {
+                let format = image::guess_format(bytes)?;
+                (image::load_from_memory_with_format(bytes, format)?, format)
+            }
+        };
+        let resized = self.resize(original);
+
+        let format = match self.format {
+            Some(Format::Webp) => ImageFormat::WebP,
+            Some(Format::Jpeg) => ImageFormat::Jpeg,
+            Some(Format::Png) => ImageFormat::Png,
+            None => original_format,
+        };
+        // JPEGs can't have an alpha channel
+        let resized = match format {
+            ImageFormat::Jpeg => DynamicImage::ImageRgb8(resized.into_rgb8()),
+            _ => resized,
+        };
+
+        let mut out = vec![];
+        resized.write_to(&mut Cursor::new(&mut out), format)?;
+        Ok((out, format.to_mime_type()))
+    }
+
+    /// Resizes the image as requested. Images are never scaled up
+    fn resize(&self, image: DynamicImage) -> DynamicImage {
+        let (width, height) = (image.width(), image.height());
+        let (w, h) = match (self.w, self.h) {
+            (None, None) => return image,
+            // With only one side given, the other follows the aspect ratio
+            (Some(w), None) => (w, u32::MAX),
+            (None, Some(h)) => (u32::MAX, h),
+            (Some(w), Some(h)) => (w, h),
+        };
+        let (w, h) = (w.min(width), h.min(height));
+        if (w, h) == (width, height) {
+            return image;
+        }
+
+        match (
+            self.fit.unwrap_or_default(),
+            self.w.is_some() && self.h.is_some(),
+        ) {
+            (Fit::Cover, true) => image.resize_to_fill(w, h, FilterType::Lanczos3),
+            (Fit::Fill, true) => image.resize_exact(w, h, FilterType::Lanczos3),
+            _ => image.resize(w, h, FilterType::Lanczos3),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(w: Option<u32>, h: Option<u32>, fit: Option<Fit>) -> Variant {
+        Variant {
+            w,
+            h,
+            fit,
+            format: Some(Format::Webp),
+        }
+    }
+
+    #[test]
+    fn sizes_are_rounded_up() {
+        let normalised = variant(Some(300), Some(33), None).normalise(None, None);
+        assert_eq!(normalised.key(), "384x64-contain.webp");
+    }
+
+    #[test]
+    fn sizes_stop_at_the_images_size() {
+        let normalised = variant(Some(300), Some(4000), None).normalise(Some(1000), Some(200));
+        assert_eq!(normalised.key(), "384x200-contain.webp");
+        let larger = variant(Some(2000), None, None).normalise(Some(1000), Some(200));
+        assert_eq!(larger.key(), "1000xauto-contain.webp");
+    }
+
+    #[test]
+    fn fit_only_matters_with_both_sides() {
+        let cover = variant(Some(100), None, Some(Fit::Cover)).normalise(None, None);
+        let contain = variant(Some(100), None, None).normalise(None, None);
+        assert_eq!(cover.key(), contain.key());
+
+        let cover = variant(Some(100), Some(100), Some(Fit::Cover)).normalise(None, None);
+        assert_eq!(cover.key(), "128x128-cover.webp");
+    }
+}
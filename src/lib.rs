@@ -1,8 +1,27 @@
 #[macro_use]
 extern crate rocket;
 
+mod config;
 mod db;
+mod download;
+mod export;
+mod feed;
+mod feed_token;
+mod hash;
+mod image_gc;
+mod image_processing;
+mod image_store;
+mod image_variant;
+mod mailer;
+mod oauth;
+mod password_reset;
+mod quota;
+mod render;
 mod routes;
+mod scanner;
 mod session;
+mod urls;
 
+pub use image_gc::collect as collect_images;
+pub use image_store::migrate as migrate_images;
 pub use routes::launch;
@@ -0,0 +1,67 @@
+use std::{io, sync::Arc};
+
+use crate::config::MailerConfig;
+
+pub mod file;
+pub mod log;
+pub mod smtp;
+
+/// A plain text email to one recipient
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Stuff that can go wrong while sending an email
+#[derive(Debug)]
+pub enum MailError {
+    /// The recipient's address isn't one we can send to
+    InvalidAddress(String),
+    /// We couldn't write the email out
+    Io(io::Error),
+    /// The mail server wouldn't take the email, with what went wrong
+    Smtp(String),
+}
+impl From<io::Error> for MailError {
+    fn from(err: io::Error) -> MailError {
+        MailError::Io(err)
+    }
+}
+
+/// Something that can deliver emails to users
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    /// Sends the email
+    async fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+/// Creates the mailer described by the config
+///
+/// ### Arguments
+///
+/// * `config` - How emails should be sent, and the settings for sending them
+///
+/// ### Returns
+///
+/// The mailer, or a description of what's wrong with its settings. It's shared, so
+/// emails can be sent in the background
+pub fn build(config: &MailerConfig) -> Result<Arc<dyn Mailer>, String> {
+    Ok(match config {
+        MailerConfig::Log => Arc::new(log::LogMailer),
+        MailerConfig::File { path } => Arc::new(file::FileMailer::new(path.clone())),
+        MailerConfig::Smtp {
+            host,
+            port,
+            username,
+            password,
+            from,
+        } => Arc::new(smtp::SmtpMailer::new(
+            host,
+            *port,
+            username.as_deref(),
+            password.as_deref(),
+            from,
+        )?),
+    })
+}
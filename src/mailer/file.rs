@@ -0,0 +1,44 @@
+use std::path::PathBuf;
+
+use rocket::{
+    time::OffsetDateTime,
+    tokio::{fs::OpenOptions, io::AsyncWriteExt},
+};
+
+use crate::mailer::{Email, MailError, Mailer};
+
+/// "Sends" emails by appending them to a file, for development and tests, where
+/// they can be read back without a mail server
+pub struct FileMailer {
+    path: PathBuf,
+}
+
+impl FileMailer {
+    /// Creates a mailer that appends emails to the file at the path, creating it if needed
+    pub fn new(path: PathBuf) -> FileMailer {
+        FileMailer { path }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        // Written all at once, so emails sent at the same time don't get mixed up
+        let message = format!(
+            "Date: {}\nTo: {}\nSubject: {}\n\n{}\n\n",
+            OffsetDateTime::now_utc(),
+            email.to,
+            email.subject,
+            email.body
+        );
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(message.as_bytes()).await?;
+
+        Ok(())
+    }
+}
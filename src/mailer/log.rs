@@ -0,0 +1,17 @@
+use crate::mailer::{Email, MailError, Mailer};
+
+/// "Sends" emails by writing them to the log, for development. Password reset emails
+/// carry working links, so it mustn't be used anywhere others can read the logs
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        info!(
+            "Email to {} about {:?}:\n{}",
+            email.to, email.subject, email.body
+        );
+
+        Ok(())
+    }
+}
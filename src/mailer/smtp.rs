@@ -0,0 +1,80 @@
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+use crate::mailer::{Email, MailError, Mailer};
+
+/// Sends emails through a mail server over SMTP, upgrading the connection with STARTTLS
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    /// Who the emails are from
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// Creates a mailer that sends emails through the given mail server
+    ///
+    /// ### Arguments
+    ///
+    /// * `host` - The mail server's hostname
+    /// * `port` - The port the mail server accepts STARTTLS connections on
+    /// * `username` - Who to log in to the mail server as, if it needs logging in to
+    /// * `password` - The password to log in with
+    /// * `from` - Who the emails are from, e.g. "Journal <noreply@dev.com>"
+    ///
+    /// ### Returns
+    ///
+    /// The mailer, or a description of what's wrong with its settings
+    pub fn new(
+        host: &str,
+        port: u16,
+        username: Option<&str>,
+        password: Option<&str>,
+        from: &str,
+    ) -> Result<SmtpMailer, String> {
+        let from = from
+            .parse()
+            .map_err(|_| format!("{from} isn't an email address"))?;
+
+        let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .map_err(|err| format!("Can't send mail through {host}: {err}"))?
+            .port(port);
+        if let Some(username) = username {
+            transport = transport.credentials(Credentials::new(
+                username.to_string(),
+                password.unwrap_or_default().to_string(),
+            ));
+        }
+
+        Ok(SmtpMailer {
+            transport: transport.build(),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        let to: Mailbox = email
+            .to
+            .parse()
+            .map_err(|_| MailError::InvalidAddress(email.to.clone()))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body.clone())
+            .map_err(|err| MailError::Smtp(err.to_string()))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|err| MailError::Smtp(err.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+use oauth2::{Client, PkceCodeVerifierS256, State, Url};
+use reqwest::header;
+use serde::Deserialize;
+
+use crate::{config::OAuthProviderConfig, urls::PublicUrls};
+
+/// Stuff that can go wrong while logging in with an OAuth provider
+#[derive(Debug)]
+pub enum OAuthError {
+    /// One of the provider's urls is invalid
+    InvalidConfig,
+    /// The provider wouldn't trade the code for an access token
+    Exchange,
+    /// The provider wouldn't tell us who the user is
+    UserInfo,
+}
+
+/// A login that's been started, which the user is off completing at the provider.
+/// It's kept in a private cookie until they come back
+pub struct PendingLogin {
+    /// The name of the provider they're logging in with
+    pub provider: String,
+    /// The state we sent the provider, which it has to send back unchanged
+    pub state: String,
+    /// The PKCE code verifier, proving we're the ones who started the login
+    pub verifier: String,
+}
+
+impl PendingLogin {
+    /// Packs the login up to be stored in a cookie
+    pub fn to_cookie(&self) -> String {
+        format!("{} {} {}", self.provider, self.state, self.verifier)
+    }
+
+    /// Unpacks a login from a cookie
+    ///
+    /// ### Returns
+    ///
+    /// The login, or None if the cookie isn't a login
+    pub fn from_cookie(cookie: &str) -> Option<PendingLogin> {
+        let mut parts = cookie.split(' ');
+        let login = PendingLogin {
+            provider: parts.next()?.to_string(),
+            state: parts.next()?.to_string(),
+            verifier: parts.next()?.to_string(),
+        };
+
+        match parts.next() {
+            None => Some(login),
+            Some(_) => None,
+        }
+    }
+
+    /// Checks the state the provider sent back is the one we sent it, in constant time
+    pub fn state_matches(&self, state: &str) -> bool {
+        self.state.len() == state.len()
+            && openssl::memcmp::eq(self.state.as_bytes(), state.as_bytes())
+    }
+}
+
+/// The part of the provider's token response we use. The oauth2 crate's own token
+/// insists on an expiry, which not every provider sends
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+}
+
+/// Who the user is, according to the provider's userinfo endpoint
+#[derive(Deserialize)]
+pub struct UserInfo {
+    /// The provider's id for the user, which never changes
+    pub sub: String,
+    pub email: Option<String>,
+    /// Whether the provider has checked the user owns the email
+    pub email_verified: Option<bool>,
+}
+
+impl UserInfo {
+    /// The user's email, if the provider has checked they own it
+    pub fn verified_email(&self) -> Option<&str> {
+        match self.email_verified {
+            Some(true) => self.email.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Starts logging in with a provider
+///
+/// ### Arguments
+///
+/// * `name` - The name of the provider
+/// * `provider` - The provider's settings
+/// * `urls` - Builds the url the provider sends the user back to
+///
+/// ### Returns
+///
+/// The url to send the user to, and the login to remember until they come back, or
+/// an error if the provider's settings are invalid
+pub fn start(
+    name: &str,
+    provider: &OAuthProviderConfig,
+    urls: &PublicUrls,
+) -> Result<(Url, PendingLogin), OAuthError> {
+    let client = client(name, provider, urls)?;
+    let state = State::new_random();
+
+    // PKCE (RFC 7636) means a stolen code is useless without the verifier, which
+    // never leaves the user's cookie jar and our server
+    let verifier = PkceCodeVerifierS256::new_random();
+    let mut url = client.authorize_url(&state);
+    url.query_pairs_mut()
+        .extend_pairs(verifier.authorize_url_params());
+
+    Ok((
+        url,
+        PendingLogin {
+            provider: name.to_string(),
+            state: state.to_base64(),
+            verifier: verifier.into(),
+        },
+    ))
+}
+
+/// Finishes logging in with a provider, once it's sent the user back to us
+///
+/// ### Arguments
+///
+/// * `provider` - The provider's settings
+/// * `login` - The login that was started
+/// * `code` - The code the provider sent back
+/// * `urls` - Builds the url the provider sent the user back to
+///
+/// ### Returns
+///
+/// Who the user is, according to the provider, or why we couldn't find out
+pub async fn finish(
+    provider: &OAuthProviderConfig,
+    login: &PendingLogin,
+    code: &str,
+    urls: &PublicUrls,
+) -> Result<UserInfo, OAuthError> {
+    let client = client(&login.provider, provider, urls)?;
+    let http = reqwest::Client::new();
+    let token = client
+        .exchange_code(code)
+        .param("code_verifier", login.verifier.as_str())
+        .with_client(&http)
+        .execute::<AccessToken>()
+        .await
+        .map_err(|_| OAuthError::Exchange)?;
+
+    let response = http
+        .get(&provider.userinfo_url)
+        .header(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token.access_token),
+        )
+        .header(header::ACCEPT, "application/json")
+        .send()
+        .await
+        .map_err(|_| OAuthError::UserInfo)?;
+    if !response.status().is_success() {
+        return Err(OAuthError::UserInfo);
+    }
+    let body = response.bytes().await.map_err(|_| OAuthError::UserInfo)?;
+
+    serde_json::from_slice(&body).map_err(|_| OAuthError::UserInfo)
+}
+
+/// Sets up an OAuth client for a provider
+///
+/// ### Arguments
+///
+/// * `name` - The name of the provider
+/// * `provider` - The provider's settings
+/// * `urls` - Builds the url the provider sends the user back to
+///
+/// ### Returns
+///
+/// The client, or an error if any of the provider's urls are invalid
+fn client(
+    name: &str,
+    provider: &OAuthProviderConfig,
+    urls: &PublicUrls,
+) -> Result<Client, OAuthError> {
+    let parse = |url: &str| Url::parse(url).map_err(|_| OAuthError::InvalidConfig);
+    let mut client = Client::new(
+        &provider.client_id,
+        parse(&provider.auth_url)?,
+        parse(&provider.token_url)?,
+    );
+    client.set_client_secret(&provider.client_secret);
+    client.set_redirect_url(parse(&urls.oauth_callback(name))?);
+    for scope in &provider.scopes {
+        client.add_scope(scope);
+    }
+
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose, Engine as _};
+    use rocket::tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+
+    use super::*;
+
+    /// Runs a pretend provider on a local port, which answers each request with the
+    /// JSON for the first path it starts with, closing the connection after each one
+    ///
+    /// ### Returns
+    ///
+    /// The provider's base url, and the requests it was sent once it's answered `count`
+    async fn mock_provider(
+        responses: Vec<(&'static str, &'static str)>,
+        count: usize,
+    ) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = tokio::spawn(async move {
+            let mut requests = vec![];
+            for _ in 0..count {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut socket).await;
+                let path = request.split(' ').nth(1).unwrap_or_default();
+                let body = responses
+                    .iter()
+                    .find(|(prefix, _)| path.starts_with(prefix))
+                    .map_or("{}", |(_, body)| body);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        (base, requests)
+    }
+
+    /// Reads a whole HTTP request, its body included
+    async fn read_request(socket: &mut TcpStream) -> String {
+        let mut request = vec![];
+        let mut chunk = [0; 1024];
+        loop {
+            let read = socket.read(&mut chunk).await.unwrap();
+            request.extend_from_slice(&chunk[..read]);
+            let text = String::from_utf8_lossy(&request).into_owned();
+            let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                continue;
+            };
+            let len = head
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .map_or(0, |(_, len)| len.trim().parse().unwrap());
+            if read == 0 || body.len() >= len {
+                return text;
+            }
+        }
+    }
+
+    /// The settings for a provider at the given base url
+    fn provider(base: &str) -> OAuthProviderConfig {
+        OAuthProviderConfig {
+            client_id: String::from("journal"),
+            client_secret: String::from("secret"),
+            auth_url: format!("{base}/authorize"),
+            token_url: format!("{base}/token"),
+            userinfo_url: format!("{base}/userinfo"),
+            scopes: vec![String::from("openid"), String::from("email")],
+            trust_email: false,
+        }
+    }
+
+    /// A login that's been started with the pretend provider
+    fn pending_login() -> PendingLogin {
+        PendingLogin {
+            provider: String::from("mock"),
+            state: String::from("state"),
+            verifier: "v".repeat(43),
+        }
+    }
+
+    #[test]
+    fn pending_logins_survive_the_cookie() {
+        let login = PendingLogin::from_cookie(&pending_login().to_cookie()).unwrap();
+        assert_eq!(login.provider, "mock");
+        assert_eq!(login.state, "state");
+        assert_eq!(login.verifier, "v".repeat(43));
+    }
+
+    #[test]
+    fn other_cookies_are_not_pending_logins() {
+        assert!(PendingLogin::from_cookie("").is_none());
+        assert!(PendingLogin::from_cookie("mock state").is_none());
+        assert!(PendingLogin::from_cookie("mock state verifier extra").is_none());
+    }
+
+    #[test]
+    fn logins_start_with_a_pkce_challenge() {
+        let urls = PublicUrls::new(None, false).unwrap();
+        let (url, login) = start("mock", &provider("http://idp.test"), &urls).unwrap();
+        let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        let param = |name: &str| {
+            query
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        let challenge = general_purpose::URL_SAFE_NO_PAD
+            .encode(openssl::sha::sha256(login.verifier.as_bytes()));
+        assert_eq!(param("code_challenge"), Some(challenge.as_str()));
+        assert_eq!(param("code_challenge_method"), Some("S256"));
+        assert_eq!(param("state"), Some(login.state.as_str()));
+        assert_eq!(param("client_id"), Some("journal"));
+    }
+
+    #[rocket::async_test]
+    async fn logins_are_finished_with_the_provider() {
+        // No expiry, as some providers leave it out
+        let (base, requests) = mock_provider(
+            vec![
+                (
+                    "/token",
+                    r#"{"access_token":"access-123","token_type":"bearer"}"#,
+                ),
+                (
+                    "/userinfo",
+                    r#"{"sub":"user-1","email":"someone@example.com","email_verified":true}"#,
+                ),
+            ],
+            2,
+        )
+        .await;
+        let login = pending_login();
+        let urls = PublicUrls::new(None, false).unwrap();
+
+        let info = finish(&provider(&base), &login, "code-456", &urls)
+            .await
+            .unwrap();
+        assert_eq!(info.sub, "user-1");
+        assert_eq!(info.verified_email(), Some("someone@example.com"));
+
+        // The code is traded along with the verifier, and the token used to ask who it is
+        let requests = requests.await.unwrap();
+        assert!(requests[0].starts_with("POST /token "));
+        assert!(requests[0].contains("code=code-456"));
+        assert!(requests[0].contains(&format!("code_verifier={}", login.verifier)));
+        assert!(requests[1].starts_with("GET /userinfo "));
+        assert!(requests[1]
+            .to_ascii_lowercase()
+            .contains("authorization: bearer access-123"));
+    }
+
+    #[rocket::async_test]
+    async fn unverified_emails_are_not_trusted() {
+        let (base, _) = mock_provider(
+            vec![
+                (
+                    "/token",
+                    r#"{"access_token":"access-123","token_type":"bearer","expires_in":3600}"#,
+                ),
+                (
+                    "/userinfo",
+                    r#"{"sub":"user-1","email":"someone@example.com","email_verified":false}"#,
+                ),
+            ],
+            2,
+        )
+        .await;
+        let urls = PublicUrls::new(None, false).unwrap();
+
+        let info = finish(&provider(&base), &pending_login(), "code-456", &urls)
+            .await
+            .unwrap();
+        assert_eq!(info.verified_email(), None);
+    }
+}
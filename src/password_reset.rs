@@ -0,0 +1,150 @@
+use base64::{engine::general_purpose, Engine as _};
+
+use rocket::time::{Duration, OffsetDateTime};
+use sqlx::PgConnection;
+
+const PASSWORD_RESET_TOKEN_LEN: usize = 32;
+
+/// Lets a user who's forgotten their password set a new one, by proving they can read
+/// the emails sent to their address. Each user has at most one token at a time, which
+/// expires, and can only be used once
+pub struct PasswordReset;
+
+impl PasswordReset {
+    /// Generates a new reset token for the user, replacing any token they already had
+    ///
+    /// ### Arguments
+    ///
+    /// * `user_id` - The id of the user the token is for
+    /// * `lifetime` - How long the token can be used for
+    /// * `conn` - A connection to the database storing the reset tokens
+    ///
+    /// ### Returns
+    ///
+    /// Error if we failed to save the token, otherwise the token itself. We only store its
+    /// hash, so this is the only time it's available
+    pub async fn create(
+        user_id: i32,
+        lifetime: Duration,
+        conn: &mut PgConnection,
+    ) -> Result<String, sqlx::Error> {
+        let token = Self::generate_token();
+        sqlx::query!(
+            "INSERT INTO password_resets (user_id, token_hash, expires_at) VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE
+            SET token_hash = EXCLUDED.token_hash, expires_at = EXCLUDED.expires_at",
+            user_id,
+            Self::hash(&token),
+            Self::expiry(OffsetDateTime::now_utc(), lifetime)
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Uses up a reset token. It's deleted whether or not it's expired, so it can't be
+    /// tried again
+    ///
+    /// ### Arguments
+    ///
+    /// * `token` - The token from the reset email
+    /// * `conn` - A connection to the database storing the reset tokens
+    ///
+    /// ### Returns
+    ///
+    /// Error if we failed to contact the database, otherwise the id of the user the token
+    /// was for, or None if it doesn't exist or has expired
+    pub async fn redeem(token: &str, conn: &mut PgConnection) -> Result<Option<i32>, sqlx::Error> {
+        let record = sqlx::query!(
+            "DELETE FROM password_resets WHERE token_hash = $1
+            RETURNING user_id, expires_at",
+            Self::hash(token)
+        )
+        .fetch_optional(conn)
+        .await?;
+
+        let now = OffsetDateTime::now_utc();
+        Ok(record
+            .filter(|record| !Self::expired(record.expires_at, now))
+            .map(|record| record.user_id))
+    }
+
+    /// Works out when a token created now should expire
+    fn expiry(now: OffsetDateTime, lifetime: Duration) -> OffsetDateTime {
+        now + lifetime
+    }
+
+    /// Whether a token that expires at the given time has expired. Tokens can't be used
+    /// from the moment they expire
+    fn expired(expires_at: OffsetDateTime, now: OffsetDateTime) -> bool {
+        expires_at <= now
+    }
+
+    /// Generates a random, url-safe token
+    fn generate_token() -> String {
+        let mut buf = [0; PASSWORD_RESET_TOKEN_LEN];
+        openssl::rand::rand_bytes(&mut buf).unwrap();
+        general_purpose::URL_SAFE_NO_PAD.encode(buf)
+    }
+
+    /// Hashes a token for storage, so a leaked database can't be used to take over accounts
+    fn hash(token: &str) -> String {
+        crate::hash::sha256_hex(token.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn tokens_are_random_and_url_safe() {
+        let tokens: HashSet<_> = (0..100).map(|_| PasswordReset::generate_token()).collect();
+        assert_eq!(tokens.len(), 100);
+        for token in tokens {
+            // 32 bytes of base64 without padding
+            assert_eq!(token.len(), 43);
+            assert!(
+                token
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                "{token}"
+            );
+        }
+    }
+
+    #[test]
+    fn tokens_are_stored_hashed() {
+        let token = PasswordReset::generate_token();
+        let hash = PasswordReset::hash(&token);
+        assert_ne!(hash, token);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, PasswordReset::hash(&token));
+        assert_ne!(hash, PasswordReset::hash(&PasswordReset::generate_token()));
+        assert_eq!(
+            PasswordReset::hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn tokens_expire_once_their_lifetime_is_up() {
+        let now = OffsetDateTime::now_utc();
+        let expires_at = PasswordReset::expiry(now, Duration::minutes(30));
+        assert_eq!(expires_at, now + Duration::minutes(30));
+
+        assert!(!PasswordReset::expired(expires_at, now));
+        assert!(!PasswordReset::expired(
+            expires_at,
+            expires_at - Duration::seconds(1)
+        ));
+        assert!(PasswordReset::expired(expires_at, expires_at));
+        assert!(PasswordReset::expired(
+            expires_at,
+            expires_at + Duration::seconds(1)
+        ));
+    }
+}
@@ -0,0 +1,155 @@
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    serde::json::Json,
+    Request,
+};
+use serde::Serialize;
+use sqlx::PgConnection;
+
+use crate::db::usage;
+
+/// The first half of the advisory lock key held while a user's usage is checked, so
+/// it can't clash with locks taken for anything else
+const QUOTA_LOCK: i32 = 1;
+
+/// Sent back when storing something would take a user over their storage quota
+pub struct QuotaExceeded {
+    /// Status::PayloadTooLarge if the thing alone is larger than the quota, otherwise
+    /// Status::InsufficientStorage
+    status: Status,
+    used: i64,
+    quota: i64,
+}
+
+/// The body sent back with QuotaExceeded. `success` is there so the editor's image and
+/// attachment tools see it as a failed upload
+#[derive(Serialize)]
+struct QuotaExceededBody {
+    success: i32,
+    error: &'static str,
+    /// The bytes the user is already using
+    used: i64,
+    /// The bytes the user is allowed to use
+    quota: i64,
+}
+
+/// Allow us to send QuotaExceeded as a response
+impl<'r> Responder<'r, 'static> for QuotaExceeded {
+    /// Sends the quota and usage as json, with the status (rocket)
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let body = Json(QuotaExceededBody {
+            success: 0,
+            error: "Storage quota exceeded",
+            used: self.used,
+            quota: self.quota,
+        });
+
+        response::Response::build_from(body.respond_to(req)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+/// Stuff that can go wrong while checking a user's quota
+pub enum QuotaError {
+    /// Storing the thing would take the user over their quota
+    Exceeded(QuotaExceeded),
+    /// We couldn't work out how much the user is using
+    Database,
+}
+
+/// Checks whether the user has room to store some more bytes. This must be called in
+/// the transaction that stores them - the user's other checks wait until it's finished,
+/// so two uploads can't both fit into the same space
+///
+/// ### Arguments
+///
+/// * `conn` - The transaction storing the user's new things
+/// * `user_id` - The id of the user who's storing something
+/// * `quota` - The bytes each user is allowed to use, or None if there's no limit
+/// * `adding` - How many more bytes the user would be using. Can be negative, when
+///   something's getting smaller
+///
+/// ### Returns
+///
+/// Ok if there's room, otherwise why not
+pub async fn check(
+    conn: &mut PgConnection,
+    user_id: i32,
+    quota: Option<u64>,
+    adding: i64,
+) -> Result<(), QuotaError> {
+    // Shrinking things is always allowed, so users can get back under their quota
+    let quota = match quota {
+        Some(quota) if adding > 0 => quota as i64,
+        _ => return Ok(()),
+    };
+
+    sqlx::query!("SELECT pg_advisory_xact_lock($1, $2)", QUOTA_LOCK, user_id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|_| QuotaError::Database)?;
+    let used = usage::get(conn, user_id)
+        .await
+        .map_err(|_| QuotaError::Database)?
+        .total();
+
+    fits(used, adding, quota).map_err(QuotaError::Exceeded)
+}
+
+/// Works out whether some more bytes fit into a user's quota
+///
+/// ### Arguments
+///
+/// * `used` - The bytes the user is already using
+/// * `adding` - How many more bytes the user would be using
+/// * `quota` - The bytes the user is allowed to use
+///
+/// ### Returns
+///
+/// Ok if they fit, otherwise the response explaining why not
+fn fits(used: i64, adding: i64, quota: i64) -> Result<(), QuotaExceeded> {
+    if used + adding <= quota {
+        return Ok(());
+    }
+
+    Err(QuotaExceeded {
+        status: match adding > quota {
+            true => Status::PayloadTooLarge,
+            false => Status::InsufficientStorage,
+        },
+        used,
+        quota,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn things_fit_up_to_the_quota() {
+        assert!(fits(0, 100, 100).is_ok());
+        assert!(fits(60, 40, 100).is_ok());
+    }
+
+    #[test]
+    fn things_that_would_fit_in_an_empty_quota_need_more_room() {
+        let exceeded = fits(61, 40, 100).unwrap_err();
+        assert_eq!(exceeded.status, Status::InsufficientStorage);
+        assert_eq!((exceeded.used, exceeded.quota), (61, 100));
+
+        // Even when nothing else is stored, as long as they're no larger than the quota
+        let exceeded = fits(1, 100, 100).unwrap_err();
+        assert_eq!(exceeded.status, Status::InsufficientStorage);
+    }
+
+    #[test]
+    fn things_larger_than_the_quota_are_too_large() {
+        let exceeded = fits(0, 101, 100).unwrap_err();
+        assert_eq!(exceeded.status, Status::PayloadTooLarge);
+        let exceeded = fits(50, 101, 100).unwrap_err();
+        assert_eq!(exceeded.status, Status::PayloadTooLarge);
+    }
+}
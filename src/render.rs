@@ -0,0 +1,313 @@
+use serde_json::Value;
+
+/// A single block of an Editor.js document - the format note content is stored in
+pub enum Block {
+    Paragraph(String),
+    Header { level: u8, text: String },
+    List { ordered: bool, items: Vec<String> },
+    Checklist(Vec<(bool, String)>),
+    Quote { text: String, caption: String },
+    Code(String),
+    Image { url: String, caption: String },
+    Delimiter,
+}
+
+/// Parses the content of a note into its blocks. All text is returned as plain
+/// text, with any inline markup removed
+///
+/// ### Arguments
+///
+/// * `content` - The content of the note. If it isn't an Editor.js document we
+///   treat the whole thing as a single paragraph
+///
+/// ### Returns
+///
+/// The blocks of the note that we know how to display, in order
+pub fn parse(content: &str) -> Vec<Block> {
+    let document: Value = match serde_json::from_str(content) {
+        Ok(document) => document,
+        Err(_) => return vec![Block::Paragraph(content.to_string())],
+    };
+    let blocks = match document["blocks"].as_array() {
+        Some(blocks) => blocks,
+        None => return vec![Block::Paragraph(content.to_string())],
+    };
+
+    blocks.iter().filter_map(parse_block).collect()
+}
+
+/// Parses a single Editor.js block, returning None if it's a block type we don't support
+fn parse_block(block: &Value) -> Option<Block> {
+    let data = &block["data"];
+    let text = |key: &str| inline_to_text(data[key].as_str().unwrap_or(""));
+
+    Some(match block["type"].as_str()? {
+        "paragraph" => Block::Paragraph(text("text")),
+        "header" => Block::Header {
+            level: data["level"].as_u64().unwrap_or(2).clamp(1, 6) as u8,
+            text: text("text"),
+        },
+        "list" => {
+            let mut items = vec![];
+            list_items(&data["items"], &mut items);
+            Block::List {
+                ordered: data["style"].as_str() == Some("ordered"),
+                items,
+            }
+        }
+        "checklist" => Block::Checklist(
+            data["items"]
+                .as_array()?
+                .iter()
+                .map(|item| {
+                    (
+                        item["checked"].as_bool().unwrap_or(false),
+                        inline_to_text(item["text"].as_str().unwrap_or("")),
+                    )
+                })
+                .collect(),
+        ),
+        "quote" => Block::Quote {
+            text: text("text"),
+            caption: text("caption"),
+        },
+        "code" => Block::Code(data["code"].as_str().unwrap_or("").to_string()),
+        "image" => Block::Image {
+            url: data["file"]["url"].as_str()?.to_string(),
+            caption: text("caption"),
+        },
+        "delimiter" => Block::Delimiter,
+        _ => return None,
+    })
+}
+
+/// Flattens the (potentially nested) items of an Editor.js list into `items`
+fn list_items(value: &Value, items: &mut Vec<String>) {
+    for item in value.as_array().into_iter().flatten() {
+        match item {
+            Value::String(text) => items.push(inline_to_text(text)),
+            item => {
+                items.push(inline_to_text(item["content"].as_str().unwrap_or("")));
+                list_items(&item["items"], items);
+            }
+        }
+    }
+}
+
+/// Strips inline markup (bold, links, etc) out of a block's text, leaving only the text itself
+///
+/// ### Arguments
+///
+/// * `inline` - The inline html Editor.js stores in its blocks
+pub fn inline_to_text(inline: &str) -> String {
+    let mut text = String::with_capacity(inline.len());
+    let mut in_tag = false;
+    let mut tag = String::new();
+    for c in inline.chars() {
+        match (in_tag, c) {
+            (false, '<') => {
+                in_tag = true;
+                tag.clear();
+            }
+            (true, '>') => {
+                in_tag = false;
+                // Line breaks are the only markup that changes the text
+                if tag.trim_end_matches('/').trim().eq_ignore_ascii_case("br") {
+                    text.push('\n');
+                }
+            }
+            (true, c) => tag.push(c),
+            (false, c) => text.push(c),
+        }
+    }
+
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Escapes text so it can be safely placed in html (or xml)
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Renders blocks as (xhtml compatible) html
+///
+/// ### Arguments
+///
+/// * `blocks` - The blocks to render
+/// * `image_src` - Given the url of an image, returns the src it should be
+///   rendered with, or None if the image should be left out
+pub fn to_html(blocks: &[Block], image_src: impl Fn(&str) -> Option<String>) -> String {
+    let text = |text: &str| escape(text).replace('\n', "<br/>");
+
+    let mut html = String::new();
+    for block in blocks {
+        match block {
+            Block::Paragraph(paragraph) => html += &format!("<p>{}</p>\n", text(paragraph)),
+            Block::Header {
+                level,
+                text: header,
+            } => html += &format!("<h{level}>{}</h{level}>\n", text(header)),
+            Block::List { ordered, items } => {
+                let tag = if *ordered { "ol" } else { "ul" };
+                html += &format!("<{tag}>\n");
+                for item in items {
+                    html += &format!("<li>{}</li>\n", text(item));
+                }
+                html += &format!("</{tag}>\n");
+            }
+            Block::Checklist(items) => {
+                html += "<ul>\n";
+                for (checked, item) in items {
+                    let check = if *checked { "&#9745;" } else { "&#9744;" };
+                    html += &format!("<li>{check} {}</li>\n", text(item));
+                }
+                html += "</ul>\n";
+            }
+            Block::Quote {
+                text: quote,
+                caption,
+            } => {
+                html += &format!("<blockquote><p>{}</p>", text(quote));
+                if !caption.is_empty() {
+                    html += &format!("<footer>{}</footer>", text(caption));
+                }
+                html += "</blockquote>\n";
+            }
+            Block::Code(code) => html += &format!("<pre><code>{}</code></pre>\n", escape(code)),
+            Block::Image { url, caption } => {
+                if let Some(src) = image_src(url) {
+                    html += &format!(
+                        "<figure><img src=\"{}\" alt=\"{}\"/>",
+                        escape(&src),
+                        escape(caption)
+                    );
+                    if !caption.is_empty() {
+                        html += &format!("<figcaption>{}</figcaption>", text(caption));
+                    }
+                    html += "</figure>\n";
+                }
+            }
+            Block::Delimiter => html += "<hr/>\n",
+        }
+    }
+
+    html
+}
+
+/// Gets the id of one of our images from its url
+///
+/// ### Arguments
+///
+/// * `url` - The url of the image, e.g. https://dev.com/api/images/5
+///
+/// ### Returns
+///
+/// The id of the image, or None if the url doesn't point at one of our images
+pub fn image_id(url: &str) -> Option<i32> {
+    let (_, id) = url.split_once("/api/images/")?;
+    let id = id.split(['?', '#', '/']).next()?;
+    id.parse().ok()
+}
+
+/// Finds the ids of every one of our images referenced anywhere in a note's content
+///
+/// ### Arguments
+///
+/// * `content` - The raw content of the note
+///
+/// ### Returns
+///
+/// The id of each reference, once per reference (so an image used twice appears twice)
+pub fn image_ids(content: &str) -> Vec<i32> {
+    content
+        .split("/api/images/")
+        .skip(1)
+        .filter_map(|rest| {
+            let digits = rest.chars().take_while(char::is_ascii_digit).count();
+            rest[..digits].parse().ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_parsed_as_plain_text() {
+        let blocks = parse(
+            r#"{"blocks":[
+                {"type":"paragraph","data":{"text":"<b>Bold</b> &amp; line<br>break"}},
+                {"type":"header","data":{"text":"Title","level":9}},
+                {"type":"unknown","data":{}},
+                {"type":"delimiter","data":{}}
+            ]}"#,
+        );
+
+        assert_eq!(blocks.len(), 3);
+        assert!(matches!(&blocks[0], Block::Paragraph(text) if text == "Bold & line\nbreak"));
+        assert!(matches!(&blocks[1], Block::Header { level: 6, text } if text == "Title"));
+        assert!(matches!(&blocks[2], Block::Delimiter));
+    }
+
+    #[test]
+    fn nested_lists_are_flattened() {
+        let blocks = parse(
+            r#"{"blocks":[{"type":"list","data":{"style":"ordered","items":[
+                {"content":"One","items":[{"content":"One and a half","items":[]}]},
+                {"content":"Two","items":[]}
+            ]}}]}"#,
+        );
+
+        let Block::List { ordered, items } = &blocks[0] else {
+            panic!("expected a list");
+        };
+        assert!(ordered);
+        assert_eq!(items, &["One", "One and a half", "Two"]);
+    }
+
+    #[test]
+    fn images_need_a_url() {
+        let blocks = parse(
+            r#"{"blocks":[
+                {"type":"image","data":{"file":{"url":"/api/images/5"},"caption":"<i>Cat</i>"}},
+                {"type":"image","data":{"caption":"Missing"}}
+            ]}"#,
+        );
+
+        assert_eq!(blocks.len(), 1);
+        assert!(matches!(
+            &blocks[0],
+            Block::Image { url, caption } if url == "/api/images/5" && caption == "Cat"
+        ));
+    }
+
+    #[test]
+    fn other_content_is_one_paragraph() {
+        for content in ["Just some text", r#"{"text":"No blocks"}"#] {
+            let blocks = parse(content);
+            assert_eq!(blocks.len(), 1);
+            assert!(matches!(&blocks[0], Block::Paragraph(text) if text == content));
+        }
+    }
+
+    #[test]
+    fn image_ids_are_read_from_urls() {
+        assert_eq!(image_id("https://dev.com/api/images/5?w=100"), Some(5));
+        assert_eq!(image_id("/api/images/12"), Some(12));
+        assert_eq!(image_id("https://example.com/cat.png"), None);
+        assert_eq!(
+            image_ids(r#"{"url":"/api/images/3"},{"url":"/api/images/30x"},"/api/images/"#),
+            vec![3, 30]
+        );
+    }
+}
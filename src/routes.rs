@@ -1,30 +1,88 @@
 use rocket::{fairing::AdHoc, Build, Rocket};
 
+use crate::{
+    config::Config,
+    export::PdfFonts,
+    image_store::{self, AttachmentStores, ImageStores, Kind},
+    mailer,
+    scanner::UploadScanner,
+};
+
 pub mod account;
 pub mod auth;
+pub mod exports;
+pub mod feeds;
+pub mod files;
 pub mod images;
 pub mod notes;
+pub mod oauth;
+pub mod prompts;
 
 pub fn launch() -> Rocket<Build> {
     // A fairing to connect us to the database
     let connect_to_db = AdHoc::try_on_ignite("Connect to DB", |rocket| {
         Box::pin(async {
-            let vars = env_file_reader::read_file(".env").expect("Failed to find/parse env file");
-            let db_url: &str = &vars["DATABASE_URL"];
+            let config = Config::load();
 
             // Connect to the database
-            let pool = sqlx::Pool::<sqlx::Postgres>::connect(db_url)
+            let pool = sqlx::Pool::<sqlx::Postgres>::connect(&config.database_url)
                 .await
                 .expect("Failed to connect to the DB");
 
-            // Hand off our pool to Rocket
-            Ok(rocket.manage(pool))
+            // Bring databases made from an older schema up to date
+            sqlx::migrate!("./data/migrations")
+                .run(&pool)
+                .await
+                .expect("Failed to migrate the DB");
+
+            // Make sure every built-in writing prompt is available
+            crate::db::prompt::sync_builtin(&pool)
+                .await
+                .expect("Failed to add the built-in prompts");
+
+            // Set up wherever we're keeping image files
+            let stores = ImageStores {
+                images: image_store::build_all(&config, pool.clone(), Kind::Image)
+                    .await
+                    .expect("Failed to set up the image stores"),
+                variants: image_store::build_all(&config, pool.clone(), Kind::Variant)
+                    .await
+                    .expect("Failed to set up the variant stores"),
+            };
+            let attachments = image_store::build_all(&config, pool.clone(), Kind::Attachment)
+                .await
+                .expect("Failed to set up the attachment stores");
+
+            // Check uploads for malware before they reach the stores
+            let scanner = UploadScanner::new(&config.scanner, config.quarantine_path.clone());
+
+            // Books can still be exported without the fonts, but only Latin text shows up
+            let fonts = match PdfFonts::load(&config.pdf_font, &config.pdf_bold_font) {
+                Ok(fonts) => Some(fonts),
+                Err(err) => {
+                    warn!("Failed to load the pdf fonts, falling back to Helvetica: {err}");
+                    None
+                }
+            };
+
+            // Set up however we're sending emails
+            let mailer = mailer::build(&config.mailer).expect("Failed to set up the mailer");
+
+            // Hand off our pool, stores, scanner, fonts, mailer and config to Rocket
+            Ok(rocket
+                .manage(pool)
+                .manage(stores)
+                .manage(AttachmentStores(attachments))
+                .manage(scanner)
+                .manage(fonts)
+                .manage(mailer)
+                .manage(config))
         })
     });
 
     rocket::build()
         .attach(connect_to_db)
-        .mount("/api", routes![account::signup,])
+        .mount("/api", routes![account::signup, account::get_usage])
         .mount(
             "/api/notes",
             routes![
@@ -35,9 +93,50 @@ pub fn launch() -> Rocket<Build> {
                 notes::get_overview_many,
                 notes::update,
                 notes::delete,
-                notes::get_diary_many
+                notes::get_diary_many,
+                notes::get_diary_mood
+            ],
+        )
+        .mount(
+            "/api/prompts",
+            routes![
+                prompts::today,
+                prompts::get_all,
+                prompts::create,
+                prompts::delete
+            ],
+        )
+        .mount(
+            "/api/images",
+            routes![
+                images::upload,
+                images::fetch,
+                images::get,
+                images::get_many,
+                images::delete
+            ],
+        )
+        .mount("/api/files", routes![files::upload, files::get])
+        .mount("/api/exports", routes![exports::book])
+        .mount(
+            "/api/feeds",
+            routes![
+                feeds::create_token,
+                feeds::delete_token,
+                feeds::diary_calendar,
+                feeds::notes_atom
+            ],
+        )
+        .mount(
+            "/api/auth",
+            routes![
+                auth::login,
+                auth::check,
+                auth::logout,
+                auth::request_password_reset,
+                auth::reset_password,
+                oauth::start,
+                oauth::callback
             ],
         )
-        .mount("/api/images", routes![images::upload, images::get])
-        .mount("/api/auth", routes![auth::login, auth::check, auth::logout])
 }
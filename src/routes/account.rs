@@ -1,9 +1,19 @@
-use crate::{db::user::User, session::Session};
+use crate::{
+    config::Config,
+    db::{
+        self,
+        usage::{self, StorageUsage},
+        user::User,
+    },
+    session::Session,
+};
 use rocket::{
     form::Form,
     http::{CookieJar, Status},
+    serde::json::Json,
     State,
 };
+use serde::Serialize;
 use sqlx::PgPool;
 
 /// Information about an account required to login / sign up
@@ -15,6 +25,17 @@ pub struct SignupForm {
     password: String,
 }
 
+/// How much storage a user is using, and how much they're allowed
+#[derive(Serialize)]
+pub struct UsageResponse {
+    /// The bytes used by each kind of thing
+    used: StorageUsage,
+    /// The bytes used across everything
+    total: i64,
+    /// The bytes the user is allowed to use, or None if there's no limit
+    quota: Option<u64>,
+}
+
 /// Signs up a new user with the provided details
 ///
 /// ### Arguments
@@ -66,3 +87,32 @@ pub async fn signup(
 
     Ok(Status::Created)
 }
+
+/// Gets how much storage the user is using across their notes, images and attachments
+///
+/// ### Arguments
+///
+/// * `user` - the user whose usage we're getting
+/// * `pool` - a pool of connections to the db storing the user's things
+/// * `config` - the server's configuration, which sets the user's storage quota
+///
+/// ### Returns
+///
+/// Status::InternalServerError if it fails to access the db, otherwise the usage
+#[get("/account/usage")]
+pub async fn get_usage(
+    user: User,
+    pool: &State<PgPool>,
+    config: &State<Config>,
+) -> Result<Json<UsageResponse>, Status> {
+    let mut conn = db::acquire_conn(pool.inner()).await?;
+    let used = usage::get(&mut conn, user.id)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(Json(UsageResponse {
+        total: used.total(),
+        used,
+        quota: config.storage_quota,
+    }))
+}
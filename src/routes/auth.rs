@@ -1,5 +1,10 @@
+use std::sync::Arc;
+
 use crate::{
+    config::Config,
     db::{self, user::User},
+    mailer::{Email, Mailer},
+    password_reset::PasswordReset,
     session::Session,
 };
 use rocket::{
@@ -7,7 +12,7 @@ use rocket::{
     http::{CookieJar, Status},
     State,
 };
-use sqlx::PgPool;
+use sqlx::{Connection, PgPool};
 
 /// Information about an account required to login
 #[derive(FromForm)]
@@ -91,3 +96,134 @@ pub async fn check(user: Option<User>) -> Result<Status, Status> {
         None => Err(Status::Unauthorized),
     }
 }
+
+/// Who wants to reset their password
+#[derive(FromForm)]
+pub struct PasswordResetRequestForm {
+    // The email of the user who's forgotten their password
+    email: String,
+}
+
+/// Emails the user a link to reset their password with, if there's a user with the
+/// email. Everything happens in the background, so the response is the same, and
+/// takes as long, whether or not they have an account
+///
+/// Returns [`Status::Accepted`] whether or not an email is sent
+#[post("/password-reset", data = "<request>")]
+pub async fn request_password_reset(
+    request: Form<PasswordResetRequestForm>,
+    pool: &State<PgPool>,
+    config: &State<Config>,
+    mailer: &State<Arc<dyn Mailer>>,
+) -> Status {
+    let pool = pool.inner().clone();
+    let urls = config.urls.clone();
+    let lifetime = config.password_reset_lifetime;
+    let mailer = Arc::clone(mailer);
+    let to = request.into_inner().email;
+    rocket::tokio::spawn(async move {
+        let Ok(mut conn) = db::acquire_conn(&pool).await else {
+            error!("Failed to connect to the DB to reset a password");
+            return;
+        };
+        let user = match User::get_by_email(&mut conn, &to).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return,
+            Err(err) => {
+                error!("Failed to look up a user to reset their password: {err}");
+                return;
+            }
+        };
+        let token = match PasswordReset::create(user.id, lifetime, &mut conn).await {
+            Ok(token) => token,
+            Err(err) => {
+                error!(
+                    "Failed to create a password reset for user {}: {err}",
+                    user.id
+                );
+                return;
+            }
+        };
+        // Hand the connection back before talking to the mail server
+        drop(conn);
+
+        let email = Email {
+            to,
+            subject: String::from("Reset your password"),
+            body: format!(
+                "Someone (hopefully you) asked to reset your password. Choose a new one here:\n\n\
+                {}\n\n\
+                The link works once, for the next {} minutes. If you didn't ask, you can ignore this email",
+                urls.password_reset(&token),
+                lifetime.whole_minutes()
+            ),
+        };
+        if let Err(err) = mailer.send(&email).await {
+            error!(
+                "Failed to send a password reset email to user {}: {err:?}",
+                user.id
+            );
+        }
+    });
+
+    Status::Accepted
+}
+
+/// A new password, and the token from the reset email proving the user can choose it
+#[derive(FromForm)]
+pub struct PasswordResetForm {
+    // The token from the link in the reset email
+    token: String,
+    // The user's new plaintext password (not yet hashed)
+    password: String,
+}
+
+/// Sets a new password for the user the reset token is for. The token can't be used
+/// again, and the user is logged out everywhere, so whoever knew the old password
+/// loses access
+///
+/// Returns [`Status::Ok`] if the password was changed
+///
+/// # Errors
+///
+/// Returns [`Status::BadRequest`] if the new password is empty
+///
+/// Returns [`Status::Unauthorized`] if the token doesn't exist, has been used, or has expired
+///
+/// Returns [`Status::InternalServerError`] if we couldn't connect to the database, or
+/// failed to hash the new password
+#[post("/password-reset/confirm", data = "<reset>")]
+pub async fn reset_password(
+    reset: Form<PasswordResetForm>,
+    pool: &State<PgPool>,
+) -> Result<Status, Status> {
+    if reset.password.is_empty() {
+        return Err(Status::BadRequest);
+    }
+
+    // Everything happens together, so a failure part way through doesn't use up the
+    // token without changing the password
+    let mut conn = db::acquire_conn(pool).await?;
+    let mut tx = conn
+        .begin()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    let user_id = PasswordReset::redeem(&reset.token, &mut tx)
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::Unauthorized)?;
+    let changed = User::set_password(&mut tx, user_id, &reset.password)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    if !changed {
+        return Err(Status::InternalServerError);
+    }
+    Session::delete_all(user_id, &mut tx)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    tx.commit().await.map_err(|_| Status::InternalServerError)?;
+
+    Ok(Status::Ok)
+}
@@ -0,0 +1,182 @@
+use std::{collections::HashSet, io::Cursor};
+
+use rocket::{
+    http::{ContentType, Header, Status},
+    response::{self, Responder},
+    tokio::task,
+    Request, Response, State,
+};
+use sqlx::PgPool;
+
+use crate::{
+    db::{
+        self,
+        image::{self, StoredImage},
+        note::{self, parse_timestamp},
+        user::User,
+    },
+    export::{self, Book, BookFormat, PdfFonts},
+    image_processing,
+    image_store::{ImageStores, Stores},
+    image_variant::{Format, Variant},
+    render::{self, Block},
+    routes::images::{cache_variant, cached_variant},
+};
+
+/// A rendered diary book, sent back as a file download
+pub struct BookFile {
+    bytes: Vec<u8>,
+    format: BookFormat,
+}
+
+/// Allow us to send a book as a response
+impl<'r> Responder<'r, 'static> for BookFile {
+    /// Sends the book as a file download (rocket)
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let content_type =
+            ContentType::parse_flexible(self.format.mime_type()).unwrap_or(ContentType::Binary);
+        let disposition = format!(
+            "attachment; filename=\"journal.{}\"",
+            self.format.extension()
+        );
+
+        Response::build()
+            .sized_body(self.bytes.len(), Cursor::new(self.bytes))
+            .header(content_type)
+            .header(Header::new("Content-Disposition", disposition))
+            .ok()
+    }
+}
+
+/// Renders the user's diary entries between `from` and `to` as a printable book
+///
+/// ### Arguments
+///
+/// * `user` - the user whose diary we're exporting
+/// * `pool` - connections to the db that's storing the diary
+/// * `stores` - the image and variant stores holding the images embedded in the diary,
+///   and the JPEG renditions of HEIF images
+/// * `from` - an ISO-8601 timestamp, only entries created at or after it are included
+/// * `to` - an ISO-8601 timestamp, only entries created before it are included
+/// * `format` - the format to export the book as (pdf or epub)
+/// * `fonts` - the fonts pdfs are written in, if they could be loaded
+///
+/// ### Returns
+///
+/// * `Status::BadRequest` if `from` or `to` weren't valid timestamps
+/// * `Status::InternalServerError` if we failed to reach the db, or couldn't render the book
+/// * `Status::Ok` and the book file on success
+#[get("/book?<from>&<to>&<format>")]
+pub async fn book(
+    user: User,
+    pool: &State<PgPool>,
+    stores: &State<ImageStores>,
+    from: &str,
+    to: &str,
+    format: BookFormat,
+    fonts: &State<Option<PdfFonts>>,
+) -> Result<BookFile, Status> {
+    let (from, to) = match (parse_timestamp(Some(from)), parse_timestamp(Some(to))) {
+        (Ok(Some(from)), Ok(Some(to))) => (from, to),
+        _ => return Err(Status::BadRequest),
+    };
+
+    // Grab the entries, and every image they reference
+    let conn = db::acquire_conn(pool).await?;
+    let entries = note::get_diary_range(conn, user.id, from, to)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let image_ids: HashSet<i32> = entries
+        .iter()
+        .flat_map(|entry| render::parse(&entry.content))
+        .filter_map(|block| match block {
+            Block::Image { url, .. } => render::image_id(&url),
+            _ => None,
+        })
+        .collect();
+    let image_ids: Vec<i32> = image_ids.into_iter().collect();
+    let conn = db::acquire_conn(pool).await?;
+    let records = image::get_many(conn, user.id, &image_ids)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let mut images = vec![];
+    for record in records {
+        let store = match stores.images.get(&record.storage) {
+            Ok(store) => store,
+            Err(_) => continue,
+        };
+        let bytes = match store.get(record.id).await {
+            Ok(Some(bytes)) => bytes,
+            _ => continue,
+        };
+        // Books can't show HEIF images any more than browsers can
+        if image_processing::is_heif(&record.mime_type) {
+            if let Some(rendition) = jpeg_rendition(pool, &stores.variants, record.id, bytes).await
+            {
+                images.push(rendition);
+            }
+            continue;
+        }
+        images.push(StoredImage {
+            id: record.id,
+            bytes,
+            mime_type: record.mime_type,
+        });
+    }
+
+    let book = Book {
+        title: String::from("Journal"),
+        author: user.email,
+        from,
+        to,
+        entries,
+        images: images.into_iter().map(|image| (image.id, image)).collect(),
+    };
+
+    // Rendering is slow, keep it off the async workers
+    let fonts = fonts.inner().clone();
+    let bytes = task::spawn_blocking(move || export::render(&book, format, fonts.as_ref()))
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(BookFile { bytes, format })
+}
+
+/// Gets the JPEG rendition of a HEIF image, the same one browsers are sent
+///
+/// ### Arguments
+///
+/// * `pool` - connections to the db recording the image's variants
+/// * `stores` - the variant stores, where the rendition is cached
+/// * `id` - the id of the image
+/// * `bytes` - the HEIF image file
+///
+/// ### Returns
+///
+/// The rendition, or None if we couldn't make it
+async fn jpeg_rendition(
+    pool: &PgPool,
+    stores: &Stores,
+    id: i32,
+    bytes: Vec<u8>,
+) -> Option<StoredImage> {
+    let variant = Variant::default().or_format(Format::Jpeg);
+    let key = variant.key();
+    if let Some(cached) = cached_variant(pool, stores, id, &key).await {
+        return Some(cached);
+    }
+
+    let (bytes, mime_type) = task::spawn_blocking(move || variant.render(&bytes))
+        .await
+        .ok()?
+        .ok()?;
+    let rendition = StoredImage {
+        id,
+        bytes,
+        mime_type: String::from(mime_type),
+    };
+    cache_variant(pool, stores, &key, &rendition).await;
+
+    Some(rendition)
+}
@@ -0,0 +1,131 @@
+use rocket::{
+    http::{ContentType, Status},
+    response::status,
+    serde::json::Json,
+    State,
+};
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::{
+    config::Config,
+    db::{
+        self,
+        note::{self, FeedFilter},
+        user::User,
+    },
+    feed::{atom, ical},
+    feed_token::FeedToken,
+};
+
+/// A newly generated feed token
+#[derive(Serialize)]
+pub struct FeedTokenResponse {
+    token: String,
+}
+
+/// Generates a new secret token for reading the user's feeds, revoking their old one
+///
+/// ### Arguments
+///
+/// * `user` - the user the token is for
+/// * `pool` - connections to the db storing the feed tokens
+///
+/// ### Returns
+///
+/// * `Status::InternalServerError` if we failed to save the token
+/// * `Status::Created` and the token on success. Feeds are read by adding `?token=<token>` to their url
+#[post("/token")]
+pub async fn create_token(
+    user: User,
+    pool: &State<PgPool>,
+) -> status::Custom<Option<Json<FeedTokenResponse>>> {
+    let mut conn = match db::acquire_conn(pool).await {
+        Ok(conn) => conn,
+        Err(_) => return status::Custom(Status::InternalServerError, None),
+    };
+
+    match FeedToken::rotate(user.id, &mut conn).await {
+        Ok(token) => status::Custom(Status::Created, Some(Json(FeedTokenResponse { token }))),
+        Err(_) => status::Custom(Status::InternalServerError, None),
+    }
+}
+
+/// Revokes the user's feed token, so their feeds can no longer be read
+///
+/// ### Returns
+///
+/// * `Status::InternalServerError` if we failed to contact the database
+/// * `Status::NotFound` if the user didn't have a feed token
+/// * `Status::Ok` if the token was revoked
+#[delete("/token")]
+pub async fn delete_token(user: User, pool: &State<PgPool>) -> Status {
+    let mut conn = match db::acquire_conn(pool).await {
+        Ok(conn) => conn,
+        Err(_) => return Status::InternalServerError,
+    };
+
+    match FeedToken::revoke(user.id, &mut conn).await {
+        Ok(true) => Status::Ok,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+/// An iCalendar feed with an all-day event for each of the user's diary entries.
+/// Authenticated with the feed token in the url rather than the session cookie
+///
+/// ### Arguments
+///
+/// * `tz` - the user's IANA time zone (e.g. Europe/London), which decides the day each
+///   entry falls on. Defaults to UTC
+///
+/// ### Returns
+///
+/// * `Status::Unauthorized` if the feed token is missing or invalid
+/// * `Status::BadRequest` if `tz` isn't a time zone
+/// * `Status::InternalServerError` if we failed to contact the database
+/// * `Status::Ok` and the .ics file on success
+#[get("/diary.ics?<tz>")]
+pub async fn diary_calendar(
+    token: FeedToken,
+    pool: &State<PgPool>,
+    config: &State<Config>,
+    tz: Option<&str>,
+) -> Result<(ContentType, String), Status> {
+    let conn = db::acquire_conn(pool).await?;
+    let entries = note::get_diary_calendar(conn, token.user_id, tz.unwrap_or("UTC"))
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .ok_or(Status::BadRequest)?;
+
+    Ok((ContentType::Calendar, ical::render(&entries, &config.urls)))
+}
+
+/// An Atom feed of the user's most recently updated notes, optionally filtered to only
+/// favourites, diary entries or notes with a given tag.
+/// Authenticated with the feed token in the url rather than the session cookie
+///
+/// ### Returns
+///
+/// * `Status::Unauthorized` if the feed token is missing or invalid
+/// * `Status::InternalServerError` if we failed to contact the database
+/// * `Status::Ok` and the feed's xml on success
+#[get("/notes.atom?<filter..>")]
+pub async fn notes_atom(
+    token: FeedToken,
+    pool: &State<PgPool>,
+    config: &State<Config>,
+    filter: FeedFilter,
+) -> Result<(ContentType, String), Status> {
+    let conn = db::acquire_conn(pool).await?;
+    let entries = note::get_feed(conn, token.user_id, &filter)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    let feed_id = format!("urn:journal:user:{}:notes", token.user_id);
+    let atom = atom::render("Journal", &feed_id, &entries, &config.urls);
+    let content_type = ContentType::new("application", "atom+xml");
+
+    Ok((content_type, atom))
+}
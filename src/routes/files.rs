@@ -0,0 +1,468 @@
+use std::path::Path;
+
+use rocket::{
+    http::{ContentType, Header, Status},
+    response::{self, status, Responder},
+    serde::json::Json,
+    tokio::{fs::File, io::AsyncReadExt},
+    Data, Request, Response, State,
+};
+use rocket_multipart_form_data::{
+    MultipartFormData, MultipartFormDataError, MultipartFormDataField, MultipartFormDataOptions,
+};
+use serde::Serialize;
+use sqlx::{Connection, PgPool};
+
+use crate::{
+    config::Config,
+    db::{
+        self,
+        file::{self, NewFile},
+        user::User,
+    },
+    image_store::{AttachmentStores, FileStream, ImageStore},
+    quota::{self, QuotaError, QuotaExceeded},
+    scanner::{UploadScanner, Verdict},
+};
+
+/// How much larger than the attachment an upload's multipart form can be
+const FORM_OVERHEAD_BYTES: u64 = 64 * 1024;
+
+/// The longest an attachment's name can be, in characters
+const MAX_NAME_LEN: usize = 255;
+
+/// The mime type of attachments whose type we don't trust
+const UNKNOWN_MIME_TYPE: &str = "application/octet-stream";
+
+/// The kinds of attachment we recognise from their first few bytes, so the size limit
+/// for their type only applies when they really are one
+const SNIFFABLE_TYPES: &[&str] = &["application/pdf", "audio/"];
+
+/// The link to an uploaded attachment, and what the editor shows about it
+#[derive(Serialize)]
+pub struct FileLink {
+    url: String,
+    name: String,
+    /// The length of the file in bytes
+    size: i32,
+    /// The file's extension, e.g. pdf
+    extension: Option<String>,
+}
+
+/// For when an attachment is uploaded and we send back a success state, potentially
+/// with a link to the uploaded file
+#[derive(Serialize)]
+pub struct FileResponse {
+    success: i32,
+    file: Option<FileLink>,
+}
+
+/// An attachment, sent back as a file download under its original name
+pub struct Attachment {
+    /// The attachment, read from the store as it's sent
+    stream: FileStream,
+    data_type: ContentType,
+    name: String,
+}
+
+/// Allow us to send an attachment as a response
+impl<'r> Responder<'r, 'static> for Attachment {
+    /// Sends the attachment as a file download (rocket). It's never shown in the
+    /// browser, as it could be anything, including a web page
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        // Old browsers only understand the plain filename, newer ones prefer filename*
+        let fallback: String = self
+            .name
+            .chars()
+            .map(|c| match c {
+                ' '..='~' if c != '"' && c != '\\' => c,
+                _ => '_',
+            })
+            .collect();
+        let disposition = format!(
+            "attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+            percent_encode(&self.name)
+        );
+
+        // Rocket only sizes bodies it can seek through, so the length is set here
+        Response::build()
+            .raw_header("Content-Length", self.stream.len.to_string())
+            .streamed_body(self.stream.reader)
+            .header(self.data_type)
+            .header(Header::new("Content-Disposition", disposition))
+            .header(Header::new("X-Content-Type-Options", "nosniff"))
+            .ok()
+    }
+}
+
+/// Percent-encodes a filename for the filename* parameter of Content-Disposition
+fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Cleans up the name an attachment was uploaded with, so it's safe to send back
+///
+/// ### Arguments
+///
+/// * `name` - the name the file was uploaded with, if it had one
+///
+/// ### Returns
+///
+/// The name without any directories or control characters, shortened if it's too long
+fn clean_name(name: Option<&str>) -> String {
+    let name = name.unwrap_or_default();
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect();
+
+    match name.trim() {
+        "" | "." | ".." => String::from("file"),
+        name => name.to_string(),
+    }
+}
+
+/// Gets the attachment with the relevant ID for the given user, as a download
+///
+/// ### Arguments
+///
+/// * `user` - the user that owns the attachment
+/// * `pool` - connections to the db that's storing the attachment records
+/// * `stores` - the attachment stores, one of which holds the attachment's bytes
+/// * `id` - the id of the attachment
+///
+/// ### Returns
+///
+/// * `Status::NotFound` if the user has no such attachment
+/// * `Status::InternalServerError` if we failed to read the attachment
+/// * `Status::Ok` and the attachment on success
+#[get("/<id>")]
+pub async fn get(
+    user: User,
+    pool: &State<PgPool>,
+    stores: &State<AttachmentStores>,
+    id: i32,
+) -> Result<Attachment, Status> {
+    let conn = db::acquire_conn(pool.inner()).await?;
+    let record = match file::get(conn, user.id, id).await {
+        Err(_) => return Err(Status::InternalServerError),
+        Ok(None) => return Err(Status::NotFound),
+        Ok(Some(record)) => record,
+    };
+    let store = stores
+        .0
+        .get(&record.storage)
+        .map_err(|_| Status::InternalServerError)?;
+    let stream = match store.open(record.id).await {
+        Err(_) | Ok(None) => return Err(Status::InternalServerError),
+        Ok(Some(stream)) => stream,
+    };
+
+    Ok(Attachment {
+        stream,
+        data_type: ContentType::parse_flexible(&record.mime_type).unwrap_or(ContentType::Binary),
+        name: record.name,
+    })
+}
+
+/// Stores a new attachment for the given user. How large it can be depends on its type.
+/// PDFs and audio are recognised from their bytes, so only files that really are one
+/// get their (usually larger) limits. Anything else is limited by the type it claims
+/// to be, so those limits are only advisory
+///
+/// ### Arguments
+///
+/// * `user` - the user uploading the attachment
+/// * `data` - the multipart form containing the attachment, in the field "file"
+/// * `content_type` - the content type of the form
+/// * `pool` - connections to the db that's storing the attachment records
+/// * `stores` - the attachment stores, the current one of which keeps the attachment's bytes
+/// * `scanner` - checks the attachment for malware
+/// * `config` - the server's configuration, which limits the size of attachments
+///
+/// ### Returns
+///
+/// * `Status::PayloadTooLarge` if the attachment is larger than its type's limit, or
+///   the user's storage quota
+/// * `Status::InsufficientStorage` if the attachment would take the user over their quota
+/// * `Status::UnprocessableEntity` if the attachment contains malware
+/// * `Status::ServiceUnavailable` if we couldn't scan the attachment for malware
+/// * `Status::BadRequest` if there's no attachment
+/// * `Status::InternalServerError` if we failed to store the attachment
+/// * `Status::Created` and a link to the attachment on success
+#[post("/", data = "<data>")]
+pub async fn upload(
+    user: User,
+    data: Data<'_>,
+    content_type: &ContentType,
+    pool: &State<PgPool>,
+    stores: &State<AttachmentStores>,
+    scanner: &State<UploadScanner>,
+    config: &State<Config>,
+) -> Result<status::Custom<Json<FileResponse>>, QuotaExceeded> {
+    // We only know the attachment's type once we've started parsing, so refuse anything
+    // larger than the largest limit here, and check its type's limit afterwards
+    let max_bytes = config.file_size_limits.max();
+    let mut options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::file("file").size_limit(max_bytes),
+    ]);
+    options.max_data_bytes = max_bytes + FORM_OVERHEAD_BYTES;
+    let multipart_form_data = match MultipartFormData::parse(content_type, data, options).await {
+        Ok(data) => data,
+        Err(MultipartFormDataError::DataTooLargeError(_)) => {
+            return failed(Status::PayloadTooLarge)
+        }
+        Err(_) => return failed(Status::BadRequest),
+    };
+
+    // The file field is removed from the form when it's dropped
+    let file_field = match multipart_form_data.files.get("file") {
+        Some(file_fields) => &file_fields[0],
+        None => return failed(Status::BadRequest),
+    };
+    let claimed_mime_type = file_field
+        .content_type
+        .as_ref()
+        .map_or(String::from(UNKNOWN_MIME_TYPE), |mime_type| {
+            mime_type.essence_str().to_ascii_lowercase()
+        });
+    let mut header = vec![];
+    let read_header = match File::open(&file_field.path).await {
+        Ok(file) => file.take(16).read_to_end(&mut header).await,
+        Err(err) => Err(err),
+    };
+    if read_header.is_err() {
+        return failed(Status::InternalServerError);
+    }
+    let mime_type = verified_mime_type(&claimed_mime_type, &header);
+    let name = clean_name(file_field.file_name.as_deref());
+    // The attachment is copied into the store straight from where the form put it
+    let size = match rocket::tokio::fs::metadata(&file_field.path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return failed(Status::InternalServerError),
+    };
+    if size > config.file_size_limits.limit(&mime_type) {
+        return failed(Status::PayloadTooLarge);
+    }
+
+    match scanner.check(user.id, &name, &file_field.path).await {
+        Ok(Verdict::Clean) => (),
+        Ok(Verdict::Infected(_)) => return failed(Status::UnprocessableEntity),
+        Err(_) => return failed(Status::ServiceUnavailable),
+    }
+
+    let mut conn = match db::acquire_conn(pool.inner()).await {
+        Ok(conn) => conn,
+        Err(_) => return failed(Status::InternalServerError),
+    };
+    let mut tx = match conn.begin().await {
+        Ok(tx) => tx,
+        Err(_) => return failed(Status::InternalServerError),
+    };
+
+    // Make sure they've got room for it
+    match quota::check(&mut tx, user.id, config.storage_quota, size as i64).await {
+        Ok(()) => (),
+        Err(QuotaError::Exceeded(exceeded)) => return Err(exceeded),
+        Err(QuotaError::Database) => return failed(Status::InternalServerError),
+    }
+
+    // record the attachment in the database, then hand its bytes to the store
+    let store = stores.0.current();
+    let new_file = NewFile {
+        name: &name,
+        mime_type: &mime_type,
+        size: size as i32,
+        storage: store.name(),
+    };
+    let id = match file::create(&mut tx, user.id, &new_file).await {
+        Ok(id) => id,
+        Err(_) => return failed(Status::InternalServerError),
+    };
+    if tx.commit().await.is_err() {
+        return failed(Status::InternalServerError);
+    }
+    // Hand the connection back while the attachment's copied into the store
+    drop(conn);
+    // The attachment can't be seen until it's marked as stored
+    if store_upload(pool, store, id, &file_field.path)
+        .await
+        .is_err()
+    {
+        // Don't leave a record, or any bytes, of an attachment we couldn't store
+        let _ = store.delete(id).await;
+        if let Ok(conn) = db::acquire_conn(pool.inner()).await {
+            let _ = file::delete(conn, id).await;
+        }
+        return failed(Status::InternalServerError);
+    }
+
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    Ok(status::Custom(
+        Status::Created,
+        Json(FileResponse {
+            success: 1,
+            file: Some(FileLink {
+                url: config.urls.file(id),
+                size: size as i32,
+                name,
+                extension,
+            }),
+        }),
+    ))
+}
+
+/// Puts the bytes of a pending attachment in the attachment store, then marks it as stored
+///
+/// ### Arguments
+///
+/// * `pool` - connections to the db that's storing the attachment records
+/// * `store` - the store the attachment was recorded as kept in
+/// * `id` - the id of the pending attachment
+/// * `path` - where the attachment file is
+///
+/// ### Returns
+///
+/// Err if we failed to store the attachment, or to mark it as stored
+async fn store_upload(
+    pool: &PgPool,
+    store: &dyn ImageStore,
+    id: i32,
+    path: &Path,
+) -> Result<(), ()> {
+    store.put_file(id, path).await.map_err(|_| ())?;
+    let conn = db::acquire_conn(pool).await.map_err(|_| ())?;
+    file::mark_stored(conn, id).await.map_err(|_| ())
+}
+
+/// Works out the type of an attachment, trusting the type it claims to be unless it's
+/// one we can recognise from its bytes
+///
+/// ### Arguments
+///
+/// * `claimed_mime_type` - The (lowercase) mime type the upload said the file was
+/// * `header` - The first 16 bytes of the file, or all of it if it's shorter
+///
+/// ### Returns
+///
+/// The type we recognised, or the claimed type if it's one we can't recognise, or
+/// application/octet-stream if it claimed to be one we can but isn't
+fn verified_mime_type(claimed_mime_type: &str, header: &[u8]) -> String {
+    let sniffable = SNIFFABLE_TYPES
+        .iter()
+        .any(|sniffable| claimed_mime_type.starts_with(sniffable));
+
+    match sniff(header) {
+        Some(sniffed) => sniffed.to_string(),
+        None if sniffable => String::from(UNKNOWN_MIME_TYPE),
+        None => claimed_mime_type.to_string(),
+    }
+}
+
+/// Recognises PDFs and common audio formats from their first few bytes
+///
+/// ### Arguments
+///
+/// * `header` - The first 16 bytes of the file, or all of it if it's shorter
+///
+/// ### Returns
+///
+/// The file's mime type, or None if it isn't one we recognise
+fn sniff(header: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+    if at(0, b"%PDF-") {
+        Some("application/pdf")
+    } else if at(0, b"ID3") || (header.len() >= 2 && header[0] == 0xff && header[1] & 0xe6 == 0xe2)
+    {
+        // MP3, either with ID3 tags or starting straight on a frame
+        Some("audio/mpeg")
+    } else if header.len() >= 2 && header[0] == 0xff && header[1] & 0xf6 == 0xf0 {
+        // AAC in ADTS frames
+        Some("audio/aac")
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        Some("audio/wav")
+    } else if at(0, b"OggS") {
+        Some("audio/ogg")
+    } else if at(0, b"fLaC") {
+        Some("audio/flac")
+    } else if at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) {
+        Some("audio/aiff")
+    } else if at(0, b"#!AMR") {
+        Some("audio/amr")
+    } else if at(4, b"ftypM4A ") {
+        Some("audio/mp4")
+    } else {
+        None
+    }
+}
+
+/// The response for an upload that failed
+///
+/// ### Arguments
+///
+/// * `status` - the status explaining why it failed
+fn failed(status: Status) -> Result<status::Custom<Json<FileResponse>>, QuotaExceeded> {
+    Ok(status::Custom(
+        status,
+        Json(FileResponse {
+            success: 0,
+            file: None,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pdfs_and_audio_are_recognised() {
+        assert_eq!(sniff(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(sniff(b"ID3\x04\0\0\0\0\0\0"), Some("audio/mpeg"));
+        assert_eq!(sniff(b"\xff\xfb\x90\x64"), Some("audio/mpeg"));
+        assert_eq!(sniff(b"\xff\xf1\x50\x80"), Some("audio/aac"));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), Some("audio/wav"));
+        assert_eq!(sniff(b"OggS\0\x02"), Some("audio/ogg"));
+        assert_eq!(sniff(b"\0\0\0\x20ftypM4A \0\0"), Some("audio/mp4"));
+        assert_eq!(sniff(b"PK\x03\x04"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn recognised_types_replace_the_claimed_type() {
+        assert_eq!(
+            verified_mime_type("text/plain", b"%PDF-1.7"),
+            "application/pdf"
+        );
+        assert_eq!(
+            verified_mime_type("audio/x-wav", b"RIFF\0\0\0\0WAVE"),
+            "audio/wav"
+        );
+    }
+
+    #[test]
+    fn pdfs_and_audio_must_be_what_they_claim() {
+        assert_eq!(
+            verified_mime_type("application/pdf", b"PK\x03\x04"),
+            UNKNOWN_MIME_TYPE
+        );
+        assert_eq!(
+            verified_mime_type("audio/mpeg", b"MZ\x90\0"),
+            UNKNOWN_MIME_TYPE
+        );
+        // Other types can't be checked, so their claims are trusted
+        assert_eq!(verified_mime_type("text/csv", b"a,b,c\n"), "text/csv");
+    }
+}
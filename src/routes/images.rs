@@ -1,23 +1,57 @@
-use std::io::Cursor;
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+};
 
 use rocket::{
-    http::{ContentType, Status},
+    http::{ContentType, Header, Status},
+    request::{FromRequest, Outcome},
     response::{self, status, Responder},
     serde::json::Json,
+    tokio::task,
     Data, Request, Response, State,
 };
 use rocket_multipart_form_data::{
-    mime, MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
+    mime, MultipartFormData, MultipartFormDataError, MultipartFormDataField,
+    MultipartFormDataOptions,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgPool};
+
+use crate::{
+    config::Config,
+    db::{
+        self,
+        image::{
+            self, CreatedImage, ImageDetails, NewImage, NewVariant, StoredImage, VariantRecord,
+        },
+        note::PageSize,
+        user::User,
+    },
+    download::{download, DownloadError},
+    image_processing::{self, ProcessError},
+    image_store::{FileStream, ImageStores, Stores},
+    image_variant::{Format, Variant},
+    quota::{self, QuotaError, QuotaExceeded},
+    routes::notes::PagedResponse,
+    scanner::{UploadScanner, Verdict},
 };
-use serde::Serialize;
-use sqlx::PgPool;
 
-use crate::db::{self, user::User};
+/// How browsers may cache images. They're private to the user, and never change
+const CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+
+/// How much larger than the image an upload's multipart form can be
+const FORM_OVERHEAD_BYTES: u64 = 64 * 1024;
 
-/// For when we send back the link to a file
+/// For when we send back the link to a file, along with what the editor needs to
+/// lay it out before it's loaded
 #[derive(Serialize)]
 pub struct ImageFileLink {
     url: String,
+    width: u32,
+    height: u32,
+    /// A tiny, blurred placeholder to show while the image loads
+    blurhash: Option<String>,
 }
 
 /// For when an image is uploaded and we send back a success state, potentially
@@ -28,11 +62,26 @@ pub struct ImageResponse {
     file: Option<ImageFileLink>,
 }
 
+/// The url of an image to download and store
+#[derive(Deserialize)]
+pub struct FetchImageInfo {
+    url: String,
+}
+
+/// The bytes of an image we're sending
+enum ImageBody {
+    /// The whole image, already in memory
+    Bytes(Vec<u8>),
+    /// The image, read from the image store as it's sent
+    Stream(FileStream),
+}
+
 /// The data for a single image
-#[derive(Debug)]
 pub struct Image {
-    bytes: Vec<u8>,
+    body: ImageBody,
     data_type: ContentType,
+    /// A strong ETag identifying the bytes, without its quotes
+    etag: String,
 }
 impl Image {
     /// Create a new image record
@@ -41,168 +90,893 @@ impl Image {
     ///
     /// * `bytes` - the bytes that make up the image filie
     /// * `data_type` - the type of image file being stored
-    pub fn new(bytes: Vec<u8>, data_type: ContentType) -> Image {
-        Image { bytes, data_type }
+    /// * `etag` - an id for the bytes, which changes whenever they do
+    pub fn new(bytes: Vec<u8>, data_type: ContentType, etag: String) -> Image {
+        Image {
+            body: ImageBody::Bytes(bytes),
+            data_type,
+            etag,
+        }
+    }
+
+    /// Create an image record that's read from the image store as it's sent
+    ///
+    /// ### Arguments
+    ///
+    /// * `stream` - the image file, opened from the image store
+    /// * `data_type` - the type of image file being stored
+    /// * `etag` - an id for the bytes, which changes whenever they do
+    fn streamed(stream: FileStream, data_type: ContentType, etag: String) -> Image {
+        Image {
+            body: ImageBody::Stream(stream),
+            data_type,
+            etag,
+        }
+    }
+
+    /// An image the client already has cached (its If-None-Match matches the ETag),
+    /// so we can tell it to use that without reading the image
+    ///
+    /// ### Arguments
+    ///
+    /// * `etag` - the ETag of the image the client has cached
+    fn not_modified(etag: String) -> Image {
+        Image::new(vec![], ContentType::Binary, etag)
     }
 }
 
 /// Allow us to send an image file as a response
 impl<'r> Responder<'r, 'static> for Image {
-    /// Sends the given image file as a response (rocket)
-    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
-        Response::build()
-            .sized_body(self.bytes.len(), Cursor::new(self.bytes))
+    /// Sends the given image file as a response (rocket). Images never change once
+    /// they're uploaded, so browsers can cache them forever, and revalidate them with
+    /// their ETag. Ranges of the image can be requested too, though only of images
+    /// that are already in memory - streamed images are always sent whole
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .header(Header::new("ETag", format!("\"{}\"", self.etag)))
+            .header(Header::new("Cache-Control", CACHE_CONTROL))
+            // Browsers mustn't second guess the type, in case an image is also valid HTML
+            .header(Header::new("X-Content-Type-Options", "nosniff"));
+
+        if let Some(if_none_match) = req.headers().get_one("If-None-Match") {
+            if etag_matches(if_none_match, &self.etag) {
+                return response.status(Status::NotModified).ok();
+            }
+        }
+
+        response
             .header(self.data_type)
-            .ok()
+            .header(Header::new("Accept-Ranges", "bytes"));
+        let bytes = match self.body {
+            ImageBody::Bytes(bytes) => bytes,
+            ImageBody::Stream(stream) => {
+                // Rocket only sizes bodies it can seek through, so the length is set here
+                return response
+                    .raw_header("Content-Length", stream.len.to_string())
+                    .streamed_body(stream.reader)
+                    .ok();
+            }
+        };
+        let len = bytes.len();
+        match req
+            .headers()
+            .get_one("Range")
+            .map(|range| parse_range(range, len))
+        {
+            Some(Some(Ok((start, end)))) => {
+                let part = bytes[start..=end].to_vec();
+                response
+                    .status(Status::PartialContent)
+                    .header(Header::new(
+                        "Content-Range",
+                        format!("bytes {start}-{end}/{len}"),
+                    ))
+                    .sized_body(part.len(), Cursor::new(part))
+                    .ok()
+            }
+            Some(Some(Err(()))) => response
+                .status(Status::RangeNotSatisfiable)
+                .header(Header::new("Content-Range", format!("bytes */{len}")))
+                .ok(),
+            // No range, or one we don't support, gets the whole image
+            _ => response.sized_body(len, Cursor::new(bytes)).ok(),
+        }
+    }
+}
+
+/// The ETags of the images the client already has cached, from the If-None-Match header
+pub struct IfNoneMatch(Option<String>);
+impl IfNoneMatch {
+    /// Whether the client has the image with the given ETag cached
+    fn matches(&self, etag: &str) -> bool {
+        self.0
+            .as_deref()
+            .is_some_and(|if_none_match| etag_matches(if_none_match, etag))
+    }
+}
+
+/// Allows us to check for a cached copy before reading an image
+#[async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = ();
+
+    /// Reads the If-None-Match header, if there is one
+    async fn from_request(req: &'r Request<'_>) -> Outcome<IfNoneMatch, ()> {
+        Outcome::Success(IfNoneMatch(
+            req.headers().get_one("If-None-Match").map(String::from),
+        ))
     }
 }
 
-/// Gets the image with the relevant ID for the given user
-#[get("/<id>")]
-pub async fn get(user: User, pool: &State<PgPool>, id: i32) -> Result<Image, Status> {
+/// Whether the client only wants part of the image, from the Range header
+pub struct RangeRequest(bool);
+
+/// Allows us to read the image into memory only when part of it is wanted
+#[async_trait]
+impl<'r> FromRequest<'r> for RangeRequest {
+    type Error = ();
+
+    /// Checks for a Range header
+    async fn from_request(req: &'r Request<'_>) -> Outcome<RangeRequest, ()> {
+        Outcome::Success(RangeRequest(req.headers().contains("Range")))
+    }
+}
+
+/// Checks whether an If-None-Match header matches an ETag
+///
+/// ### Arguments
+///
+/// * `if_none_match` - the value of the If-None-Match header, a list of ETags or *
+/// * `etag` - the ETag of the image, without its quotes
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        // If-None-Match uses weak comparison, so W/ prefixes are ignored
+        let candidate = candidate.strip_prefix("W/").unwrap_or(candidate);
+        candidate == "*" || candidate.trim_matches('"') == etag
+    })
+}
+
+/// Parses a Range header. Only single ranges of bytes are supported
+///
+/// ### Arguments
+///
+/// * `range` - the value of the Range header, e.g. "bytes=0-499", "bytes=500-" or "bytes=-500"
+/// * `len` - the length of the image in bytes
+///
+/// ### Returns
+///
+/// None if it's not a range we support (so the whole image should be sent), Err if the
+/// range is outside the image, otherwise the first and last (inclusive) bytes of the range
+fn parse_range(range: &str, len: usize) -> Option<Result<(usize, usize), ()>> {
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    if end.contains(',') {
+        return None;
+    }
+    let (start, end) = match (start.trim(), end.trim()) {
+        // The last `end` bytes
+        ("", end) => {
+            let suffix: usize = end.parse().ok()?;
+            if suffix == 0 {
+                return Some(Err(()));
+            }
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+
+    if start >= len {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+/// Gets the image with the relevant ID for the given user, optionally resized and/or
+/// converted to another format. Sizes are rounded up to one of a fixed set, and
+/// generated variants are cached in the variant store, so each is only made once.
+/// Originals are streamed from the image store, unless only part of one is wanted.
+/// HEIF images are sent as JPEGs, as few browsers can show them, unless the original
+/// is asked for
+///
+/// ### Arguments
+///
+/// * `user` - the user that owns the image
+/// * `pool` - connections to the db that's storing the image records
+/// * `stores` - the image and variant stores, which hold the image's bytes and any
+///   cached variants
+/// * `id` - the id of the image
+/// * `variant` - the `w` and/or `h` to scale the image down to, how to `fit` it into
+///   them (contain, cover or fill), and the `format` to convert it to (webp, jpeg or png)
+/// * `original` - true to get HEIF images as HEIF, rather than as JPEGs
+/// * `if_none_match` - the ETags of the images the client already has cached
+/// * `range` - whether the client only wants part of the image
+///
+/// ### Returns
+///
+/// * `Status::BadRequest` if the requested size is too large
+/// * `Status::NotFound` if the user has no such image
+/// * `Status::InternalServerError` if we failed to read or convert the image
+/// * `Status::NotModified` if the client's cached copy is still good
+/// * `Status::PartialContent` and part of the image if a range was requested
+/// * `Status::Ok` and the image on success
+#[allow(clippy::too_many_arguments)]
+#[get("/<id>?<original>&<variant..>")]
+pub async fn get(
+    user: User,
+    pool: &State<PgPool>,
+    stores: &State<ImageStores>,
+    id: i32,
+    original: Option<bool>,
+    variant: Variant,
+    if_none_match: IfNoneMatch,
+    range: RangeRequest,
+) -> Result<Image, Status> {
+    if !variant.is_valid() {
+        return Err(Status::BadRequest);
+    }
+
     // Get the image
-    let mut conn = db::acquire_conn(pool.inner()).await?;
-    let record = match sqlx::query!(
-        "SELECT image, mime_type FROM images WHERE id = $1 AND user_id = $2",
-        id,
-        user.id
-    )
-    .fetch_one(&mut conn)
-    .await
-    {
+    let conn = db::acquire_conn(pool.inner()).await?;
+    let record = match image::get(conn, user.id, id).await {
         Err(_) => return Err(Status::InternalServerError),
-        Ok(record) => record,
+        Ok(None) => return Err(Status::NotFound),
+        Ok(Some(record)) => record,
+    };
+    let store = stores
+        .images
+        .get(&record.storage)
+        .map_err(|_| Status::InternalServerError)?;
+    let as_jpeg = image_processing::is_heif(&record.mime_type) && !original.unwrap_or(false);
+    let variant = match as_jpeg {
+        true => variant.or_format(Format::Jpeg),
+        false => variant,
     };
+    let size = |size: Option<i32>| size.and_then(|size| u32::try_from(size).ok());
+    let variant = variant.normalise(size(record.width), size(record.height));
 
-    // Try and compute the mime type
-    let mime_type = match ContentType::parse_flexible(&record.mime_type) {
-        None => return Err(Status::InternalServerError),
-        Some(mime_type) => mime_type,
+    // Images never change, so their hash (and the variant) identifies the bytes we'd
+    // send. Images uploaded before we hashed them get an ETag from their bytes instead
+    let key = variant.key();
+    let etag = record.sha256.as_ref().map(|sha256| {
+        if variant.is_original() {
+            sha256.clone()
+        } else {
+            format!("{sha256}-{key}")
+        }
+    });
+    if let Some(etag) = etag.as_ref().filter(|etag| if_none_match.matches(etag)) {
+        return Ok(Image::not_modified(etag.clone()));
+    }
+
+    // Use the variant if we've made it before
+    if !variant.is_original() {
+        if let Some(cached) = cached_variant(pool, &stores.variants, record.id, &key).await {
+            return to_image(cached.bytes, &cached.mime_type, etag);
+        }
+    }
+
+    // Ranges need the image in memory, as do images without a hash to use as an ETag
+    if let Some(etag) = etag.as_ref().filter(|_| variant.is_original() && !range.0) {
+        let data_type =
+            ContentType::parse_flexible(&record.mime_type).ok_or(Status::InternalServerError)?;
+        return match store.open(record.id).await {
+            Err(_) | Ok(None) => Err(Status::InternalServerError),
+            Ok(Some(stream)) => Ok(Image::streamed(stream, data_type, etag.clone())),
+        };
+    }
+
+    let bytes = match store.get(record.id).await {
+        Err(_) | Ok(None) => return Err(Status::InternalServerError),
+        Ok(Some(bytes)) => bytes,
+    };
+    if variant.is_original() {
+        return to_image(bytes, &record.mime_type, etag);
+    }
+
+    // Resizing is slow, keep it off the async workers
+    let (bytes, mime_type) = task::spawn_blocking(move || variant.render(&bytes))
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .map_err(|_| Status::InternalServerError)?;
+    let generated = StoredImage {
+        id: record.id,
+        bytes,
+        mime_type: String::from(mime_type),
     };
+    cache_variant(pool, &stores.variants, &key, &generated).await;
 
-    Ok(Image::new(record.image, mime_type))
+    to_image(generated.bytes, &generated.mime_type, etag)
 }
 
-/// Stores a new image for the given user
-#[post("/", data = "<data>")]
-pub async fn upload(
+/// Gets a variant of an image that's been generated and cached before
+///
+/// ### Arguments
+///
+/// * `pool` - connections to the db that's storing the variant records
+/// * `stores` - the variant stores, one of which holds the variant's bytes
+/// * `image_id` - the id of the original image
+/// * `key` - the key identifying the variant
+///
+/// ### Returns
+///
+/// The variant, or None if it hasn't been cached (or we couldn't read it)
+pub async fn cached_variant(
+    pool: &PgPool,
+    stores: &Stores,
+    image_id: i32,
+    key: &str,
+) -> Option<StoredImage> {
+    let conn = db::acquire_conn(pool).await.ok()?;
+    let record = image::get_variant(conn, image_id, key).await.ok()??;
+    let store = stores.get(&record.storage).ok()?;
+    // Its bytes are stored just after it's recorded, so may not be there yet
+    let bytes = store.get(record.id).await.ok()??;
+
+    Some(StoredImage {
+        id: image_id,
+        bytes,
+        mime_type: record.mime_type,
+    })
+}
+
+/// Caches a generated variant of an image in the variant store, so it doesn't have to
+/// be generated again. Cached variants can always be generated again, so they don't
+/// count toward the user's storage quota. Failing to cache one isn't worth failing a
+/// request over, so nothing's returned
+///
+/// ### Arguments
+///
+/// * `pool` - connections to the db that's storing the variant records
+/// * `stores` - the variant stores, the current one of which keeps the variant's bytes
+/// * `key` - the key identifying the variant
+/// * `variant` - the variant, with the id of the original image
+pub async fn cache_variant(pool: &PgPool, stores: &Stores, key: &str, variant: &StoredImage) {
+    let Ok(size) = i32::try_from(variant.bytes.len()) else {
+        return;
+    };
+    let Ok(mut conn) = db::acquire_conn(pool).await else {
+        return;
+    };
+
+    let store = stores.current();
+    let new_variant = NewVariant {
+        key,
+        mime_type: &variant.mime_type,
+        storage: store.name(),
+        size,
+    };
+    // Someone else has already cached it if there's nothing to store
+    let Ok(Some(id)) = image::create_variant(&mut conn, variant.id, &new_variant).await else {
+        return;
+    };
+    drop(conn);
+    if store.put(id, &variant.bytes).await.is_err() {
+        if let Ok(conn) = db::acquire_conn(pool).await {
+            let _ = image::delete_variant(conn, id).await;
+        }
+    }
+}
+
+/// Removes the bytes of deleted variants from the stores they're kept in
+///
+/// ### Arguments
+///
+/// * `stores` - the variant stores
+/// * `variants` - the records of the deleted variants
+///
+/// ### Returns
+///
+/// Err if we failed to remove any of them
+pub async fn remove_variants(stores: &Stores, variants: &[VariantRecord]) -> Result<(), ()> {
+    let mut result = Ok(());
+    for variant in variants {
+        let Ok(store) = stores.get(&variant.storage) else {
+            result = Err(());
+            continue;
+        };
+        if store.delete(variant.id).await.is_err() {
+            error!(
+                "Failed to remove variant {} from {}",
+                variant.id,
+                store.name()
+            );
+            result = Err(());
+        }
+    }
+
+    result
+}
+
+/// Wraps up image bytes as a response
+///
+/// ### Arguments
+///
+/// * `bytes` - the bytes of the image file
+/// * `mime_type` - the mime type of the image file
+/// * `etag` - the image's ETag, or None to make one from its bytes
+///
+/// ### Returns
+///
+/// The image, or `Status::InternalServerError` if the mime type is invalid
+fn to_image(bytes: Vec<u8>, mime_type: &str, etag: Option<String>) -> Result<Image, Status> {
+    let etag = etag.unwrap_or_else(|| crate::hash::sha256_hex(&bytes));
+    match ContentType::parse_flexible(mime_type) {
+        None => Err(Status::InternalServerError),
+        Some(mime_type) => Ok(Image::new(bytes, mime_type, etag)),
+    }
+}
+
+/// Gets a page of the user's images, most recently uploaded first, along with the
+/// notes that use each one
+///
+/// ### Arguments
+///
+/// * `user` - the user whose images we're listing
+/// * `pool` - connections to the db that's storing the image records
+/// * `page` - the page of images to get
+/// * `page_size` - the max number of images per page
+///
+/// ### Returns
+///
+/// * `Status::InternalServerError` when we failed to reach the db
+/// * `Status::BadRequest` if an invalid pagesize was requested
+/// * `Status::Ok` and the page of images, and a bool for if there's more results on success
+#[get("/?<page>&<page_size>")]
+pub async fn get_many(
     user: User,
-    data: Data<'_>,
-    content_type: &ContentType,
     pool: &State<PgPool>,
-) -> status::Custom<Json<ImageResponse>> {
-    let mut conn = match db::acquire_conn(pool.inner()).await {
-        Err(_) => {
-            return status::Custom(
-                Status::InternalServerError,
-                Json(ImageResponse {
-                    success: 0,
-                    file: None,
-                }),
-            )
+    page: i32,
+    page_size: Option<i32>,
+) -> status::Custom<Option<Json<PagedResponse<Vec<ImageDetails>>>>> {
+    let conn = match db::acquire_conn(pool).await {
+        Ok(conn) => conn,
+        Err(_) => return status::Custom(Status::InternalServerError, None),
+    };
+
+    // Validate input parameter
+    let page_size = match PageSize::new(page_size.unwrap_or(20)) {
+        Ok(page_size) => page_size,
+        Err(_) => return status::Custom(Status::BadRequest, None),
+    };
+
+    // Fetch and return
+    match image::get_page(conn, user.id, page, page_size).await {
+        Ok((images, more)) => {
+            status::Custom(Status::Ok, Some(Json(PagedResponse::new(images, more))))
+        }
+        Err(_) => status::Custom(Status::InternalServerError, None),
+    }
+}
+
+/// Deletes one of the user's images. Images still used by a note aren't deleted unless
+/// it's forced, as the notes would be left with a broken image
+///
+/// ### Arguments
+///
+/// * `user` - the user who owns the image
+/// * `pool` - connections to the db that's storing the image records
+/// * `stores` - the image and variant stores, which hold the image's bytes and those
+///   of its variants
+/// * `id` - the id of the image to delete
+/// * `force` - true to delete the image even if notes are using it
+///
+/// ### Returns
+///
+/// * `Status::InternalServerError` if we failed to reach the db or image store
+/// * `Status::NotFound` if the user has no such image
+/// * `Status::Conflict` if notes are still using the image, and it wasn't forced
+/// * `Status::Ok` if the image was deleted
+#[delete("/<id>?<force>")]
+pub async fn delete(
+    user: User,
+    pool: &State<PgPool>,
+    stores: &State<ImageStores>,
+    id: i32,
+    force: Option<bool>,
+) -> Status {
+    let conn = match db::acquire_conn(pool).await {
+        Ok(conn) => conn,
+        Err(_) => return Status::InternalServerError,
+    };
+    let store = match image::get(conn, user.id, id).await {
+        Err(_) => return Status::InternalServerError,
+        Ok(None) => return Status::NotFound,
+        Ok(Some(record)) => match stores.images.get(&record.storage) {
+            Ok(store) => store,
+            Err(_) => return Status::InternalServerError,
+        },
+    };
+
+    if !force.unwrap_or(false) {
+        let conn = match db::acquire_conn(pool).await {
+            Ok(conn) => conn,
+            Err(_) => return Status::InternalServerError,
+        };
+        match image::get_referencing_notes(conn, user.id, id).await {
+            Err(_) => return Status::InternalServerError,
+            Ok(notes) if !notes.is_empty() => return Status::Conflict,
+            Ok(_) => (),
         }
+    }
+
+    // Remove the record first, so the image can't be served with its bytes missing
+    let conn = match db::acquire_conn(pool).await {
         Ok(conn) => conn,
+        Err(_) => return Status::InternalServerError,
+    };
+    let variants = match image::delete(conn, id).await {
+        Err(_) => return Status::InternalServerError,
+        Ok(None) => return Status::NotFound,
+        Ok(Some(variants)) => variants,
     };
+    let removed_variants = remove_variants(&stores.variants, &variants).await;
+    match (store.delete(id).await, removed_variants) {
+        (Ok(()), Ok(())) => Status::Ok,
+        _ => Status::InternalServerError,
+    }
+}
 
-    // parse our input data as a multipart form
-    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+/// Stores a new image for the given user. The image's bytes are checked to really be
+/// the type of image it was uploaded as, and photos are rotated upright and stripped
+/// of their metadata before they're stored
+///
+/// ### Arguments
+///
+/// * `user` - the user uploading the image
+/// * `data` - the multipart form containing the image, in the field "image"
+/// * `content_type` - the content type of the form
+/// * `pool` - connections to the db that's storing the image records
+/// * `stores` - the image stores, the current one of which keeps the image's bytes
+/// * `scanner` - checks the image for malware
+/// * `config` - the server's configuration, which limits the size of uploads
+///
+/// ### Returns
+///
+/// * `Status::PayloadTooLarge` if the image is larger than the configured limit, or
+///   the user's storage quota
+/// * `Status::InsufficientStorage` if the image would take the user over their quota
+/// * `Status::UnprocessableEntity` if the image contains malware
+/// * `Status::ServiceUnavailable` if we couldn't scan the image for malware
+/// * `Status::UnsupportedMediaType` if it isn't an image we accept
+/// * `Status::BadRequest` if there's no image, or we couldn't read it
+/// * `Status::InternalServerError` if we failed to store the image
+/// * `Status::Created` and a link to the image if it's new
+/// * `Status::Ok` and a link to the image if they'd already uploaded it
+#[post("/", data = "<data>")]
+pub async fn upload(
+    user: User,
+    data: Data<'_>,
+    content_type: &ContentType,
+    pool: &State<PgPool>,
+    stores: &State<ImageStores>,
+    scanner: &State<UploadScanner>,
+    config: &State<Config>,
+) -> Result<status::Custom<Json<ImageResponse>>, QuotaExceeded> {
+    // parse our input data as a multipart form, refusing anything too large. The form
+    // itself can be a little larger than the image, to fit its boundaries and headers
+    let mut options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
         MultipartFormDataField::file("image")
             .content_type_by_string(Some(mime::IMAGE_STAR))
-            .unwrap(),
+            .unwrap()
+            .size_limit(config.image_max_bytes),
     ]);
-    let multipart_form_data = MultipartFormData::parse(content_type, data, options).await;
-    let multipart_form_data = match multipart_form_data {
+    options.max_data_bytes = config.image_max_bytes + FORM_OVERHEAD_BYTES;
+    let multipart_form_data = match MultipartFormData::parse(content_type, data, options).await {
         Ok(data) => data,
-        Err(_) => {
-            return status::Custom(
-                Status::InternalServerError,
-                Json(ImageResponse {
-                    success: 0,
-                    file: None,
-                }),
-            );
+        Err(MultipartFormDataError::DataTooLargeError(_)) => {
+            return failed(Status::PayloadTooLarge)
+        }
+        Err(MultipartFormDataError::DataTypeError(_)) => {
+            return failed(Status::UnsupportedMediaType)
         }
+        Err(_) => return failed(Status::BadRequest),
     };
 
-    let photo = multipart_form_data.files.get("image"); // Use the get method to preserve file fields from moving out of the MultipartFormData instance in order to delete them automatically when the MultipartFormData instance is being dropped
-    if let Some(file_fields) = photo {
-        let file_field = &file_fields[0]; // Because we only put one "image" field to the allowed_fields, the max length of this file_fields is 1.
-        println!("IN THE SOME SECTION");
+    // The file field is removed from the form when it's dropped
+    let file_field = match multipart_form_data.files.get("image") {
+        Some(file_fields) => &file_fields[0],
+        None => return failed(Status::BadRequest),
+    };
+    let claimed_mime_type = match &file_field.content_type {
+        Some(mime_type) => mime_type.essence_str().to_string(),
+        None => return failed(Status::UnsupportedMediaType),
+    };
+    let upload = Upload {
+        path: file_field.path.clone(),
+        name: file_field.file_name.as_deref().unwrap_or("image"),
+        claimed_mime_type,
+    };
 
-        let _content_type = &file_field.content_type;
-        let _file_name = &file_field.file_name;
-        let _path = &file_field.path;
+    save(user.id, upload, pool, stores, scanner, config).await
+}
 
-        // get the file data as a vec<u8> string, and ensure _content_type exists
-        let content = rocket::tokio::fs::read(_path).await;
-        if _content_type.is_none() || content.is_err() {
-            return status::Custom(
-                Status::InternalServerError,
-                Json(ImageResponse {
-                    success: 0,
-                    file: None,
-                }),
-            );
-        }
-        let content = content.unwrap();
-        let _content_type = _content_type.as_ref().unwrap();
-
-        // get the mimetype and validate it's an image format
-        let content_type = _content_type.essence_str();
-        let mime_type = match ContentType::parse_flexible(content_type) {
-            None => {
-                return status::Custom(
-                    Status::InternalServerError,
-                    Json(ImageResponse {
-                        success: 0,
-                        file: None,
-                    }),
-                )
-            }
-            Some(data) => data,
-        };
-        if mime_type.top() != "image" {
-            return status::Custom(
-                Status::InternalServerError,
-                Json(ImageResponse {
-                    success: 0,
-                    file: None,
-                }),
-            );
-        }
-        let mime_type = mime_type.to_string();
-        println!("{mime_type}");
-
-        // insert the image into the database
-        let res = sqlx::query!(
-            "INSERT INTO images (user_id, image, mime_type) VALUES ($1, $2, $3) RETURNING id",
-            user.id,
-            content,
-            mime_type
-        )
-        .fetch_one(&mut conn)
-        .await;
-
-        // You can now deal with the uploaded file.
-        if let Ok(record) = res {
-            return status::Custom(
-                Status::Created,
-                Json(ImageResponse {
-                    success: 1,
-                    file: Some(ImageFileLink {
-                        url: format!("https://dev.com/api/images/{}", record.id),
-                    }),
-                }),
-            );
+/// Stores an image downloaded from a url for the given user, for when an image is
+/// pasted or linked into the editor. The download is checked the same way as uploads
+///
+/// ### Arguments
+///
+/// * `user` - the user adding the image
+/// * `fetch` - the url of the image
+/// * `pool` - connections to the db that's storing the image records
+/// * `stores` - the image stores, the current one of which keeps the image's bytes
+/// * `scanner` - checks the image for malware
+/// * `config` - the server's configuration, which limits the size of downloads
+///
+/// ### Returns
+///
+/// * `Status::BadRequest` if the url is invalid
+/// * `Status::Forbidden` if the url leads somewhere private
+/// * `Status::BadGateway` if we couldn't download the image
+/// * `Status::PayloadTooLarge` if the image is larger than the configured limit, or
+///   the user's storage quota
+/// * `Status::InsufficientStorage` if the image would take the user over their quota
+/// * `Status::UnprocessableEntity` if the image contains malware
+/// * `Status::ServiceUnavailable` if we couldn't scan the image for malware
+/// * `Status::UnsupportedMediaType` if it isn't an image we accept
+/// * `Status::InternalServerError` if we failed to store the image
+/// * `Status::Created` and a link to the image if it's new
+/// * `Status::Ok` and a link to the image if they'd already added it
+#[post("/fetch", data = "<fetch>")]
+pub async fn fetch(
+    user: User,
+    fetch: Json<FetchImageInfo>,
+    pool: &State<PgPool>,
+    stores: &State<ImageStores>,
+    scanner: &State<UploadScanner>,
+    config: &State<Config>,
+) -> Result<status::Custom<Json<ImageResponse>>, QuotaExceeded> {
+    let downloaded = download(
+        &fetch.url,
+        config.image_max_bytes,
+        config.image_fetch_timeout,
+    )
+    .await;
+    let downloaded = match downloaded {
+        Ok(downloaded) => downloaded,
+        Err(DownloadError::InvalidUrl) => return failed(Status::BadRequest),
+        Err(DownloadError::Forbidden) => return failed(Status::Forbidden),
+        Err(DownloadError::TooLarge) => return failed(Status::PayloadTooLarge),
+        Err(DownloadError::Failed) => return failed(Status::BadGateway),
+    };
+    let claimed_mime_type = match downloaded.mime_type {
+        Some(mime_type) => mime_type,
+        None => return failed(Status::UnsupportedMediaType),
+    };
+
+    // The download is removed once it's dropped, after it's been saved
+    let upload = Upload {
+        path: downloaded.file.path().to_path_buf(),
+        name: &fetch.url,
+        claimed_mime_type,
+    };
+    save(user.id, upload, pool, stores, scanner, config).await
+}
+
+/// An image a user's added, waiting to be checked and stored
+struct Upload<'a> {
+    /// Where the image file is, which must stay there until it's been stored
+    path: PathBuf,
+    /// What the image was called (its file name or url), for the logs
+    name: &'a str,
+    /// The mime type the image was added as
+    claimed_mime_type: String,
+}
+
+/// Checks an image a user's added for malware, cleans it up, and stores it
+///
+/// ### Arguments
+///
+/// * `user_id` - the id of the user adding the image
+/// * `upload` - the image they've added
+/// * `pool` - connections to the db that's storing the image records
+/// * `stores` - the image stores, the current one of which keeps the image's bytes
+/// * `scanner` - checks the image for malware
+/// * `config` - the server's configuration, which sets the user's storage quota
+///
+/// ### Returns
+///
+/// The response to send back, a link to the image if it was stored, or QuotaExceeded
+/// if the image would take the user over their storage quota
+async fn save(
+    user_id: i32,
+    upload: Upload<'_>,
+    pool: &State<PgPool>,
+    stores: &State<ImageStores>,
+    scanner: &State<UploadScanner>,
+    config: &State<Config>,
+) -> Result<status::Custom<Json<ImageResponse>>, QuotaExceeded> {
+    // Scan it before we try decoding it, as decoders are where malicious images strike
+    match scanner.check(user_id, upload.name, &upload.path).await {
+        Ok(Verdict::Clean) => (),
+        Ok(Verdict::Infected(_)) => return failed(Status::UnprocessableEntity),
+        Err(_) => return failed(Status::ServiceUnavailable),
+    }
+
+    // check it's really an image, rotate it upright and strip its metadata, then
+    // store what's left
+    let keep_capture_time = config.keep_capture_time;
+    let processed = task::spawn_blocking(move || {
+        image_processing::process(&upload.path, &upload.claimed_mime_type, keep_capture_time)
+    })
+    .await;
+    let processed = match processed {
+        Ok(Ok(processed)) => processed,
+        Ok(Err(ProcessError::Image(_))) => return failed(Status::BadRequest),
+        Ok(Err(ProcessError::Io(_))) => return failed(Status::InternalServerError),
+        Ok(Err(_)) => return failed(Status::UnsupportedMediaType),
+        Err(_) => return failed(Status::InternalServerError),
+    };
+
+    let mut conn = match db::acquire_conn(pool.inner()).await {
+        Ok(conn) => conn,
+        Err(_) => return failed(Status::InternalServerError),
+    };
+    let mut tx = match conn.begin().await {
+        Ok(tx) => tx,
+        Err(_) => return failed(Status::InternalServerError),
+    };
+
+    // Make sure they've got room for it. If they've uploaded these exact bytes before,
+    // we hand back the existing image, which takes up no more room
+    let size = match image::exists(&mut tx, user_id, &processed.sha256).await {
+        Ok(true) => 0,
+        Ok(false) => processed.size as i64,
+        Err(_) => return failed(Status::InternalServerError),
+    };
+    match quota::check(&mut tx, user_id, config.storage_quota, size).await {
+        Ok(()) => (),
+        Err(QuotaError::Exceeded(exceeded)) => return Err(exceeded),
+        Err(QuotaError::Database) => return failed(Status::InternalServerError),
+    }
+
+    // record the image in the database, then hand its bytes to the image store
+    let new_image = NewImage {
+        mime_type: processed.mime_type,
+        storage: stores.images.current().name(),
+        sha256: &processed.sha256,
+        size: processed.size as i32,
+        width: processed.width as i32,
+        height: processed.height as i32,
+        blurhash: processed.blurhash.as_deref(),
+        captured_at: processed.captured_at,
+    };
+    let created = match image::create(&mut tx, user_id, &new_image).await {
+        Ok(created) => created,
+        Err(_) => return failed(Status::InternalServerError),
+    };
+    if tx.commit().await.is_err() {
+        return failed(Status::InternalServerError);
+    }
+    // Hand the connection back while the image is copied into the store
+    drop(conn);
+    let (id, is_new) = (created.id, created.is_new);
+
+    // The image can't be seen until it's marked as stored. If another upload of the same
+    // bytes is still storing them, we store them too, in case that upload fails
+    if created.pending
+        && store_upload(pool, &stores.images, &created, &processed.path)
+            .await
+            .is_err()
+    {
+        // Only our reference goes, as other uploads of the image may still succeed
+        if let Ok(conn) = db::acquire_conn(pool.inner()).await {
+            let _ = image::remove_upload(conn, id).await;
         }
+        return failed(Status::InternalServerError);
     }
 
-    // TODO define a maximum file size
-    status::Custom(
-        Status::InternalServerError,
+    Ok(status::Custom(
+        if is_new { Status::Created } else { Status::Ok },
+        Json(ImageResponse {
+            success: 1,
+            file: Some(ImageFileLink {
+                url: config.urls.image(id),
+                width: processed.width,
+                height: processed.height,
+                blurhash: processed.blurhash,
+            }),
+        }),
+    ))
+}
+
+/// Puts the bytes of a pending image in the image store, then marks it as stored
+///
+/// ### Arguments
+///
+/// * `pool` - connections to the db that's storing the image records
+/// * `stores` - the image stores, one of which the image was recorded as kept in
+/// * `created` - the record of the pending image
+/// * `path` - where the image file is
+///
+/// ### Returns
+///
+/// Err if we failed to store the image, or to mark it as stored
+async fn store_upload(
+    pool: &PgPool,
+    stores: &Stores,
+    created: &CreatedImage,
+    path: &Path,
+) -> Result<(), ()> {
+    let store = stores.get(&created.storage).map_err(|_| ())?;
+    store.put_file(created.id, path).await.map_err(|_| ())?;
+    let conn = db::acquire_conn(pool).await.map_err(|_| ())?;
+    image::mark_stored(conn, created.id).await.map_err(|_| ())
+}
+
+/// The response for an upload that failed
+///
+/// ### Arguments
+///
+/// * `status` - the status explaining why it failed
+fn failed(status: Status) -> Result<status::Custom<Json<ImageResponse>>, QuotaExceeded> {
+    Ok(status::Custom(
+        status,
         Json(ImageResponse {
             success: 0,
             file: None,
         }),
-    )
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_parsed() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Some(Ok((0, 499))));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(Ok((500, 999))));
+        // Ends past the image are cut short
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some(Ok((900, 999))));
+    }
+
+    #[test]
+    fn suffix_ranges_count_from_the_end() {
+        assert_eq!(parse_range("bytes=-200", 1000), Some(Ok((800, 999))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(Ok((0, 999))));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+    }
+
+    #[test]
+    fn ranges_outside_the_image_are_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=1000-1200", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+    }
+
+    #[test]
+    fn malformed_ranges_are_ignored() {
+        for range in [
+            "items=0-499",
+            "bytes=0",
+            "bytes=a-b",
+            "bytes=500-100",
+            "bytes=0-1,5-9",
+            "bytes=-",
+        ] {
+            assert_eq!(parse_range(range, 1000), None, "{range}");
+        }
+    }
+
+    #[test]
+    fn etags_are_matched() {
+        assert!(etag_matches("\"abc\"", "abc"));
+        assert!(etag_matches("\"xyz\", \"abc\"", "abc"));
+        assert!(!etag_matches("\"abcd\"", "abc"));
+        assert!(!etag_matches("", "abc"));
+    }
+
+    #[test]
+    fn weak_etags_and_wildcards_match() {
+        assert!(etag_matches("W/\"abc\"", "abc"));
+        assert!(etag_matches("\"xyz\", W/\"abc\"", "abc"));
+        assert!(etag_matches("*", "abc"));
+    }
 }
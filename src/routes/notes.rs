@@ -1,11 +1,19 @@
-use crate::db::{
-    self,
-    note::{self, CreateNoteInfo, Note, NoteOverview, UpdateNoteInfo},
-    user::User,
+use crate::{
+    config::Config,
+    db::{
+        self,
+        note::{
+            self, CreateNoteInfo, DiaryFilter, MoodBucket, MoodPoint, Note, NoteOverview,
+            UpdateNoteInfo, UpdateOutcome,
+        },
+        usage,
+        user::User,
+    },
+    quota::{self, QuotaError, QuotaExceeded},
 };
 use rocket::{http::Status, response::status, serde::json::Json, State};
 use serde::Serialize;
-use sqlx::PgPool;
+use sqlx::{Connection, PgPool};
 
 /// Represents a generic paged response - the data and if there's more after this
 #[derive(Serialize)]
@@ -13,6 +21,17 @@ pub struct PagedResponse<T> {
     data: T,
     more: bool,
 }
+impl<T> PagedResponse<T> {
+    /// Create a new page of results
+    ///
+    /// ### Arguments
+    ///
+    /// * `data` - the results on this page
+    /// * `more` - whether there are more results after this page
+    pub fn new(data: T, more: bool) -> PagedResponse<T> {
+        PagedResponse { data, more }
+    }
+}
 
 #[derive(Serialize)]
 pub struct UpdateResponse {
@@ -25,23 +44,51 @@ pub struct UpdateResponse {
 ///
 /// * `create` - the information required to create the note
 /// * `pool` - a pool of connections to the database we want to create the note in
+/// * `config` - the server's configuration, which sets the user's storage quota
 /// * `user` - the user creating the note
+///
+/// ### Returns
+///
+/// * `Status::BadRequest` if the note contained invalid values
+/// * `Status::InsufficientStorage` if the note would take the user over their quota
+/// * `Status::PayloadTooLarge` if the note alone is larger than the quota
+/// * `Status::Created` and the created note on success
 #[post("/", format = "json", data = "<create>")]
 pub async fn create(
     create: Json<CreateNoteInfo>,
     pool: &State<PgPool>,
+    config: &State<Config>,
     user: User,
-) -> status::Custom<Option<Json<Note>>> {
-    let conn = match db::acquire_conn(pool).await {
+) -> Result<status::Custom<Option<Json<Note>>>, QuotaExceeded> {
+    if !create.is_valid() {
+        return Ok(status::Custom(Status::BadRequest, None));
+    }
+
+    let mut conn = match db::acquire_conn(pool).await {
         Ok(conn) => conn,
-        Err(_) => return status::Custom(Status::InternalServerError, None),
+        Err(_) => return Ok(status::Custom(Status::InternalServerError, None)),
     };
+    let mut tx = match conn.begin().await {
+        Ok(tx) => tx,
+        Err(_) => return Ok(status::Custom(Status::InternalServerError, None)),
+    };
+
+    // Make sure they've got room for it
+    match quota::check(&mut tx, user.id, config.storage_quota, create.size()).await {
+        Ok(()) => (),
+        Err(QuotaError::Exceeded(exceeded)) => return Err(exceeded),
+        Err(QuotaError::Database) => return Ok(status::Custom(Status::InternalServerError, None)),
+    }
 
     // Create the note, returning the ID of the created note on success, or an error on failure
-    match note::create(conn, user.id, &create).await {
+    let note = match note::create(&mut tx, user.id, &create).await {
+        Ok(note) => note,
+        Err(_) => return Ok(status::Custom(Status::InternalServerError, None)),
+    };
+    Ok(match tx.commit().await {
         Err(_) => status::Custom(Status::InternalServerError, None),
-        Ok(note) => status::Custom(Status::Created, Some(Json(note))),
-    }
+        Ok(()) => status::Custom(Status::Created, Some(Json(note))),
+    })
 }
 
 /// Gets the note with the specified ID
@@ -206,18 +253,20 @@ pub async fn get_overview_many(
 /// * `user` - the user who's making the requeset
 /// * `page` - the numbered page we're hoping to get data for
 /// * `page_size` - how many results in each page
+/// * `filter` - mood, weather and location filters the diary notes must match
 ///
 /// ### Returns
 ///
 /// * `status::InternalServerError` when we failed to reach thedb, or couldn't get the notes
 /// * `status::BadRequest` if an invalid pagesize was returned
 /// * `status::Ok` and a json-encoded vector of notes, and a bool for if there's more results on success
-#[get("/diary?<page>&<page_size>")]
+#[get("/diary?<page>&<page_size>&<filter..>")]
 pub async fn get_diary_many(
     pool: &State<PgPool>,
     user: User,
     page: i32,
     page_size: Option<i32>,
+    filter: DiaryFilter,
 ) -> status::Custom<Option<Json<PagedResponse<Vec<Note>>>>> {
     let conn = match db::acquire_conn(pool).await {
         Ok(conn) => conn,
@@ -231,7 +280,7 @@ pub async fn get_diary_many(
     };
 
     // Fetch and return
-    match note::get_diary_notes(conn, user.id, page, page_size).await {
+    match note::get_diary_notes(conn, user.id, page, page_size, &filter).await {
         Ok(notes) => status::Custom(
             Status::Ok,
             Some(Json(PagedResponse {
@@ -243,6 +292,53 @@ pub async fn get_diary_many(
     }
 }
 
+/// Gets the average mood of the user's diary entries over time
+///
+/// ### Arguments
+///
+/// * `pool` - connections to the db that's storing our notes
+/// * `user` - the user who's making the request
+/// * `bucket` - the period we're averaging over (day, week or month), defaults to day
+/// * `from` - an optional ISO-8601 timestamp, only entries created at or after it are included
+/// * `to` - an optional ISO-8601 timestamp, only entries created before it are included
+/// * `tz` - the user's IANA time zone (e.g. Europe/London), which periods are split
+///   at midnight in. Defaults to UTC
+///
+/// ### Returns
+///
+/// * `status::InternalServerError` when we failed to reach the db
+/// * `status::BadRequest` if `from` or `to` weren't valid timestamps, or `tz` isn't a time zone
+/// * `status::Ok` and the json-encoded average mood for each period, oldest first
+#[get("/diary/mood?<bucket>&<from>&<to>&<tz>")]
+pub async fn get_diary_mood(
+    pool: &State<PgPool>,
+    user: User,
+    bucket: Option<MoodBucket>,
+    from: Option<&str>,
+    to: Option<&str>,
+    tz: Option<&str>,
+) -> status::Custom<Option<Json<Vec<MoodPoint>>>> {
+    // Validate input parameters
+    let (from, to) = match (note::parse_timestamp(from), note::parse_timestamp(to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        _ => return status::Custom(Status::BadRequest, None),
+    };
+
+    let conn = match db::acquire_conn(pool).await {
+        Ok(conn) => conn,
+        Err(_) => return status::Custom(Status::InternalServerError, None),
+    };
+
+    // Fetch and return
+    let bucket = bucket.unwrap_or(MoodBucket::Day);
+    let tz = tz.unwrap_or("UTC");
+    match note::get_mood_summary(conn, user.id, bucket, from, to, tz).await {
+        Ok(Some(points)) => status::Custom(Status::Ok, Some(Json(points))),
+        Ok(None) => status::Custom(Status::BadRequest, None),
+        Err(_) => status::Custom(Status::InternalServerError, None),
+    }
+}
+
 /// Delete the note with the given id owned by the provided user
 ///
 /// ### Arguments
@@ -278,26 +374,67 @@ pub async fn delete(note_id: i32, pool: &State<PgPool>, user: User) -> Status {
 /// * `note_id` - the id of the note we're updating
 /// * `update` - the update package, containing only the fields we're hoping to update
 /// * `pool` - a pool of connections to the database in which the note is stored
+/// * `config` - the server's configuration, which sets the user's storage quota
 /// * `user` - the user who owns the note / the user who's making the request
+///
+/// ### Returns
+///
+/// * `Status::BadRequest` if the update contained invalid values, or would move a
+///   diary entry's created_at into the future
+/// * `Status::InsufficientStorage` if the note would grow past the user's quota
+/// * `Status::PayloadTooLarge` if the note alone would be larger than the quota
+/// * `Status::NotFound` if no such note exists for the user
+/// * `Status::Locked` if the note hasn't reached its unlock time
+/// * `Status::Ok` and the new update time on success
 #[patch("/<note_id>", format = "json", data = "<update>")]
 pub async fn update(
     note_id: i32,
     update: Json<UpdateNoteInfo>,
     pool: &State<PgPool>,
+    config: &State<Config>,
     user: User,
-) -> status::Custom<Option<Json<UpdateResponse>>> {
-    let conn = match db::acquire_conn(pool).await {
+) -> Result<status::Custom<Option<Json<UpdateResponse>>>, QuotaExceeded> {
+    if !update.is_valid() {
+        return Ok(status::Custom(Status::BadRequest, None));
+    }
+
+    let mut conn = match db::acquire_conn(pool).await {
         Ok(conn) => conn,
-        Err(_) => return status::Custom(Status::InternalServerError, None),
+        Err(_) => return Ok(status::Custom(Status::InternalServerError, None)),
     };
+    let mut tx = match conn.begin().await {
+        Ok(tx) => tx,
+        Err(_) => return Ok(status::Custom(Status::InternalServerError, None)),
+    };
+
+    // Make sure they've got room for however much the note's growing by
+    let (old_title, old_content) = match usage::get_note_size(&mut tx, user.id, note_id).await {
+        Ok(Some(sizes)) => sizes,
+        Ok(None) => return Ok(status::Custom(Status::NotFound, None)),
+        Err(_) => return Ok(status::Custom(Status::InternalServerError, None)),
+    };
+    let (new_title, new_content) = update.sizes();
+    let growth = new_title.unwrap_or(old_title) + new_content.unwrap_or(old_content)
+        - (old_title + old_content);
+    match quota::check(&mut tx, user.id, config.storage_quota, growth).await {
+        Ok(()) => (),
+        Err(QuotaError::Exceeded(exceeded)) => return Err(exceeded),
+        Err(QuotaError::Database) => return Ok(status::Custom(Status::InternalServerError, None)),
+    }
 
     // Perform the update
-    match note::update(conn, user.id, note_id, &update).await {
+    let outcome = note::update(&mut tx, user.id, note_id, &update).await;
+    if tx.commit().await.is_err() {
+        return Ok(status::Custom(Status::InternalServerError, None));
+    }
+    Ok(match outcome {
         Err(_) => status::Custom(Status::InternalServerError, None), // failed to talk to the db
-        Ok(Some(None)) => status::Custom(Status::InternalServerError, None), // failed to update
-        Ok(None) => status::Custom(Status::NotFound, None),          // no such note exists
-        Ok(Some(Some(update_time))) => {
+        Ok(UpdateOutcome::Failed) => status::Custom(Status::InternalServerError, None), // failed to update
+        Ok(UpdateOutcome::NotFound) => status::Custom(Status::NotFound, None), // no such note exists
+        Ok(UpdateOutcome::Locked) => status::Custom(Status::Locked, None),     // not unlocked yet
+        Ok(UpdateOutcome::Invalid) => status::Custom(Status::BadRequest, None), // not valid for this note
+        Ok(UpdateOutcome::Updated(update_time)) => {
             status::Custom(Status::Ok, Some(Json(UpdateResponse { update_time })))
         }
-    }
+    })
 }
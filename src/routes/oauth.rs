@@ -0,0 +1,159 @@
+use rocket::{
+    http::{Cookie, CookieJar, SameSite, Status},
+    response::Redirect,
+    time::Duration,
+    State,
+};
+use sqlx::PgPool;
+
+use crate::{
+    config::Config,
+    db::{self, oauth, user::User},
+    oauth::{self as provider_login, PendingLogin},
+    session::Session,
+};
+
+/// The cookie a login is kept in while the user's away at the provider
+const OAUTH_COOKIE_NAME: &str = "oauth";
+
+/// How long the user has to log in with the provider
+const OAUTH_COOKIE_EXPIRY_MINUTES: i64 = 10;
+
+/// Starts logging in with an OAuth provider, by sending the user off to it. If they're
+/// already logged in, the provider's account is linked to theirs instead
+///
+/// ### Arguments
+///
+/// * `provider` - the name of the provider, as configured
+/// * `config` - the server's configuration, which lists the providers
+/// * `jar` - the jar we'll keep the login in until the user comes back
+///
+/// ### Returns
+///
+/// * `Status::NotFound` if there's no such provider
+/// * `Status::InternalServerError` if the provider is configured wrong
+/// * A redirect to the provider on success
+#[get("/oauth/<provider>")]
+pub async fn start(
+    provider: &str,
+    config: &State<Config>,
+    jar: &CookieJar<'_>,
+) -> Result<Redirect, Status> {
+    let settings = config
+        .oauth_providers
+        .get(provider)
+        .ok_or(Status::NotFound)?;
+    let (url, login) = provider_login::start(provider, settings, &config.urls)
+        .map_err(|_| Status::InternalServerError)?;
+
+    // The provider sends the user back from its own site, which strict cookies would
+    // be left out of
+    let mut cookie = Cookie::new(OAUTH_COOKIE_NAME, login.to_cookie());
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_max_age(Duration::minutes(OAUTH_COOKIE_EXPIRY_MINUTES));
+    jar.add_private(cookie);
+
+    Ok(Redirect::to(url.to_string()))
+}
+
+/// Finishes logging in with an OAuth provider, once it's sent the user back. The
+/// provider's account is linked to a user by, in order:
+///
+/// * whoever it's already linked to
+/// * the user that's logged in, if they started the login while logged in
+/// * the user with the same email, if the provider has verified they own it and is
+///   trusted to link accounts by email
+/// * a new user with that email, if the provider has verified they own it
+///
+/// Otherwise an existing user has to log in before linking the provider, so a
+/// provider that's careless about verifying emails can't be used to take over accounts
+///
+/// ### Arguments
+///
+/// * `provider` - the name of the provider, as configured
+/// * `code` - the code to trade for an access token, missing if the user said no
+/// * `state` - the state we sent the provider, which it sends back
+/// * `config` - the server's configuration, which lists the providers
+/// * `pool` - connections to the db storing the users and their linked accounts
+/// * `jar` - the jar the login was kept in, and the session will be stored in
+/// * `session` - the session of the currently logged in user, if there is one
+///
+/// ### Returns
+///
+/// * `Status::NotFound` if there's no such provider
+/// * `Status::BadRequest` if the login wasn't started here, has expired, or the state
+///   doesn't match
+/// * `Status::Unauthorized` if the user didn't allow the login
+/// * `Status::BadGateway` if the provider wouldn't tell us who the user is
+/// * `Status::Forbidden` if the provider hasn't verified the user's email, and it
+///   isn't linked to anyone
+/// * `Status::Conflict` if the provider's account is linked to someone other than the
+///   logged in user, or its email belongs to a user it isn't trusted to link to
+/// * `Status::InternalServerError` if we failed to reach the db
+/// * A redirect to the frontend, logged in, on success
+#[get("/oauth/<provider>/callback?<code>&<state>")]
+pub async fn callback(
+    provider: &str,
+    code: Option<&str>,
+    state: Option<&str>,
+    config: &State<Config>,
+    pool: &State<PgPool>,
+    jar: &CookieJar<'_>,
+    session: Option<Session>,
+) -> Result<Redirect, Status> {
+    let settings = config
+        .oauth_providers
+        .get(provider)
+        .ok_or(Status::NotFound)?;
+
+    // Each login can only be finished once
+    let login = jar
+        .get_private(OAUTH_COOKIE_NAME)
+        .and_then(|cookie| PendingLogin::from_cookie(cookie.value()))
+        .ok_or(Status::BadRequest)?;
+    jar.remove_private(Cookie::named(OAUTH_COOKIE_NAME));
+    if login.provider != provider || !state.is_some_and(|state| login.state_matches(state)) {
+        return Err(Status::BadRequest);
+    }
+    let code = code.ok_or(Status::Unauthorized)?;
+
+    let info = provider_login::finish(settings, &login, code, &config.urls)
+        .await
+        .map_err(|_| Status::BadGateway)?;
+
+    // Work out who they are
+    let mut conn = db::acquire_conn(pool).await?;
+    let linked = oauth::get_user_id(&mut conn, provider, &info.sub)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let logged_in = session.as_ref().map(|session| session.user_id);
+    let user_id = match (linked, logged_in) {
+        (Some(linked), Some(logged_in)) if linked != logged_in => return Err(Status::Conflict),
+        (Some(linked), _) => linked,
+        (None, Some(logged_in)) => logged_in,
+        (None, None) => {
+            let email = info.verified_email().ok_or(Status::Forbidden)?;
+            let existing = User::get_by_email(&mut conn, email)
+                .await
+                .map_err(|_| Status::InternalServerError)?;
+            match existing {
+                Some(user) if settings.trust_email => user.id,
+                Some(_) => return Err(Status::Conflict),
+                None => User::create_without_password(&mut conn, email)
+                    .await
+                    .map_err(|_| Status::InternalServerError)?,
+            }
+        }
+    };
+    if linked.is_none() {
+        oauth::link(&mut conn, user_id, provider, &info.sub)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+    }
+
+    if logged_in.is_none() {
+        Session::init(user_id, jar, &mut conn).await;
+    }
+
+    Ok(Redirect::to(config.urls.home()))
+}
@@ -0,0 +1,111 @@
+use crate::db::{
+    self,
+    prompt::{self, CreatePromptInfo, Prompt},
+    user::User,
+};
+use rocket::{http::Status, response::status, serde::json::Json, time::OffsetDateTime, State};
+use sqlx::PgPool;
+
+/// Gets the user's writing prompt for today. It stays the same for the whole (UTC) day
+///
+/// ### Arguments
+///
+/// * `pool` - connections to the db storing the prompts
+/// * `user` - the user who's making the request
+///
+/// ### Returns
+///
+/// * `Status::InternalServerError` if we failed to contact the database
+/// * `Status::NotFound` if there are no prompts to pick from
+/// * `Status::Ok` and the json-encoded prompt on success
+#[get("/today")]
+pub async fn today(pool: &State<PgPool>, user: User) -> status::Custom<Option<Json<Prompt>>> {
+    let conn = match db::acquire_conn(pool).await {
+        Ok(conn) => conn,
+        Err(_) => return status::Custom(Status::InternalServerError, None),
+    };
+
+    let today = OffsetDateTime::now_utc().date();
+    match prompt::get_for_day(conn, user.id, today).await {
+        Ok(Some(prompt)) => status::Custom(Status::Ok, Some(Json(prompt))),
+        Ok(None) => status::Custom(Status::NotFound, None),
+        Err(_) => status::Custom(Status::InternalServerError, None),
+    }
+}
+
+/// Gets every prompt available to the user, both built-in and their own
+///
+/// ### Returns
+///
+/// * `Status::InternalServerError` if we failed to contact the database
+/// * `Status::Ok` and the json-encoded prompts on success
+#[get("/")]
+pub async fn get_all(
+    pool: &State<PgPool>,
+    user: User,
+) -> status::Custom<Option<Json<Vec<Prompt>>>> {
+    let conn = match db::acquire_conn(pool).await {
+        Ok(conn) => conn,
+        Err(_) => return status::Custom(Status::InternalServerError, None),
+    };
+
+    match prompt::get_all(conn, user.id).await {
+        Ok(prompts) => status::Custom(Status::Ok, Some(Json(prompts))),
+        Err(_) => status::Custom(Status::InternalServerError, None),
+    }
+}
+
+/// Adds a prompt of the user's own to their library
+///
+/// ### Arguments
+///
+/// * `create` - the prompt being created
+/// * `pool` - connections to the db storing the prompts
+/// * `user` - the user creating the prompt
+///
+/// ### Returns
+///
+/// * `Status::BadRequest` if the prompt is empty or too long
+/// * `Status::InternalServerError` if we failed to contact the database
+/// * `Status::Created` and the json-encoded prompt on success
+#[post("/", format = "json", data = "<create>")]
+pub async fn create(
+    create: Json<CreatePromptInfo>,
+    pool: &State<PgPool>,
+    user: User,
+) -> status::Custom<Option<Json<Prompt>>> {
+    if !create.is_valid() {
+        return status::Custom(Status::BadRequest, None);
+    }
+
+    let conn = match db::acquire_conn(pool).await {
+        Ok(conn) => conn,
+        Err(_) => return status::Custom(Status::InternalServerError, None),
+    };
+
+    match prompt::create(conn, user.id, &create).await {
+        Ok(prompt) => status::Custom(Status::Created, Some(Json(prompt))),
+        Err(_) => status::Custom(Status::InternalServerError, None),
+    }
+}
+
+/// Deletes one of the user's own prompts
+///
+/// ### Returns
+///
+/// * `Status::InternalServerError` if we failed to contact the database
+/// * `Status::NotFound` if the user has no such prompt (built-in prompts can't be deleted)
+/// * `Status::Ok` if the prompt was deleted
+#[delete("/<prompt_id>")]
+pub async fn delete(prompt_id: i32, pool: &State<PgPool>, user: User) -> Status {
+    let conn = match db::acquire_conn(pool).await {
+        Ok(conn) => conn,
+        Err(_) => return Status::InternalServerError,
+    };
+
+    match prompt::delete(conn, user.id, prompt_id).await {
+        Err(_) => Status::InternalServerError,
+        Ok(false) => Status::NotFound,
+        Ok(true) => Status::Ok,
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use rocket::{time::OffsetDateTime, tokio::fs};
+
+use crate::config::ScannerConfig;
+
+pub mod clamav;
+
+/// What a scanner made of a file
+pub enum Verdict {
+    Clean,
+    /// The file is infected, with the name of what was found in it
+    Infected(String),
+}
+
+/// Stuff that can go wrong while scanning a file
+#[derive(Debug)]
+pub enum ScanError {
+    /// We couldn't read the file, or talk to the scanner
+    Io(io::Error),
+    /// The scanner couldn't scan the file, with what it said went wrong
+    Failed(String),
+}
+impl From<io::Error> for ScanError {
+    fn from(err: io::Error) -> ScanError {
+        ScanError::Io(err)
+    }
+}
+
+/// Something that can check a file for malware
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    /// The name of the scanner, for the logs
+    fn name(&self) -> &'static str;
+
+    /// Scans the file at the path
+    async fn scan(&self, path: &Path) -> Result<Verdict, ScanError>;
+}
+
+/// Checks uploaded images and attachments for malware before they're stored, and
+/// moves any that are infected into quarantine
+pub struct UploadScanner {
+    /// The scanner to use, or None if uploads aren't scanned
+    scanner: Option<Box<dyn Scanner>>,
+    /// The directory infected uploads are copied into
+    quarantine_path: PathBuf,
+}
+
+impl UploadScanner {
+    /// Creates the upload scanner described by the config
+    ///
+    /// ### Arguments
+    ///
+    /// * `config` - The scanner to use, and its settings
+    /// * `quarantine_path` - The directory to copy infected uploads into
+    pub fn new(config: &ScannerConfig, quarantine_path: PathBuf) -> UploadScanner {
+        let scanner: Option<Box<dyn Scanner>> = match config {
+            ScannerConfig::None => None,
+            ScannerConfig::ClamAv { socket } => {
+                Some(Box::new(clamav::ClamAvScanner::new(socket.clone())))
+            }
+        };
+
+        UploadScanner {
+            scanner,
+            quarantine_path,
+        }
+    }
+
+    /// Scans an upload before it's stored. Infected uploads are copied into quarantine,
+    /// and every outcome is logged
+    ///
+    /// ### Arguments
+    ///
+    /// * `user_id` - The id of the user that uploaded the file
+    /// * `name` - What the upload was called (its file name or url), for the logs
+    /// * `path` - Where the upload is
+    ///
+    /// ### Returns
+    ///
+    /// What the scanner made of the upload (always clean if scanning is turned off),
+    /// or an error if it couldn't be scanned
+    pub async fn check(&self, user_id: i32, name: &str, path: &Path) -> Result<Verdict, ScanError> {
+        let scanner = match &self.scanner {
+            Some(scanner) => scanner,
+            None => return Ok(Verdict::Clean),
+        };
+
+        match scanner.scan(path).await {
+            Ok(Verdict::Clean) => {
+                info!(
+                    "{} found upload {name:?} from user {user_id} clean",
+                    scanner.name()
+                );
+                Ok(Verdict::Clean)
+            }
+            Ok(Verdict::Infected(signature)) => {
+                match self.quarantine(user_id, path).await {
+                    Ok(quarantined) => warn!(
+                        "{} found {signature} in upload {name:?} from user {user_id}, \
+                        rejected it and quarantined it at {}",
+                        scanner.name(),
+                        quarantined.display()
+                    ),
+                    Err(err) => error!(
+                        "{} found {signature} in upload {name:?} from user {user_id}, \
+                        rejected it but failed to quarantine it: {err}",
+                        scanner.name()
+                    ),
+                }
+                Ok(Verdict::Infected(signature))
+            }
+            Err(err) => {
+                error!(
+                    "{} failed to scan upload {name:?} from user {user_id}: {err:?}",
+                    scanner.name()
+                );
+                Err(err)
+            }
+        }
+    }
+
+    /// Copies an infected upload into quarantine, named after when it was uploaded and
+    /// who by. The upload itself is removed along with the rest of the request
+    ///
+    /// ### Arguments
+    ///
+    /// * `user_id` - The id of the user that uploaded the file
+    /// * `path` - Where the upload is
+    ///
+    /// ### Returns
+    ///
+    /// Where the upload was copied to, or an error if we couldn't copy it
+    async fn quarantine(&self, user_id: i32, path: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(&self.quarantine_path).await?;
+        let quarantined = self.quarantine_path.join(format!(
+            "{}-user-{user_id}",
+            OffsetDateTime::now_utc().unix_timestamp_nanos()
+        ));
+        fs::copy(path, &quarantined).await?;
+
+        Ok(quarantined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    /// A scanner that finds the same thing in every file
+    struct FakeScanner(Option<&'static str>);
+
+    #[async_trait]
+    impl Scanner for FakeScanner {
+        fn name(&self) -> &'static str {
+            "Fake"
+        }
+
+        async fn scan(&self, _path: &Path) -> Result<Verdict, ScanError> {
+            Ok(match self.0 {
+                Some(signature) => Verdict::Infected(signature.to_string()),
+                None => Verdict::Clean,
+            })
+        }
+    }
+
+    /// Creates an upload scanner using a fake scanner, which quarantines into the
+    /// directory, along with an upload in another directory
+    fn upload_scanner(
+        finds: Option<&'static str>,
+        quarantine_path: PathBuf,
+    ) -> (UploadScanner, tempfile::TempDir, PathBuf) {
+        let scanner = UploadScanner {
+            scanner: Some(Box::new(FakeScanner(finds))),
+            quarantine_path,
+        };
+        let uploads = tempdir().unwrap();
+        let upload = uploads.path().join("upload");
+        fs::write(&upload, b"not really a virus").unwrap();
+
+        (scanner, uploads, upload)
+    }
+
+    #[rocket::async_test]
+    async fn infected_uploads_are_copied_into_quarantine() {
+        let quarantine = tempdir().unwrap();
+        // The quarantine directory is created when it's first needed
+        let quarantine_path = quarantine.path().join("quarantine");
+        let (scanner, _uploads, upload) =
+            upload_scanner(Some("Eicar-Signature"), quarantine_path.clone());
+
+        let verdict = scanner.check(1, "upload.jpg", &upload).await.unwrap();
+        assert!(matches!(verdict, Verdict::Infected(signature) if signature == "Eicar-Signature"));
+
+        let quarantined: Vec<_> = fs::read_dir(&quarantine_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(quarantined.len(), 1);
+        let name = quarantined[0].file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with("-user-1"), "{name}");
+        assert_eq!(fs::read(&quarantined[0]).unwrap(), b"not really a virus");
+        // The upload is only copied, it's removed along with the rest of the request
+        assert!(upload.exists());
+    }
+
+    #[rocket::async_test]
+    async fn clean_uploads_are_not_quarantined() {
+        let quarantine = tempdir().unwrap();
+        let quarantine_path = quarantine.path().join("quarantine");
+        let (scanner, _uploads, upload) = upload_scanner(None, quarantine_path.clone());
+
+        let verdict = scanner.check(1, "upload.jpg", &upload).await.unwrap();
+        assert!(matches!(verdict, Verdict::Clean));
+        assert!(!quarantine_path.exists());
+    }
+}
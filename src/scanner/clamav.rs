@@ -0,0 +1,147 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use rocket::tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    time,
+};
+
+use crate::scanner::{ScanError, Scanner, Verdict};
+
+/// How much of a file is sent to ClamAV at a time
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// How long we'll wait for ClamAV to scan a file
+const SCAN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Scans files with a ClamAV daemon (clamd), over its local socket
+pub struct ClamAvScanner {
+    socket: PathBuf,
+}
+
+impl ClamAvScanner {
+    /// Creates a scanner that talks to the ClamAV daemon listening on the given socket
+    pub fn new(socket: PathBuf) -> ClamAvScanner {
+        ClamAvScanner { socket }
+    }
+
+    /// Sends a file to ClamAV, and reads back its reply
+    ///
+    /// ### Arguments
+    ///
+    /// * `path` - Where the file is
+    ///
+    /// ### Returns
+    ///
+    /// ClamAV's reply, e.g. "stream: OK", or an error if we couldn't talk to it
+    async fn send(&self, path: &Path) -> Result<String, ScanError> {
+        let mut file = File::open(path).await?;
+        let mut stream = UnixStream::connect(&self.socket).await?;
+
+        // The file is streamed as chunks prefixed with their length, and ended with an
+        // empty chunk. ClamAV doesn't need access to our files that way
+        stream.write_all(b"zINSTREAM\0").await?;
+        let mut chunk = vec![0; CHUNK_BYTES];
+        loop {
+            let read = file.read(&mut chunk).await?;
+            stream.write_all(&(read as u32).to_be_bytes()).await?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&chunk[..read]).await?;
+        }
+
+        let mut reply = vec![];
+        stream.read_to_end(&mut reply).await?;
+        Ok(String::from_utf8_lossy(&reply)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string())
+    }
+}
+
+#[async_trait]
+impl Scanner for ClamAvScanner {
+    fn name(&self) -> &'static str {
+        "ClamAV"
+    }
+
+    async fn scan(&self, path: &Path) -> Result<Verdict, ScanError> {
+        let reply = time::timeout(SCAN_TIMEOUT, self.send(path))
+            .await
+            .map_err(|_| ScanError::Failed(String::from("Timed out")))??;
+
+        parse_reply(reply)
+    }
+}
+
+/// Works out what ClamAV made of a file from its reply. Replies look like
+/// "stream: OK", "stream: Eicar-Signature FOUND", or end in ERROR if the file couldn't
+/// be scanned (e.g. it's over clamd's StreamMaxLength)
+///
+/// ### Arguments
+///
+/// * `reply` - ClamAV's reply, without the trailing null
+///
+/// ### Returns
+///
+/// The verdict, or the reply as an error if it wasn't either of them
+fn parse_reply(reply: String) -> Result<Verdict, ScanError> {
+    let result = reply.strip_prefix("stream:").unwrap_or(&reply).trim();
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(signature.to_string()))
+    } else {
+        Err(ScanError::Failed(reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(reply: &str) -> Result<Verdict, ScanError> {
+        parse_reply(reply.to_string())
+    }
+
+    #[test]
+    fn clean_files_are_clean() {
+        assert!(matches!(parse("stream: OK"), Ok(Verdict::Clean)));
+    }
+
+    #[test]
+    fn infected_files_are_named_after_what_was_found() {
+        assert!(matches!(
+            parse("stream: Win.Test.EICAR_HDB-1 FOUND"),
+            Ok(Verdict::Infected(signature)) if signature == "Win.Test.EICAR_HDB-1"
+        ));
+    }
+
+    #[test]
+    fn errors_are_passed_on() {
+        let reply = "INSTREAM size limit exceeded. ERROR";
+        assert!(matches!(
+            parse(reply),
+            Err(ScanError::Failed(failure)) if failure == reply
+        ));
+        assert!(matches!(
+            parse("stream: Can't allocate memory ERROR"),
+            Err(ScanError::Failed(_))
+        ));
+    }
+
+    #[test]
+    fn replies_we_dont_understand_are_failures() {
+        for reply in ["", "stream:", "stream: FOUND", "stream: OK OK", "PONG"] {
+            assert!(
+                matches!(parse(reply), Err(ScanError::Failed(_))),
+                "{reply:?} should have failed"
+            );
+        }
+    }
+}
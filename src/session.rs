@@ -100,6 +100,25 @@ impl Session {
         self.remove_from_db(conn).await
     }
 
+    /// Deletes every one of the user's sessions from our database, logging them out
+    /// everywhere (e.g. once their password's been reset)
+    ///
+    /// ### Arguments
+    ///
+    /// * `user_id` - The id of the user we're logging out
+    /// * `conn` - A connection to the db that stores the sessions
+    ///
+    /// ### Returns
+    ///
+    /// Error if we failed to contact the db, otherwise how many sessions were deleted
+    pub async fn delete_all(user_id: i32, conn: &mut PgConnection) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!("DELETE FROM sessions WHERE user_id = $1", user_id)
+            .execute(conn)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
     /// Deletes the session from our database
     ///
     /// ### Arguments
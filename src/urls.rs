@@ -0,0 +1,112 @@
+/// Where the server is publicly reachable, if not configured
+const DEFAULT_BASE_URL: &str = "https://dev.com";
+
+/// Builds the links to our resources that we hand out, from the configured base url
+#[derive(Clone)]
+pub struct PublicUrls {
+    /// The scheme and host (and any path prefix) we're reachable at, without a trailing /
+    base: String,
+    /// Whether links used by our own frontend should leave out the base, so they work
+    /// whichever host the frontend was loaded from
+    relative: bool,
+}
+
+impl PublicUrls {
+    /// Sets up our links
+    ///
+    /// ### Arguments
+    ///
+    /// * `base` - The url the server is publicly reachable at, e.g. https://dev.com.
+    ///   Defaults to https://dev.com
+    /// * `relative` - Whether links used by our own frontend should leave out the base
+    ///
+    /// ### Returns
+    ///
+    /// The links, or a description of what's wrong with the base url
+    pub fn new(base: Option<&str>, relative: bool) -> Result<PublicUrls, String> {
+        let base = base.unwrap_or(DEFAULT_BASE_URL).trim_end_matches('/');
+        if !base.starts_with("https://") && !base.starts_with("http://") {
+            return Err(format!("{base} isn't an http(s) url"));
+        }
+
+        Ok(PublicUrls {
+            base: base.to_string(),
+            relative,
+        })
+    }
+
+    /// Gets the link to one of our images, as stored in notes' content
+    ///
+    /// ### Arguments
+    ///
+    /// * `image_id` - The id of the image we're linking to
+    pub fn image(&self, image_id: i32) -> String {
+        self.frontend(&format!("/api/images/{image_id}"))
+    }
+
+    /// Gets the link to one of our images for use outside our frontend, such as in a
+    /// feed. This is always absolute, as feed readers have no page to resolve it against
+    ///
+    /// ### Arguments
+    ///
+    /// * `image_id` - The id of the image we're linking to
+    pub fn absolute_image(&self, image_id: i32) -> String {
+        format!("{}/api/images/{image_id}", self.base)
+    }
+
+    /// Gets the link to download one of our attachments
+    ///
+    /// ### Arguments
+    ///
+    /// * `file_id` - The id of the attachment we're linking to
+    pub fn file(&self, file_id: i32) -> String {
+        self.frontend(&format!("/api/files/{file_id}"))
+    }
+
+    /// Gets the link to the given note on the frontend. This is always absolute, as
+    /// it's used by feed readers and calendar apps
+    ///
+    /// ### Arguments
+    ///
+    /// * `note_id` - The id of the note we're linking to
+    pub fn note(&self, note_id: i32) -> String {
+        format!("{}/notes?id={note_id}", self.base)
+    }
+
+    /// Gets the link to the frontend's home page, where users land once they've logged in
+    pub fn home(&self) -> String {
+        self.frontend("/")
+    }
+
+    /// Gets the link on the frontend where users choose a new password, from the link
+    /// in a password reset email. This is always absolute, as it's opened from an email
+    ///
+    /// ### Arguments
+    ///
+    /// * `token` - The reset token, which is url-safe
+    pub fn password_reset(&self, token: &str) -> String {
+        format!("{}/reset-password?token={token}", self.base)
+    }
+
+    /// Gets the url OAuth providers send users back to once they've logged in. This is
+    /// always absolute, as it has to match the url registered with the provider
+    ///
+    /// ### Arguments
+    ///
+    /// * `provider` - The name of the provider, as configured
+    pub fn oauth_callback(&self, provider: &str) -> String {
+        format!("{}/api/auth/oauth/{provider}/callback", self.base)
+    }
+
+    /// Gets a link for our own frontend, relative if it's been configured that way
+    ///
+    /// ### Arguments
+    ///
+    /// * `path` - The path being linked to, starting with a /
+    fn frontend(&self, path: &str) -> String {
+        match self.relative {
+            true => path.to_string(),
+            false => format!("{}{path}", self.base),
+        }
+    }
+}